@@ -0,0 +1,95 @@
+//! What the audio streams of the pipeline run on.
+//!
+//! A `Backend` opens a stream on a device chosen by a selector (see the `devices` module) and
+//! attaches it to one end of the buffers of the pipeline. `CpalBackend` uses the devices of a
+//! cpal host; `virtual_devices::VirtualBackend` simulates devices so that the whole pipeline can
+//! run without sound hardware.
+
+use cpal::traits::StreamTrait;
+use std::sync::{Arc, Mutex};
+
+use crate::devices::{self, Direction};
+use crate::processing::{Mono2StereoOutput, Stereo2MonoCapture};
+use crate::streams::{self, StreamHealth};
+
+/// The buffer end a stream is attached to.
+#[derive(Clone)]
+pub enum Endpoint {
+    Input(Arc<Mutex<Stereo2MonoCapture>>),
+    Output(Arc<Mutex<Mono2StereoOutput>>),
+}
+
+impl Endpoint {
+    pub fn direction(&self) -> Direction {
+        match self {
+            Endpoint::Input(_) => Direction::Input,
+            Endpoint::Output(_) => Direction::Output,
+        }
+    }
+}
+
+/// Opens the streams of the pipeline.
+pub trait Backend {
+    /// A stream, stopped when dropped
+    type Stream;
+
+    /// Builds a stream on the device matching `selector`, feeding or draining `endpoint` and
+    /// counting its callbacks, lost samples and errors in `health`. The stream is not started.
+    ///
+    /// Returns the stream and a description of its configuration.
+    fn build_stream(
+        &self,
+        selector: &str,
+        endpoint: &Endpoint,
+        health: Arc<StreamHealth>,
+    ) -> Result<(Self::Stream, String), anyhow::Error>;
+
+    fn play(&self, stream: &Self::Stream) -> Result<(), anyhow::Error>;
+}
+
+/// The devices of a cpal host, all run at the same sample rate.
+pub struct CpalBackend {
+    host: cpal::Host,
+    sample_rate: u32,
+    buffer_frames: Option<u32>,
+}
+
+impl CpalBackend {
+    pub fn new(host: cpal::Host, sample_rate: u32, buffer_frames: Option<u32>) -> Self {
+        CpalBackend {
+            host,
+            sample_rate,
+            buffer_frames,
+        }
+    }
+}
+
+impl Backend for CpalBackend {
+    type Stream = cpal::Stream;
+
+    /// Looks the device up again every time, in case it was unplugged and plugged back.
+    fn build_stream(
+        &self,
+        selector: &str,
+        endpoint: &Endpoint,
+        health: Arc<StreamHealth>,
+    ) -> Result<(cpal::Stream, String), anyhow::Error> {
+        let direction = endpoint.direction();
+        let device = devices::find_device(&self.host, selector, direction)?;
+        let negotiated =
+            streams::negotiate(&device, direction, self.sample_rate, self.buffer_frames)?;
+        let stream = match endpoint {
+            Endpoint::Input(capture) => {
+                streams::build_input_stream(&device, &negotiated, capture.clone(), health)?
+            }
+            Endpoint::Output(output) => {
+                streams::build_output_stream(&device, &negotiated, output.clone(), health)?
+            }
+        };
+        Ok((stream, negotiated.to_string()))
+    }
+
+    fn play(&self, stream: &cpal::Stream) -> Result<(), anyhow::Error> {
+        Ok(stream.play()?)
+    }
+}
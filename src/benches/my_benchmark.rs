@@ -1,7 +1,8 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 
 use raec::nlmf;
-use raec::processing::{Mono2StereoOutput, Stereo2MonoCapture};
+use raec::processing::{AECFiltering, AECParameters, Mono2StereoOutput, Stereo2MonoCapture};
+use raec::simulation::Scenario;
 
 pub fn callbacks_benchmark(c: &mut Criterion) {
     let input_ring = ringbuf::RingBuffer::<f32>::new(1024);
@@ -49,6 +50,30 @@ pub fn filter_adapt_benchmark(c: &mut Criterion) {
     });
 }
 
+pub fn simulated_echo_benchmark(c: &mut Criterion) {
+    let scenario = Scenario {
+        duration_s: 1.0,
+        ..Scenario::default()
+    };
+    let simulation = scenario.generate().unwrap();
+    let parameters = AECParameters {
+        sample_rate: scenario.sample_rate as f32,
+        ..AECParameters::default()
+    };
+
+    let mut group = c.benchmark_group("Simulation");
+    group.throughput(Throughput::Elements(simulation.microphone.len() as u64));
+    group.sample_size(10);
+    group.bench_function("process one second of echo", |b| {
+        b.iter(|| {
+            let mut filter = AECFiltering::offline(&parameters, vec![0.0; parameters.taps]);
+            black_box(filter.process_offline(&simulation.microphone, &simulation.far_end))
+        })
+    });
+    group.finish();
+}
+
 criterion_group!(callbacks, callbacks_benchmark);
 criterion_group!(filter, filter_adapt_benchmark);
-criterion_main!(callbacks, filter);
+criterion_group!(simulation, simulated_echo_benchmark);
+criterion_main!(callbacks, filter, simulation);
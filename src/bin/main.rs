@@ -1,284 +1,985 @@
 //! Feeds back the input stream directly into the output stream.
 //!
-//! Assumes that the input and output devices can use the same stream configuration and that they
-//! support the f32 sample format.
+//! Assumes that the input and output devices can run at the same sample rate; the sample format,
+//! number of channels and buffer size are negotiated with each device (see the `streams` module).
 //!
-//! Uses a delay of `LATENCY_MS` milliseconds in case the default input and output streams are not
-//! precisely synchronised.
-
-use clap::{App, Arg};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use processing::{AECFiltering, Mono2StereoOutput, Stereo2MonoCapture};
+//! Uses a delay of `stream.latency_ms` milliseconds (see the `config` module) in case the input
+//! and output streams are not precisely synchronised. The resulting delay from the microphone to
+//! the output is measured and logged while running; see the `latency` module.
+
+use backend::CpalBackend;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use config::Config;
+use cpal::traits::DeviceTrait;
+use devices::Direction;
+use evaluation::{Case, Comparison, Report};
+use log::{debug, error, info, warn};
+use pipeline::Pipeline;
+use plot::{Dashboard, DashboardOptions};
+use processing::WeightInit;
 use raec::*;
-use ringbuf::RingBuffer;
+use recorder::{Recorder, Recording};
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
 use std::io::stdin;
-use std::sync::{Arc, Mutex};
-use std::thread::Thread;
-
-const LATENCY_MS: f32 = 100.0;
-
-fn list_devices() -> Result<(), anyhow::Error> {
-    // Adapted from https://github.com/RustAudio/cpal/blob/269c60fde0c1c09fbdf50d65d7bf0d3a4e8d217c/examples/enumerate.rs
-    println!("Supported hosts:\n  {:?}", cpal::ALL_HOSTS);
-    let available_hosts = cpal::available_hosts();
-    println!("Available hosts:\n  {:?}", available_hosts);
-
-    for host_id in available_hosts {
-        println!("{}", host_id.name());
-        let host = cpal::host_from_id(host_id)?;
-
-        let default_in = host.default_input_device().map(|e| e.name().unwrap());
-        let default_out = host.default_output_device().map(|e| e.name().unwrap());
-        println!("  Default Input Device:\n    {:?}", default_in);
-        println!("  Default Output Device:\n    {:?}", default_out);
-
-        let devices = host.devices()?;
-        println!("  Devices: ");
-        for (device_index, device) in devices.enumerate() {
-            println!("  {}. \"{}\"", device_index, device.name()?);
-
-            // Input configs
-            if let Ok(conf) = device.default_input_config() {
-                println!("    Default input stream config:\n      {:?}", conf);
-            }
-            let input_configs = match device.supported_input_configs() {
-                Ok(f) => f.collect(),
-                Err(e) => {
-                    println!("    Error getting supported input configs: {:?}", e);
-                    Vec::new()
-                }
-            };
-            if !input_configs.is_empty() {
-                println!("    All supported input stream configs:");
-                for (config_index, config) in input_configs.into_iter().enumerate() {
-                    println!("      {}.{}. {:?}", device_index, config_index, config);
-                }
-            }
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use supervisor::Supervisor;
+
+/// Time between two measurements of the latency
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Shortest time between two actions on a latency over the budget, so that the buffers can settle
+/// and the log is not flooded
+const LATENCY_BUDGET_HOLDOFF: Duration = Duration::from_secs(60);
+
+/// Finds the host called `name`, or the default host when no name is given.
+fn find_host(name: Option<&str>) -> Result<cpal::Host, anyhow::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+    Ok(cpal::host_from_id(
+        cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Could not find the host \"{}\"", name))?,
+    )?)
+}
 
-            // Output configs
-            if let Ok(conf) = device.default_output_config() {
-                println!("    Default output stream config:\n      {:?}", conf);
-            }
-            let output_configs = match device.supported_output_configs() {
-                Ok(f) => f.collect(),
-                Err(e) => {
-                    println!("    Error getting supported output configs: {:?}", e);
-                    Vec::new()
+/// Prints the devices of the given host, or of all the available hosts, as text or JSON.
+fn list_devices(host_name: Option<&str>, json: bool) -> Result<(), anyhow::Error> {
+    let hosts = match host_name {
+        Some(_) => vec![devices::host_info(&find_host(host_name)?)?],
+        None => cpal::available_hosts()
+            .into_iter()
+            .map(|id| devices::host_info(&cpal::host_from_id(id)?))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&hosts)?);
+        return Ok(());
+    }
+
+    for host in hosts {
+        println!("Host \"{}\"", host.name);
+        for device in host.devices {
+            println!("  {}", device.id);
+            for (direction, capabilities) in [
+                (Direction::Input, &device.input),
+                (Direction::Output, &device.output),
+            ]
+            .iter()
+            {
+                let capabilities = match capabilities {
+                    Some(capabilities) => capabilities,
+                    None => continue,
+                };
+                let default = if capabilities.is_default {
+                    " (host default)"
+                } else {
+                    ""
+                };
+                println!("    {}{}", direction, default);
+                if let Some(config) = &capabilities.default_config {
+                    println!("      default:   {}", config);
                 }
-            };
-            if !output_configs.is_empty() {
-                println!("    All supported output stream configs:");
-                for (config_index, config) in output_configs.into_iter().enumerate() {
-                    println!("      {}.{}. {:?}", device_index, config_index, config);
+                for config in &capabilities.supported_configs {
+                    println!("      supported: {}", config);
                 }
             }
         }
     }
-
     Ok(())
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    // Parse CLI arguments
-    let matches = App::new("RAEC")
-        .version("0.1")
-        .author("")
-        .about("Simple acoustic echo cancellation experiment.")
-        .arg(
-            Arg::with_name("host_id")
-                .long("host")
-                .value_name("HOST_ID")
-                .help("Sets the audio host to use")
-                .takes_value(true), //.group("device_ids"),
-        )
-        .arg(
-            Arg::with_name("mic_device_id")
-                .short("m")
-                .long("microphone")
-                .value_name("MICROPHONE_DEVICE_ID")
-                .help("Sets which device to use as the microphone signal")
-                .takes_value(true), //.group("device_ids"),
-        )
-        .arg(
-            Arg::with_name("capture_device_id")
-                .short("c")
-                .long("capture")
-                .value_name("CAPTURE_DEVICE_ID")
-                .help("Sets which device to use as the capture signal")
-                .takes_value(true), //.group("device_ids"),
-        )
-        .arg(
-            Arg::with_name("output_device_id")
-                .short("o")
-                .long("output")
-                .value_name("OUTPUT_DEVICE_ID")
-                .help("Sets which device to use as the output signal")
-                .takes_value(true), //.group("device_ids"),
-        )
-        .arg(
-            Arg::with_name("list_devices")
-                .short("l")
-                .long("list")
-                .help("List available audio devices and their IDs"),
-        )
-        .arg(
-            Arg::with_name("mu")
-                .long("mu")
-                .default_value("1.0")
-                .help("Adaptive filter step size"),
-        )
-        .get_matches();
+/// Opens a short stream on a device and prints how its callbacks come.
+fn probe_device(matches: &ArgMatches) -> Result<(), anyhow::Error> {
+    let host = find_host(matches.value_of("host_id"))?;
+    let direction = match matches.value_of("direction") {
+        Some("output") => Direction::Output,
+        _ => Direction::Input,
+    };
+    let parse = |arg: &str| -> Result<Option<u32>, anyhow::Error> {
+        matches
+            .value_of(arg)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Could not parse --{}: {}", arg, e))
+            })
+            .transpose()
+    };
+    let seconds: f32 = matches
+        .value_of("seconds")
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Could not parse the duration: {}", e))?;
+    // SAFETY: the device argument is required
+    let device = devices::find_device(&host, matches.value_of("device").unwrap(), direction)?;
+    let report = probe::probe(
+        &device,
+        direction,
+        Duration::from_secs_f32(seconds),
+        parse("sample-rate")?,
+        parse("buffer-frames")?,
+    )?;
+    if matches.value_of("format") == Some("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
 
-    if matches.is_present("list_devices") {
-        return list_devices();
+/// Runs a recording through the offline processing and compares the result with the recorded
+/// output.
+fn replay_recording(matches: &ArgMatches) -> Result<(), anyhow::Error> {
+    // SAFETY: the recording argument is required
+    let directory = std::path::Path::new(matches.value_of("recording").unwrap());
+    let recording = Recording::load(directory)?;
+    let replayed = recorder::replay(&recording);
+    let differences: Vec<f32> = recording
+        .frames
+        .iter()
+        .zip(&replayed)
+        .filter(|(frame, output)| frame.output.to_bits() != output.to_bits())
+        .map(|(frame, output)| (frame.output - output).abs())
+        .collect();
+    if differences.is_empty() {
+        println!(
+            "Replayed {} samples: the output is identical to the recording",
+            replayed.len()
+        );
+    } else {
+        println!(
+            "Replayed {} samples: {} differ from the recording, by up to {}",
+            replayed.len(),
+            differences.len(),
+            differences.iter().fold(0.0_f32, |max, d| max.max(*d))
+        );
+    }
+    if recording.metadata.dropped_events > 0 {
+        warn!(
+            "{} events were lost while recording; the replay cannot be exact",
+            recording.metadata.dropped_events
+        );
+    }
+    if let Some(path) = matches.value_of("output") {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: recording.metadata.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for &sample in &replayed {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+        info!("Wrote {}", path);
     }
+    Ok(())
+}
 
-    assert!(
-        matches.is_present("host_id") &&
-        matches.is_present("mic_device_id") &&
-        matches.is_present("capture_device_id") &&
-        matches.is_present("output_device_id") ,
-        "You must provide the IDs of the devices to use as well as the name of the audio host. See raec --help."
-    );
+/// Scores the echo cancellation over a corpus, or the built-in scenarios, and compares the
+/// scores with those of a baseline report.
+fn evaluate_corpus(config: &Config, matches: &ArgMatches) -> Result<(), anyhow::Error> {
+    let cases = match matches.value_of("corpus") {
+        Some(directory) => evaluation::load_corpus(std::path::Path::new(directory))?,
+        None => evaluation::default_scenarios()
+            .iter()
+            .map(|(name, scenario)| Case::from_scenario(name, scenario))
+            .collect::<Result<_, _>>()?,
+    };
+    let mut scores = Vec::with_capacity(cases.len());
+    for case in &cases {
+        debug!("Evaluating {} ({:.1} s)", case.name, case.duration_s());
+        scores.push(evaluation::evaluate(case, config)?);
+    }
+    let report = Report::new(scores);
+    let text = match matches.value_of("format") {
+        Some("csv") => report.to_csv(),
+        _ => serde_json::to_string_pretty(&report)? + "\n",
+    };
+    match matches.value_of("output") {
+        Some(path) => {
+            std::fs::write(path, text)?;
+            info!("Wrote {}", path);
+        }
+        None => print!("{}", text),
+    }
 
-    let mu = matches
-        .value_of("mu")
-        .unwrap() // SAFETY: "mu" has a default value
-        .parse()
-        .expect("Could not parse the value of mu");
+    if let Some(path) = matches.value_of("baseline") {
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow::anyhow!("Could not read the baseline {}: {}", path, e))?;
+        let baseline: Report = serde_json::from_reader(file).map_err(|e| {
+            anyhow::anyhow!(
+                "The baseline {} is not a JSON report of raec eval: {}",
+                path,
+                e
+            )
+        })?;
+        let comparison = Comparison::new(&baseline, &report);
+        eprint!("{}", comparison);
+        let regressions = comparison.regressions().count();
+        if regressions > 0 {
+            return Err(anyhow::anyhow!(
+                "{} regression(s) against the baseline {}",
+                regressions,
+                path
+            ));
+        }
+    }
+    Ok(())
+}
 
-    let host_id = matches.value_of("host_id").unwrap(); // SAFETY: we already checked that the group of IDs is present
-    let host = cpal::host_from_id(
-        cpal::available_hosts()
-            .into_iter()
-            .find(|id| id.name() == host_id)
-            .expect(format!("Could not find the host \"{}\"", host_id).as_str()),
-    )?;
+/// Reads the configuration file and profile given on the command line, if any, and applies the
+/// command line options on top of it.
+fn load_config(matches: &ArgMatches) -> Result<Config, anyhow::Error> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(std::path::Path::new(path), matches.value_of("profile"))?,
+        None if matches.is_present("profile") => {
+            return Err(anyhow::anyhow!(
+                "--profile needs a configuration file; see --config"
+            ))
+        }
+        None => Config::default(),
+    };
 
-    // Devices
-    // Microphone:
-    let input_device =
-        host.devices()?
-        .nth(matches.value_of("mic_device_id").unwrap()// SAFETY: We have checked already that the id is present in the arguments
+    let override_device = |arg: &str, device: &mut Option<String>| {
+        if let Some(selector) = matches.value_of(arg) {
+            *device = Some(selector.to_string());
+        }
+    };
+    override_device("host_id", &mut config.devices.host);
+    override_device("mic_device_id", &mut config.devices.microphone);
+    override_device("capture_device_id", &mut config.devices.capture);
+    override_device("output_device_id", &mut config.devices.output);
+    if let Some(latency) = matches.value_of("latency_ms") {
+        config.stream.latency_ms = latency
             .parse()
-            .expect("Failed to parse microphone ID; use raec --list to see a list of devices and use the corresponding number of the wished entry on the list as the ID.")
-        )
-        .expect("Failed to open microphone device");
-    // Capture:
-    let capture_device =
-        host.devices()?
-        .nth(matches.value_of("capture_device_id").unwrap()// SAFETY: We have checked already that the id is present in the arguments
+            .map_err(|e| anyhow::anyhow!("Could not parse the latency: {}", e))?;
+    }
+    if let Some(budget) = matches.value_of("max_latency_ms") {
+        config.stream.max_latency_ms = Some(
+            budget
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Could not parse the latency budget: {}", e))?,
+        );
+    }
+    if let Some(algorithm) = matches.value_of("algorithm") {
+        config.filter.algorithm = algorithm.parse()?;
+    }
+    if let Some(taps) = matches.value_of("taps") {
+        config.filter.taps = taps
             .parse()
-            .expect("Failed to parse capture ID; use raec --list to see a list of devices and use the corresponding number of the wished entry on the list as the ID.")
-        )
-        .expect("Failed to open capture device");
-    let output_device =
-        host.devices()?
-        .nth(matches.value_of("output_device_id").unwrap()// SAFETY: We have checked already that the id is present in the arguments
+            .map_err(|e| anyhow::anyhow!("Could not parse the number of taps: {}", e))?;
+    }
+    if let Some(mode) = matches.value_of("output_mode") {
+        config.post_processing.output_mode = mode.parse()?;
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        config.filter.initial_weights = WeightInit::SeededRandom(
+            seed.parse()
+                .map_err(|e| anyhow::anyhow!("Could not parse the seed: {}", e))?,
+        );
+    }
+    if let Some(mu) = matches.value_of("mu") {
+        config.filter.mu = mu
             .parse()
-            .expect("Failed to parse output ID; use raec --list to see a list of devices and use the corresponding number of the wished entry on the list as the ID.")
-        )
-        .expect("Failed to open output device");
+            .map_err(|e| anyhow::anyhow!("Could not parse the value of mu: {}", e))?;
+    }
+    if let Some(control) = matches.value_of("step_size") {
+        config.filter.step_size.control = control.parse()?;
+    }
+    config.filter.path_change.enabled |= matches.is_present("detect_path_changes");
+    if let Some(model) = matches.value_of("nonlinear_model") {
+        config.filter.nonlinearity.model = model.parse()?;
+    }
+    let telemetry = &mut config.telemetry;
+    telemetry.plot |= matches.is_present("plot");
+    telemetry.spectrogram |= matches.is_present("spectrogram");
+    telemetry.weights |= matches.is_present("weights");
+    // a service has no display to open windows on
+    telemetry.headless |= matches.is_present("headless") || matches.is_present("daemon");
+    if let Some(directory) = matches.value_of("plot_dir") {
+        telemetry.plot_dir = Some(directory.into());
+    }
+    if let Some(directory) = matches.value_of("record_dir") {
+        telemetry.record_dir = Some(directory.into());
+    }
+    if let Some(format) = matches.value_of("plot_format") {
+        telemetry.plot_format = format.parse()?;
+    }
+    config.validate()?;
+    Ok(config)
+}
 
-    println!("Using input device: \"{}\"", input_device.name()?);
-    println!("Using capture device: \"{}\"", capture_device.name()?);
-    println!("Using output device: \"{}\"", output_device.name()?);
+/// Why `run` returned.
+enum Stop {
+    /// Asked to exit, from the terminal, a window or a signal
+    Exit,
+    /// The reloaded configuration cannot be applied to the running pipeline
+    Restart(Box<Config>),
+}
 
-    // We'll try and use the same configuration between streams to keep it simple.
-    let config: cpal::StreamConfig = input_device.default_input_config()?.into();
+/// Requests received as signals, polled by the main loop.
+struct Signals {
+    /// Set on SIGINT and SIGTERM
+    terminate: Arc<AtomicBool>,
+    /// Set on SIGHUP
+    reload: Arc<AtomicBool>,
+}
 
-    // Create a delay in case the input and output devices aren't synced.
-    let latency_frames = (LATENCY_MS / 1_000.0) * config.sample_rate.0 as f32;
-    let latency_samples = latency_frames as usize; //* config.channels as usize;
-
-    // The buffers to share samples
-    let input_ring = RingBuffer::new(latency_samples * 2);
-    let (mut input_ring_producer, input_ring_consumer) = input_ring.split();
-
-    let capture_ring = RingBuffer::new(latency_samples * 2);
-    let (mut capture_ring_producer, capture_ring_consumer) = capture_ring.split();
-
-    let output_ring = RingBuffer::new(latency_samples * 2);
-    let (mut output_ring_producer, output_ring_consumer) = output_ring.split();
-
-    // Fill the samples with 0.0 equal to the length of the delay.
-    for _ in 0..latency_samples {
-        // The ring buffer has twice as much space as necessary to add latency here,
-        // so this should never fail
-        input_ring_producer.push(0.0).unwrap();
-        capture_ring_producer.push(0.0).unwrap();
-        output_ring_producer.push(0.0).unwrap();
+impl Signals {
+    fn register() -> Result<Self, anyhow::Error> {
+        let terminate = Arc::new(AtomicBool::new(false));
+        for &signal in TERM_SIGNALS {
+            // a second signal exits at once, in case the orderly shutdown hangs
+            flag::register_conditional_shutdown(signal, 1, terminate.clone())?;
+            flag::register(signal, terminate.clone())?;
+        }
+        let reload = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        flag::register(signal_hook::consts::SIGHUP, reload.clone())?;
+        Ok(Signals { terminate, reload })
     }
+}
 
-    let shared_parking_thread_handle: Arc<Mutex<Option<Thread>>> = Arc::new(Mutex::new(None));
+/// Builds the whole pipeline described by `config` and runs it until asked to stop, or until a
+/// reloaded configuration needs a new pipeline.
+fn run(
+    mut config: Config,
+    matches: &ArgMatches,
+    signals: &Signals,
+    exit_requests: Option<&mpsc::Receiver<()>>,
+) -> Result<Stop, anyhow::Error> {
+    let (host_id, mic_device_id, capture_device_id, output_device_id) = match &config.devices {
+        config::DevicesConfig {
+            host: Some(host),
+            microphone: Some(microphone),
+            capture: Some(capture),
+            output: Some(output),
+        } => (host, microphone, capture, output),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "You must provide the devices to use as well as the name of the audio host, on \
+                 the command line or in the configuration file. See raec --help."
+            ))
+        }
+    };
+
+    let host = find_host(Some(host_id))?;
+
+    // The microphone decides the sample rate when none is configured; the devices are looked up
+    // again by the supervisor whenever their stream has to be rebuilt.
+    let sample_rate = match config.stream.sample_rate {
+        Some(rate) => rate,
+        None => {
+            let input_device = devices::find_device(&host, mic_device_id, Direction::Input)?;
+            info!("Using input device: \"{}\"", input_device.name()?);
+            streams::default_sample_rate(&input_device, Direction::Input)?
+        }
+    };
 
-    let mut input_processing = Stereo2MonoCapture::new_with_parking(
-        input_ring_producer,
-        shared_parking_thread_handle.clone(),
-    );
-    let mut capture_processing = Stereo2MonoCapture::new(capture_ring_producer);
-    let mut output_processing = Mono2StereoOutput::new(output_ring_consumer);
-    let filter_processing = AECFiltering::new(
-        input_ring_consumer,
-        capture_ring_consumer,
-        output_ring_producer,
-        mu,
+    // Create a delay in case the input and output devices aren't synced.
+    let ms_to_samples = |ms: f32| ((ms / 1_000.0) * sample_rate as f32) as usize;
+    let latency_samples = ms_to_samples(config.stream.latency_ms); //* config.channels as usize;
+    let buffer_samples = ms_to_samples(config.stream.buffer_ms());
+
+    // the cutoffs of the post-processing stages stay those they were built with
+    let parameters = config.aec_parameters(sample_rate as f32);
+    let pipeline = Pipeline::new(
+        &parameters,
+        config.filter.initial_weights.weights(config.filter.taps)?,
+        latency_samples,
+        buffer_samples,
     );
+    let mut filter_processing = pipeline.filter;
+    let recorder = match &config.telemetry.record_dir {
+        Some(directory) => {
+            let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let (recorder, tap) = Recorder::start(
+                &directory.join(format!("session-{}", started)),
+                filter_processing.parameters(),
+                filter_processing.weights(),
+                config.to_toml().ok(),
+            )?;
+            info!("Recording into {}", recorder.directory().display());
+            filter_processing.recorder_tap = Some(tap);
+            Some(recorder)
+        }
+        None => None,
+    };
 
     // Build streams.
-    println!(
-        "Attempting to build streams with f32 samples and `{:?}`.",
-        config
+    info!("Attempting to build streams.");
+    let mut supervisor = Supervisor::new(
+        CpalBackend::new(host, sample_rate, config.stream.buffer_frames),
+        Duration::from_secs_f32(config.stream.stall_timeout_ms / 1_000.0),
     );
-    let input_stream = input_device.build_input_stream(
-        &config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| input_processing.callback_and_unpark(data),
-        err_fn,
-    )?;
-    println!("Succeded input stream");
-    let capture_stream = capture_device.build_input_stream(
-        &config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| capture_processing.callback(data),
-        err_fn,
-    )?;
-    println!("Succeded capture stream");
-    let output_stream = output_device.build_output_stream(
-        &config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| output_processing.callback(data),
-        err_fn,
-    )?;
-    println!("Succeded output stream");
-
-    println!("Successfully built streams.");
+    supervisor.add_input("input", mic_device_id, pipeline.microphone)?;
+    supervisor.add_input("capture", capture_device_id, pipeline.capture)?;
+    supervisor.add_output("output", output_device_id, pipeline.output)?;
+    info!("Successfully built streams.");
 
     // Play the streams.
-    println!("Starting the input and capture streams");
-
-    capture_stream.play()?;
-    input_stream.play()?;
-    output_stream.play()?;
+    info!("Starting the input and capture streams");
+    supervisor.play()?;
+
+    debug!("latency samples {}", latency_samples);
+
+    let dashboard_options = DashboardOptions {
+        telemetry: config.telemetry.plot,
+        spectrogram: config.telemetry.spectrogram,
+        weights: config.telemetry.weights,
+        windows: !config.telemetry.headless,
+        sample_rate: sample_rate as f32,
+        mu: config.filter.mu,
+    };
+    let mut dashboard = Dashboard::attach(&mut filter_processing, &dashboard_options)?;
+
+    let latency = filter_processing.latency();
+    let mut processing_thread = pipeline.wakeup.start(filter_processing);
+
+    let has_windows = dashboard.windows_open();
+    if has_windows {
+        println!("{}", plot::KEY_HELP);
+        info!("Everything looks good! Close the plot windows or press enter to exit...");
+    } else if exit_requests.is_some() {
+        info!("Everything looks good! Press enter to exit...");
+    } else {
+        info!("Everything looks good! Running until SIGINT or SIGTERM...");
+    }
+    let mut latency_due = Instant::now() + LATENCY_REPORT_INTERVAL;
+    let mut latency_reported = false;
+    let mut over_budget_at: Option<Instant> = None;
+    let stop = loop {
+        if signals.terminate.load(Ordering::Relaxed) {
+            info!("Received a termination signal, stopping");
+            break Stop::Exit;
+        }
+        if exit_requests.is_some_and(|requests| requests.try_recv().is_ok())
+            || (has_windows && !dashboard.windows_open())
+        {
+            break Stop::Exit;
+        }
+        if signals.reload.swap(false, Ordering::Relaxed) {
+            match load_config(matches) {
+                Ok(new_config) => match config.live_changes(&new_config, &parameters) {
+                    Some(controls) => {
+                        info!(
+                            "Reloaded the configuration; {} setting(s) changed",
+                            controls.len()
+                        );
+                        for control in controls {
+                            // the keys change the step size and output mode from the new values
+                            dashboard.follow(&control);
+                            if processing_thread.control(control).is_err() {
+                                error!("Too many pending control messages; dropped {:?}", control);
+                            }
+                        }
+                        config = new_config;
+                    }
+                    None => break Stop::Restart(Box::new(new_config)),
+                },
+                Err(e) => error!(
+                    "Could not reload the configuration, keeping the current one: {}",
+                    e
+                ),
+            }
+        }
+        if Instant::now() >= latency_due {
+            latency_due += LATENCY_REPORT_INTERVAL;
+            let report = latency.report(
+                sample_rate as f32,
+                supervisor.device_latency("input"),
+                supervisor.device_latency("output"),
+            );
+            if latency_reported {
+                debug!("Latency {}", report);
+            } else {
+                info!("Latency {}", report);
+                latency_reported = true;
+            }
+            let held_off = over_budget_at.is_some_and(|at| at.elapsed() < LATENCY_BUDGET_HOLDOFF);
+            if let (Some(budget), false) = (config.stream.max_latency_ms, held_off) {
+                let excess = report.total_ms() - budget;
+                if excess > 0.0 {
+                    over_budget_at = Some(Instant::now());
+                }
+                if excess > 0.0 && report.mic_buffer_ms >= excess {
+                    warn!(
+                        "The latency exceeds the budget of {} ms ({}); dropping the buffered \
+                         microphone and reference samples",
+                        budget, report
+                    );
+                    if processing_thread.resync().is_err() {
+                        error!("Too many pending control messages; could not resynchronise");
+                    }
+                } else if excess > 0.0 {
+                    warn!(
+                        "The latency exceeds the budget of {} ms ({}); try a lower \
+                         stream.latency_ms or smaller device buffers",
+                        budget, report
+                    );
+                }
+            }
+        }
+        if !supervisor.check().rebuilt.is_empty() {
+            // whatever was buffered while a stream was down no longer lines up
+            if processing_thread.resync().is_err() {
+                error!("Too many pending control messages; could not resynchronise");
+            }
+        }
+        let messages = match dashboard.update() {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Could not update the plot windows, stopping: {}", e);
+                break Stop::Exit;
+            }
+        };
+        for message in messages {
+            debug!("{:?}", message);
+            if processing_thread.control(message).is_err() {
+                error!("Too many pending control messages; dropped {:?}", message);
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
 
-    println!("latency samples {}", latency_samples);
+    // Tear down in order: the processing thread first, then the streams feeding it
+    let _ = processing_thread.kill();
+    if let Err(e) = dashboard.update() {
+        error!("Could not update the plot windows: {}", e);
+    }
 
-    // filter_processing.debug_channel = Some(plot_send);
+    if let Some(recorder) = recorder {
+        let directory = recorder.directory().to_path_buf();
+        let metadata = recorder.finish()?;
+        info!(
+            "Recorded {} samples into {}",
+            metadata.samples,
+            directory.display()
+        );
+        if metadata.dropped_events > 0 {
+            warn!(
+                "The recorder could not keep up and lost {} events; the recording cannot be \
+                 replayed exactly",
+                metadata.dropped_events
+            );
+        }
+    }
 
-    let (processing_thread, parking_thread_handle) = filter_processing.start_thread();
-    *shared_parking_thread_handle.lock().unwrap() = Some(parking_thread_handle);
+    if let Some(directory) = &config.telemetry.plot_dir {
+        for path in dashboard.save(directory, config.telemetry.plot_format)? {
+            info!("Wrote {}", path.display());
+        }
+    }
 
-    println!("Everything looks good! Press enter to exit...");
+    drop(supervisor);
+    Ok(stop)
+}
 
-    let _ = stdin().read_line(&mut String::new());
+fn main() -> Result<(), anyhow::Error> {
+    // Parse CLI arguments
+    let matches =
+        App::new("RAEC")
+            .version("0.1")
+            .author("")
+            .about("Simple acoustic echo cancellation experiment.")
+            .after_help(
+                "DEVICE SELECTION:\n    \
+             Devices are selected by their exact name, by a case-insensitive part of their name or \
+             by a regular expression between slashes (e.g. \"/^USB.*Headset$/\"); only devices \
+             supporting the needed direction are considered. The keywords default-input and \
+             default-output select the default devices of the host, and the identifiers listed by \
+             raec devices select a single device even when several share its name.\n\n\
+             CONFIGURATION:\n    \
+             All the settings can also be given in a TOML file with --config, optionally with a \
+             named profile from that file with --profile; see raec.example.toml. Options given on \
+             the command line take precedence over the file.\n\n\
+             SERVICE MODE:\n    \
+             raec stops in an orderly way on SIGINT or SIGTERM, and on enter unless --daemon is \
+             given. SIGHUP reloads the configuration file: the filter settings and the switching \
+             of the post-processing stages are applied to the running pipeline, other changes \
+             rebuild it. Messages are logged to standard error at the level set by RUST_LOG \
+             [default: info].",
+            )
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .value_name("FILE")
+                    .help("Reads the settings from a TOML configuration file; see CONFIGURATION")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .value_name("NAME")
+                    .help("Applies a named profile of the configuration file")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("print_config")
+                    .long("print-config")
+                    .help("Prints the resulting configuration as TOML and exits"),
+            )
+            .arg(
+                Arg::with_name("host_id")
+                    .long("host")
+                    .value_name("HOST_ID")
+                    .help("Sets the audio host to use")
+                    .takes_value(true), //.group("device_ids"),
+            )
+            .arg(
+                Arg::with_name("mic_device_id")
+                    .short("m")
+                    .long("microphone")
+                    .value_name("MICROPHONE_DEVICE")
+                    .help("Sets which device to use as the microphone signal; see DEVICE SELECTION")
+                    .takes_value(true), //.group("device_ids"),
+            )
+            .arg(
+                Arg::with_name("capture_device_id")
+                    .short("c")
+                    .long("capture")
+                    .value_name("CAPTURE_DEVICE")
+                    .help("Sets which device to use as the capture signal; see DEVICE SELECTION")
+                    .takes_value(true), //.group("device_ids"),
+            )
+            .arg(
+                Arg::with_name("output_device_id")
+                    .short("o")
+                    .long("output")
+                    .value_name("OUTPUT_DEVICE")
+                    .help("Sets which device to use as the output signal; see DEVICE SELECTION")
+                    .takes_value(true), //.group("device_ids"),
+            )
+            .arg(
+                Arg::with_name("list_devices")
+                    .short("l")
+                    .long("list")
+                    .help("List available audio devices and their names; see raec devices"),
+            )
+            .arg(
+                Arg::with_name("daemon")
+                    .long("daemon")
+                    .help("Runs as a service without a terminal or windows; see SERVICE MODE"),
+            )
+            .arg(
+                Arg::with_name("plot")
+                    .long("plot")
+                    .help("Open a window plotting buffer levels and echo return loss enhancement"),
+            )
+            .arg(Arg::with_name("spectrogram").long("spectrogram").help(
+                "Open a window with the spectrograms of the microphone, reference and output",
+            ))
+            .arg(Arg::with_name("weights").long("weights").help(
+                "Open a window with the impulse and frequency response of the adaptive filter",
+            ))
+            .arg(
+                Arg::with_name("headless")
+                    .long("headless")
+                    .help("Collect the requested plots without opening any window; see --plot-dir"),
+            )
+            .arg(
+                Arg::with_name("plot_dir")
+                    .long("plot-dir")
+                    .value_name("DIRECTORY")
+                    .help("On exit, write the requested plots as images into this directory")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("plot_format")
+                    .long("plot-format")
+                    .value_name("FORMAT")
+                    .possible_values(&["png", "svg"])
+                    .help("Image format used with --plot-dir [default: png]"),
+            )
+            .arg(
+                Arg::with_name("record_dir")
+                    .long("record")
+                    .value_name("DIRECTORY")
+                    .takes_value(true)
+                    .help(
+                        "Records the microphone, reference, echo estimate and output of the \
+                         session into a new directory under this one; see raec replay",
+                    ),
+            )
+            .arg(
+                Arg::with_name("latency_ms")
+                    .long("latency-ms")
+                    .value_name("MILLISECONDS")
+                    .takes_value(true)
+                    .help("Delay added to cope with unsynchronised devices [default: 100]"),
+            )
+            .arg(
+                Arg::with_name("max_latency_ms")
+                    .long("max-latency-ms")
+                    .value_name("MILLISECONDS")
+                    .takes_value(true)
+                    .help(
+                        "Longest acceptable delay from the microphone to the output; the buffered \
+                         microphone and reference samples are dropped when the microphone buffer \
+                         makes the delay go over, at most once a minute, and the delay is logged \
+                         [default: none]",
+                    ),
+            )
+            .arg(
+                Arg::with_name("algorithm")
+                    .long("algorithm")
+                    .value_name("ALGORITHM")
+                    .possible_values(&["nlmf", "subband", "kalman"])
+                    .help(
+                        "Adaptive filter cancelling the echo: nlmf over the full band, or subband \
+                         for faster convergence on speech at the cost of some latency, or kalman, \
+                         which needs no step size and rides out double talk [default: nlmf]",
+                    ),
+            )
+            .arg(
+                Arg::with_name("taps")
+                    .long("taps")
+                    .value_name("TAPS")
+                    .takes_value(true)
+                    .help("Number of taps of the adaptive filter, a multiple of 8 [default: 1024]"),
+            )
+            .arg(
+                Arg::with_name("mu")
+                    .long("mu")
+                    .takes_value(true)
+                    .help("Adaptive filter step size [default: 1.0]"),
+            )
+            .arg(
+                Arg::with_name("step_size")
+                    .long("step-size")
+                    .value_name("CONTROL")
+                    .possible_values(&["fixed", "variable"])
+                    .help(
+                        "Step size control: fixed at mu, or variable, up to mu while the echo is \
+                         converging and lower once it has [default: fixed]",
+                    ),
+            )
+            .arg(
+                Arg::with_name("detect_path_changes")
+                    .long("detect-path-changes")
+                    .help(
+                        "Run a shadow filter next to the nlmf filter, to detect changes of the \
+                         echo path and re-converge after them",
+                    ),
+            )
+            .arg(
+                Arg::with_name("nonlinear_model")
+                    .long("nonlinear-model")
+                    .value_name("MODEL")
+                    .possible_values(&["linear", "soft-clip"])
+                    .help(
+                        "Model of the distortion of the loudspeaker, applied to the reference \
+                         before the adaptive filter; soft-clip for small loudspeakers played \
+                         loud [default: linear]",
+                    ),
+            )
+            .arg(
+                Arg::with_name("seed")
+                    .long("seed")
+                    .value_name("SEED")
+                    .takes_value(true)
+                    .help(
+                        "Initialises the filter weights from a random generator seeded with this \
+                         number, for reproducible runs [default: unseeded]",
+                    ),
+            )
+            .arg(
+                Arg::with_name("output_mode")
+                    .long("output-mode")
+                    .value_name("MODE")
+                    .possible_values(&[
+                        "processing",
+                        "bypass",
+                        "echo-estimate",
+                        "reference-passthrough",
+                    ])
+                    .help(
+                        "What to send to the output device; switch live with the b and m keys of \
+                         the plot windows [default: processing]",
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("devices")
+                    .about("Lists the audio hosts and devices with their supported configurations")
+                    .arg(
+                        Arg::with_name("host_id")
+                            .long("host")
+                            .value_name("HOST_ID")
+                            .help("Only lists the devices of this host")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .possible_values(&["text", "json"])
+                            .default_value("text")
+                            .help("Output format"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("replay")
+                    .about("Runs a recording made with --record through the offline processing")
+                    .arg(
+                        Arg::with_name("recording")
+                            .value_name("DIRECTORY")
+                            .required(true)
+                            .help("The directory of the recording"),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .long("output")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .help("Writes the replayed output to this WAV file"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("eval")
+                    .about(
+                        "Scores the echo cancellation over a corpus with the filter settings \
+                         given by the options above, e.g. raec --taps 512 eval CORPUS",
+                    )
+                    .arg(Arg::with_name("corpus").value_name("DIRECTORY").help(
+                        "Holds one directory per case with mic.wav, reference.wav and \
+                                 optionally near_end.wav, or scenario .toml files [default: \
+                                 built-in generated scenarios]",
+                    ))
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .possible_values(&["json", "csv"])
+                            .default_value("json")
+                            .help("Format of the report"),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .long("output")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .help("Writes the report to this file instead of the standard output"),
+                    )
+                    .arg(
+                        Arg::with_name("baseline")
+                            .long("baseline")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .help(
+                                "Compares the scores with a JSON report of an earlier run, and \
+                                 fails on a regression",
+                            ),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("probe")
+                    .about(
+                        "Opens a short test stream and reports its buffer sizes and timing jitter",
+                    )
+                    .arg(
+                        Arg::with_name("device")
+                            .value_name("DEVICE")
+                            .required(true)
+                            .help("The device to probe; see DEVICE SELECTION"),
+                    )
+                    .arg(
+                        Arg::with_name("host_id")
+                            .long("host")
+                            .value_name("HOST_ID")
+                            .help("Sets the audio host to use [default: the default host]")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("direction")
+                            .long("direction")
+                            .value_name("DIRECTION")
+                            .possible_values(&["input", "output"])
+                            .default_value("input")
+                            .help("Whether to record from or play to the device"),
+                    )
+                    .arg(
+                        Arg::with_name("seconds")
+                            .long("seconds")
+                            .value_name("SECONDS")
+                            .default_value("2")
+                            .help("How long to run the stream"),
+                    )
+                    .arg(
+                        Arg::with_name("sample-rate")
+                            .long("sample-rate")
+                            .value_name("HZ")
+                            .takes_value(true)
+                            .help("Sample rate of the stream [default: the rate of the device]"),
+                    )
+                    .arg(
+                        Arg::with_name("buffer-frames")
+                            .long("buffer-frames")
+                            .value_name("FRAMES")
+                            .takes_value(true)
+                            .help("Buffer size to ask for [default: chosen by the device]"),
+                    )
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .possible_values(&["text", "json"])
+                            .default_value("text")
+                            .help("Output format"),
+                    ),
+            )
+            .get_matches();
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    match matches.subcommand() {
+        ("devices", Some(devices_matches)) => {
+            return list_devices(
+                devices_matches.value_of("host_id"),
+                devices_matches.value_of("format") == Some("json"),
+            )
+        }
+        ("probe", Some(probe_matches)) => return probe_device(probe_matches),
+        ("replay", Some(replay_matches)) => return replay_recording(replay_matches),
+        ("eval", Some(eval_matches)) => {
+            return evaluate_corpus(&load_config(&matches)?, eval_matches)
+        }
+        _ => {}
+    }
+    if matches.is_present("list_devices") {
+        return list_devices(matches.value_of("host_id"), false);
+    }
 
-    let _ = processing_thread.kill();
+    let mut config = load_config(&matches)?;
+    if matches.is_present("print_config") {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
 
-    drop(input_stream);
-    drop(capture_stream);
-    drop(output_stream);
+    let signals = Signals::register()?;
+    // as a service there is no one at the terminal; the signals are the only way to stop
+    let exit_requests = if matches.is_present("daemon") {
+        None
+    } else {
+        let (exit_sender, exit_receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = stdin().read_line(&mut String::new());
+            let _ = exit_sender.send(());
+        });
+        Some(exit_receiver)
+    };
+    while let Stop::Restart(new_config) = run(config, &matches, &signals, exit_requests.as_ref())? {
+        info!("Restarting with the reloaded configuration");
+        config = *new_config;
+    }
 
-    println!("Done!");
+    info!("Done!");
     Ok(())
 }
-
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
-}
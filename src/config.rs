@@ -0,0 +1,592 @@
+//! Configuration of the whole pipeline, read from a TOML file.
+//!
+//! The top level of the file holds the base configuration; every key is optional and falls back
+//! to the built-in default. Named profiles under `[profiles.<name>]` use the same layout and only
+//! need to list what differs from the base, e.g.
+//!
+//! ```toml
+//! [filter]
+//! taps = 1024
+//!
+//! [profiles.headset.filter]
+//! taps = 256
+//! ```
+//!
+//! See `raec.example.toml` at the root of the repository for every available key.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::kalman::KalmanParameters;
+use crate::nonlinear::Nonlinearity;
+use crate::path_change::PathChangeDetection;
+use crate::plot::ImageFormat;
+use crate::processing::{AECParameters, Algorithm, Control, OutputMode, Stage, WeightInit};
+use crate::step_size::StepSize;
+use crate::subband::SubbandParameters;
+
+/// Name of the table holding the named profiles
+const PROFILES_KEY: &str = "profiles";
+
+/// The audio devices to use; see the `devices` module for the selection syntax.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DevicesConfig {
+    pub host: Option<String>,
+    pub microphone: Option<String>,
+    pub capture: Option<String>,
+    pub output: Option<String>,
+}
+
+/// Buffering between the audio streams and the processing thread.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+    /// Delay added in case the input and output devices are not synchronised (ms)
+    pub latency_ms: f32,
+    /// Capacity of the buffers between streams (ms); twice the latency when not given
+    pub buffer_ms: Option<f32>,
+    /// Sample rate of all the streams (Hz); the default rate of the microphone when not given
+    pub sample_rate: Option<u32>,
+    /// Size of the buffers of the audio devices (frames); chosen by each device when not given
+    pub buffer_frames: Option<u32>,
+    /// A stream without callbacks for this long is rebuilt (ms)
+    pub stall_timeout_ms: f32,
+    /// Longest acceptable delay from the microphone to the output (ms); see the `latency`
+    /// module. The microphone and reference buffers are dropped, to line up again, when the
+    /// microphone buffer makes the delay go over; at most once a minute.
+    pub max_latency_ms: Option<f32>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            latency_ms: 100.0,
+            buffer_ms: None,
+            sample_rate: None,
+            buffer_frames: None,
+            stall_timeout_ms: 500.0,
+            max_latency_ms: None,
+        }
+    }
+}
+
+impl StreamConfig {
+    pub fn buffer_ms(&self) -> f32 {
+        self.buffer_ms.unwrap_or(2.0 * self.latency_ms)
+    }
+}
+
+/// The adaptive filter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub algorithm: Algorithm,
+    /// Number of taps; must be a multiple of 8
+    pub taps: usize,
+    /// Step size; the largest one with the `variable` step size control
+    pub mu: f32,
+    /// Regularisation of the normalisation of the step size
+    pub eps: f32,
+    /// The weights are only adapted while the novelty is below this threshold
+    pub novelty_threshold: f32,
+    /// How the weights start out
+    pub initial_weights: WeightInit,
+    /// The nonlinear part of the echo path model; see the `nonlinear` module
+    pub nonlinearity: Nonlinearity,
+    /// How the step size follows the residual; see the `step_size` module
+    pub step_size: StepSize,
+    /// Detection of changes of the echo path; see the `path_change` module
+    pub path_change: PathChangeDetection,
+    /// The filter bank of the `subband` algorithm; see the `subband` module
+    pub subband: SubbandParameters,
+    /// The blocks and echo path model of the `kalman` algorithm; see the `kalman` module
+    pub kalman: KalmanParameters,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        let parameters = AECParameters::default();
+        FilterConfig {
+            algorithm: parameters.algorithm,
+            taps: parameters.taps,
+            mu: parameters.mu,
+            eps: parameters.eps,
+            novelty_threshold: parameters.novelty_threshold,
+            initial_weights: WeightInit::default(),
+            nonlinearity: parameters.nonlinearity,
+            step_size: parameters.step_size,
+            path_change: parameters.path_change,
+            subband: parameters.subband,
+            kalman: parameters.kalman,
+        }
+    }
+}
+
+/// A filter stage applied to the output of the echo canceller.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StageConfig {
+    pub enabled: bool,
+    pub cutoff_hz: f32,
+}
+
+impl StageConfig {
+    fn cutoff(&self) -> Option<f32> {
+        if self.enabled {
+            Some(self.cutoff_hz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Filters applied to the output of the echo canceller, and what is output.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostProcessingConfig {
+    pub output_mode: OutputMode,
+    pub highpass: StageConfig,
+    pub lowpass: StageConfig,
+}
+
+impl Default for PostProcessingConfig {
+    fn default() -> Self {
+        let parameters = AECParameters::default();
+        PostProcessingConfig {
+            output_mode: parameters.output_mode,
+            highpass: StageConfig {
+                enabled: parameters.highpass_hz.is_some(),
+                cutoff_hz: parameters.highpass_hz.unwrap_or(300.0),
+            },
+            lowpass: StageConfig {
+                enabled: parameters.lowpass_hz.is_some(),
+                cutoff_hz: parameters.lowpass_hz.unwrap_or(3400.0),
+            },
+        }
+    }
+}
+
+/// What is reported about the running filter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// Number of processed samples between two telemetry messages
+    pub interval_samples: usize,
+    /// Number of processed samples between two snapshots of the filter weights
+    pub weights_snapshot_interval: usize,
+    /// Plot buffer levels and echo return loss enhancement
+    pub plot: bool,
+    /// Plot the spectrograms of microphone, reference and output
+    pub spectrogram: bool,
+    /// Plot the impulse and frequency response of the adaptive filter
+    pub weights: bool,
+    /// Collect the plots without opening any window
+    pub headless: bool,
+    /// On exit, write the plots as images into this directory
+    pub plot_dir: Option<PathBuf>,
+    pub plot_format: ImageFormat,
+    /// Record every session into a new directory under this one; see the `recorder` module
+    pub record_dir: Option<PathBuf>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        let parameters = AECParameters::default();
+        TelemetryConfig {
+            interval_samples: parameters.telemetry_interval,
+            weights_snapshot_interval: parameters.weights_snapshot_interval,
+            plot: false,
+            spectrogram: false,
+            weights: false,
+            headless: false,
+            plot_dir: None,
+            plot_format: ImageFormat::Png,
+            record_dir: None,
+        }
+    }
+}
+
+/// The configuration of the whole pipeline.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub devices: DevicesConfig,
+    pub stream: StreamConfig,
+    pub filter: FilterConfig,
+    pub post_processing: PostProcessingConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file, applying the named profile if any.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Config, anyhow::Error> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!(
+                "Could not read configuration file {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        Config::from_toml(&text, profile)
+            .map_err(|e| anyhow::anyhow!("In configuration file {}: {}", path.display(), e))
+    }
+
+    /// Parses a configuration, applying the named profile on top of the base configuration.
+    pub fn from_toml(text: &str, profile: Option<&str>) -> Result<Config, anyhow::Error> {
+        let mut file = match text.parse::<toml::Value>()? {
+            toml::Value::Table(table) => table,
+            _ => unreachable!("a TOML document is always a table"),
+        };
+        let profiles = match file.remove(PROFILES_KEY) {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => return Err(anyhow::anyhow!("\"{}\" must be a table", PROFILES_KEY)),
+            None => toml::value::Table::new(),
+        };
+        // start from the defaults so that a partially given table keeps the other defaults
+        let mut base = match toml::Value::try_from(Config::default())? {
+            toml::Value::Table(table) => table,
+            _ => unreachable!("the configuration is a struct"),
+        };
+        merge(&mut base, &file);
+        if let Some(name) = profile {
+            let overrides = profiles.get(name).ok_or_else(|| {
+                let mut names: Vec<&str> = profiles.keys().map(|k| k.as_str()).collect();
+                names.sort_unstable();
+                anyhow::anyhow!(
+                    "Unknown profile \"{}\"; available profiles: {}",
+                    name,
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                )
+            })?;
+            match overrides {
+                toml::Value::Table(overrides) => merge(&mut base, overrides),
+                _ => return Err(anyhow::anyhow!("Profile \"{}\" must be a table", name)),
+            }
+        }
+        let config: Config = toml::Value::Table(base).try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Names of the profiles defined in a configuration.
+    pub fn profiles(text: &str) -> Result<Vec<String>, anyhow::Error> {
+        let value = text.parse::<toml::Value>()?;
+        let mut names: Vec<String> = value
+            .get(PROFILES_KEY)
+            .and_then(|profiles| profiles.as_table())
+            .map(|profiles| profiles.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Checks the values which would otherwise only fail once the streams are running.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.filter.taps == 0 || !self.filter.taps.is_multiple_of(8) {
+            return Err(anyhow::anyhow!(
+                "filter.taps must be a positive multiple of 8, not {}",
+                self.filter.taps
+            ));
+        }
+        self.filter
+            .nonlinearity
+            .validate()
+            .map_err(|e| anyhow::anyhow!("filter.nonlinearity: {}", e))?;
+        self.filter
+            .step_size
+            .validate()
+            .map_err(|e| anyhow::anyhow!("filter.step_size: {}", e))?;
+        self.filter
+            .path_change
+            .validate()
+            .map_err(|e| anyhow::anyhow!("filter.path_change: {}", e))?;
+        self.filter
+            .subband
+            .validate()
+            .map_err(|e| anyhow::anyhow!("filter.subband: {}", e))?;
+        self.filter
+            .kalman
+            .validate()
+            .map_err(|e| anyhow::anyhow!("filter.kalman: {}", e))?;
+        if self.stream.latency_ms <= 0.0 {
+            return Err(anyhow::anyhow!("stream.latency_ms must be positive"));
+        }
+        if self.stream.buffer_ms() <= self.stream.latency_ms {
+            return Err(anyhow::anyhow!(
+                "stream.buffer_ms ({}) must be larger than stream.latency_ms ({})",
+                self.stream.buffer_ms(),
+                self.stream.latency_ms
+            ));
+        }
+        if let Some(budget) = self.stream.max_latency_ms {
+            // the microphone and output buffers both start with the latency
+            if budget <= 2.0 * self.stream.latency_ms {
+                return Err(anyhow::anyhow!(
+                    "stream.max_latency_ms ({}) must be larger than twice stream.latency_ms ({}), \
+                     which the buffers hold from the start",
+                    budget,
+                    self.stream.latency_ms
+                ));
+            }
+        }
+        if self.stream.stall_timeout_ms <= 0.0 {
+            return Err(anyhow::anyhow!("stream.stall_timeout_ms must be positive"));
+        }
+        if self.telemetry.interval_samples == 0 || self.telemetry.weights_snapshot_interval == 0 {
+            return Err(anyhow::anyhow!("telemetry intervals must be positive"));
+        }
+        if self.telemetry.weights && self.filter.algorithm == Algorithm::Subband {
+            return Err(anyhow::anyhow!(
+                "telemetry.weights: the subband algorithm has no time domain weights to show"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The configuration as a TOML document, e.g. to share the settings in use.
+    pub fn to_toml(&self) -> Result<String, anyhow::Error> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// The parameters of the processing thread described by this configuration, for streams
+    /// running at `sample_rate` (Hz).
+    pub fn aec_parameters(&self, sample_rate: f32) -> AECParameters {
+        AECParameters {
+            sample_rate,
+            algorithm: self.filter.algorithm,
+            taps: self.filter.taps,
+            mu: self.filter.mu,
+            eps: self.filter.eps,
+            novelty_threshold: self.filter.novelty_threshold,
+            highpass_hz: self.post_processing.highpass.cutoff(),
+            lowpass_hz: self.post_processing.lowpass.cutoff(),
+            telemetry_interval: self.telemetry.interval_samples,
+            weights_snapshot_interval: self.telemetry.weights_snapshot_interval,
+            output_mode: self.post_processing.output_mode,
+            nonlinearity: self.filter.nonlinearity.clone(),
+            step_size: self.filter.step_size.clone(),
+            path_change: self.filter.path_change.clone(),
+            subband: self.filter.subband.clone(),
+            kalman: self.filter.kalman.clone(),
+        }
+    }
+
+    /// The controls turning a pipeline running with this configuration, and built with `built`,
+    /// into one running with `new`, or `None` when some of the changes need the pipeline to be
+    /// built again.
+    ///
+    /// The filter step size, regularisation and novelty threshold and the output mode can be
+    /// changed live, as can the post-processing stages as long as their cutoff stays the one they
+    /// were built with. This configuration may have been reached through live changes already, so
+    /// the cutoffs come from `built` rather than from it. The latency budget only matters to
+    /// whoever watches the pipeline and needs no control.
+    pub fn live_changes(&self, new: &Config, built: &AECParameters) -> Option<Vec<Control>> {
+        let defaults = PostProcessingConfig::default();
+        let mut controls = vec![];
+        let mut unchanged = new.clone();
+        unchanged.stream.max_latency_ms = self.stream.max_latency_ms;
+        let live_values = [
+            (
+                self.filter.mu,
+                &mut unchanged.filter.mu,
+                Control::SetMu as fn(f32) -> Control,
+            ),
+            (self.filter.eps, &mut unchanged.filter.eps, Control::SetEps),
+            (
+                self.filter.novelty_threshold,
+                &mut unchanged.filter.novelty_threshold,
+                Control::SetNoveltyThreshold,
+            ),
+        ];
+        for (old, new, control) in live_values {
+            if old != *new {
+                controls.push(control(*new));
+                *new = old;
+            }
+        }
+        if self.post_processing.output_mode != new.post_processing.output_mode {
+            controls.push(Control::SetOutputMode(new.post_processing.output_mode));
+            unchanged.post_processing.output_mode = self.post_processing.output_mode;
+        }
+        let stages = [
+            (
+                Stage::HighPass,
+                &self.post_processing.highpass,
+                &mut unchanged.post_processing.highpass,
+                built.highpass_hz.unwrap_or(defaults.highpass.cutoff_hz),
+            ),
+            (
+                Stage::LowPass,
+                &self.post_processing.lowpass,
+                &mut unchanged.post_processing.lowpass,
+                built.lowpass_hz.unwrap_or(defaults.lowpass.cutoff_hz),
+            ),
+        ];
+        // a stage disabled from the start was built with the default cutoff
+        for (stage, old, new, built_hz) in stages {
+            if old.enabled != new.enabled && new.cutoff_hz == built_hz {
+                controls.push(Control::EnableStage(stage, new.enabled));
+                *new = old.clone();
+            }
+        }
+        if &unchanged == self {
+            Some(controls)
+        } else {
+            None
+        }
+    }
+}
+
+/// Recursively replaces the values of `base` by those of `overrides`; tables are merged key by key.
+fn merge(base: &mut toml::value::Table, overrides: &toml::value::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge(base, overrides)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../raec.example.toml");
+
+    #[test]
+    fn test_profiles() {
+        let base = Config::from_toml(EXAMPLE, None).unwrap();
+        assert_eq!(base.filter.taps, 1024);
+        assert_eq!(
+            Config::profiles(EXAMPLE).unwrap(),
+            vec!["conference-room", "headset"]
+        );
+
+        let headset = Config::from_toml(EXAMPLE, Some("headset")).unwrap();
+        assert_eq!(headset.filter.taps, 256);
+        assert!(!headset.post_processing.lowpass.enabled);
+        // values not in the profile come from the base configuration
+        assert_eq!(headset.filter.eps, base.filter.eps);
+        assert_eq!(
+            headset.post_processing.highpass,
+            base.post_processing.highpass
+        );
+
+        let room = Config::from_toml(EXAMPLE, Some("conference-room")).unwrap();
+        assert!(room.filter.taps > base.filter.taps);
+        assert!(room.stream.buffer_ms() > room.stream.latency_ms);
+
+        assert!(Config::from_toml(EXAMPLE, Some("kitchen")).is_err());
+    }
+
+    #[test]
+    fn test_defaults_and_errors() {
+        let config = Config::from_toml("", None).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.aec_parameters(48_000.0), AECParameters::default());
+        // the printed configuration reads back the same
+        let printed = config.to_toml().unwrap();
+        assert_eq!(Config::from_toml(&printed, None).unwrap(), config);
+
+        // misspelled keys are reported instead of silently ignored
+        assert!(Config::from_toml("[filter]\ntapz = 8", None).is_err());
+        assert!(Config::from_toml("[filter]\ntaps = 100", None).is_err());
+        assert!(Config::from_toml("[stream]\nbuffer_ms = 50", None).is_err());
+        // the buffers alone hold twice the latency
+        assert!(Config::from_toml("[stream]\nmax_latency_ms = 150", None).is_err());
+        assert!(Config::from_toml("[stream]\nmax_latency_ms = 250", None).is_ok());
+        assert!(Config::from_toml("[filter.nonlinearity]\nmodel = \"cubic\"", None).is_err());
+        assert!(Config::from_toml("[filter.nonlinearity]\nclip_level = 0.0", None).is_err());
+        assert!(Config::from_toml("[filter.step_size]\ncontrol = \"adaptive\"", None).is_err());
+        assert!(Config::from_toml("[filter.step_size]\nsmoothing_ms = 0.0", None).is_err());
+        assert!(Config::from_toml("[filter.kalman]\ntransition = 1.5", None).is_err());
+        assert!(Config::from_toml("[filter.path_change]\nmu = 0.0", None).is_err());
+        assert!(Config::from_toml(
+            "[filter]\nalgorithm = \"subband\"\n[telemetry]\nweights = true",
+            None
+        )
+        .is_err());
+
+        // a partially given table keeps the defaults of the other keys
+        let config = Config::from_toml("[post_processing.lowpass]\nenabled = false", None).unwrap();
+        assert_eq!(config.aec_parameters(48_000.0).lowpass_hz, None);
+        assert_eq!(config.post_processing.lowpass.cutoff_hz, 3400.0);
+
+        let config =
+            Config::from_toml("[filter]\ninitial_weights = { seeded-random = 42 }", None).unwrap();
+        assert_eq!(config.filter.initial_weights, WeightInit::SeededRandom(42));
+        assert_eq!(
+            Config::from_toml(&config.to_toml().unwrap(), None).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn test_live_changes() {
+        let base = Config::from_toml(EXAMPLE, None).unwrap();
+        let built = base.aec_parameters(48_000.0);
+        assert_eq!(base.live_changes(&base, &built), Some(vec![]));
+
+        let mut tuned = base.clone();
+        tuned.filter.mu = 0.5;
+        tuned.filter.novelty_threshold = 0.01;
+        tuned.post_processing.lowpass.enabled = false;
+        tuned.post_processing.output_mode = OutputMode::Bypass;
+        tuned.stream.max_latency_ms = Some(300.0);
+        assert_eq!(
+            base.live_changes(&tuned, &built),
+            Some(vec![
+                Control::SetMu(0.5),
+                Control::SetNoveltyThreshold(0.01),
+                Control::SetOutputMode(OutputMode::Bypass),
+                Control::EnableStage(Stage::LowPass, false),
+            ])
+        );
+        // switched back on with the cutoff it was built with
+        assert_eq!(
+            tuned.live_changes(&base, &built),
+            Some(vec![
+                Control::SetMu(1.0),
+                Control::SetNoveltyThreshold(0.0025),
+                Control::SetOutputMode(OutputMode::Processing),
+                Control::EnableStage(Stage::LowPass, true),
+            ])
+        );
+
+        // the rest needs a new pipeline
+        let mut resized = base.clone();
+        resized.filter.taps = 2048;
+        assert_eq!(base.live_changes(&resized, &built), None);
+        let mut moved = base.clone();
+        moved.post_processing.highpass.cutoff_hz = 100.0;
+        assert_eq!(base.live_changes(&moved, &built), None);
+        let headset = Config::from_toml(EXAMPLE, Some("headset")).unwrap();
+        assert_eq!(base.live_changes(&headset, &built), None);
+
+        // built at 200 Hz, then switched off live: switching it back on at another cutoff needs
+        // a new pipeline, even though that is the cutoff it is disabled at
+        let mut built_at_200 = base.clone();
+        built_at_200.post_processing.highpass.cutoff_hz = 200.0;
+        let built = built_at_200.aec_parameters(48_000.0);
+        let mut disabled = built_at_200.clone();
+        disabled.post_processing.highpass.enabled = false;
+        assert_eq!(
+            built_at_200.live_changes(&disabled, &built),
+            Some(vec![Control::EnableStage(Stage::HighPass, false)])
+        );
+        assert_eq!(disabled.live_changes(&base, &built), None);
+        assert_eq!(
+            disabled.live_changes(&built_at_200, &built),
+            Some(vec![Control::EnableStage(Stage::HighPass, true)])
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Selection of audio devices from what the user wrote on the command line.
+//!
+//! A device can be asked for by its exact name, by a case-insensitive part of its name, by a
+//! regular expression written between slashes (e.g. `/^USB.*Headset$/`), or with one of the
+//! keywords `default-input` and `default-output`. Only devices supporting the needed direction
+//! are considered, so an input and an output device sharing a name do not clash.
+//!
+//! Each device also has an identifier made of the host and device names (see `device_ids`),
+//! listed by `raec devices`; a selector equal to one of them picks that device. It stays the same
+//! across runs and reboots, except for devices sharing a name with others.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::streams::ConfigRange;
+
+/// Keyword selecting the default input device of the host
+pub const DEFAULT_INPUT: &str = "default-input";
+/// Keyword selecting the default output device of the host
+pub const DEFAULT_OUTPUT: &str = "default-output";
+
+/// Whether a device is needed to record or to play audio.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
+}
+
+/// A parsed device selection.
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    /// The default input device of the host
+    DefaultInput,
+    /// The default output device of the host
+    DefaultOutput,
+    /// A name matched exactly, or else as a case-insensitive substring
+    Name(String),
+    /// A regular expression the device name must match
+    Pattern(Regex),
+}
+
+impl std::str::FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let selector = match s {
+            DEFAULT_INPUT => DeviceSelector::DefaultInput,
+            DEFAULT_OUTPUT => DeviceSelector::DefaultOutput,
+            _ if s.len() > 1 && s.starts_with('/') && s.ends_with('/') => {
+                let pattern = &s[1..s.len() - 1];
+                DeviceSelector::Pattern(Regex::new(pattern).map_err(|e| {
+                    anyhow::anyhow!("Invalid device pattern \"{}\": {}", pattern, e)
+                })?)
+            }
+            _ => DeviceSelector::Name(s.to_string()),
+        };
+        Ok(selector)
+    }
+}
+
+impl DeviceSelector {
+    /// Picks the index of the only name matching the selector among `names`.
+    ///
+    /// An exact match always wins over substring matches. `default_name` is the name of the
+    /// host default device for the direction the candidates were collected for, if any.
+    pub fn select(
+        &self,
+        names: &[String],
+        default_name: Option<&str>,
+    ) -> Result<usize, anyhow::Error> {
+        let matches: Vec<usize> = match self {
+            DeviceSelector::DefaultInput | DeviceSelector::DefaultOutput => {
+                let default_name = default_name
+                    .ok_or_else(|| anyhow::anyhow!("The host has no {} device", self))?;
+                // the default device may share its name with other devices; take the first one
+                return names
+                    .iter()
+                    .position(|name| name == default_name)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("The {} device \"{}\" is not available", self, default_name)
+                    });
+            }
+            DeviceSelector::Name(wanted) => {
+                let exact: Vec<usize> = (0..names.len()).filter(|&i| &names[i] == wanted).collect();
+                if !exact.is_empty() {
+                    exact
+                } else {
+                    let wanted = wanted.to_lowercase();
+                    (0..names.len())
+                        .filter(|&i| names[i].to_lowercase().contains(&wanted))
+                        .collect()
+                }
+            }
+            DeviceSelector::Pattern(regex) => (0..names.len())
+                .filter(|&i| regex.is_match(&names[i]))
+                .collect(),
+        };
+        match matches.as_slice() {
+            [index] => Ok(*index),
+            [] => Err(anyhow::anyhow!(
+                "No device matches {}; available devices:{}",
+                self,
+                candidate_list(names.iter())
+            )),
+            _ => Err(anyhow::anyhow!(
+                "{} matches several devices, be more specific:{}",
+                self,
+                candidate_list(matches.iter().map(|&i| &names[i]))
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::DefaultInput => write!(f, "default input"),
+            DeviceSelector::DefaultOutput => write!(f, "default output"),
+            DeviceSelector::Name(name) => write!(f, "\"{}\"", name),
+            DeviceSelector::Pattern(regex) => write!(f, "/{}/", regex.as_str()),
+        }
+    }
+}
+
+fn candidate_list<'a>(names: impl Iterator<Item = &'a String>) -> String {
+    names.map(|name| format!("\n  \"{}\"", name)).collect()
+}
+
+/// Whether the device can be opened in the given direction.
+pub fn supports(device: &cpal::Device, direction: Direction) -> bool {
+    match direction {
+        Direction::Input => device
+            .supported_input_configs()
+            .map(|mut configs| configs.next().is_some())
+            .unwrap_or(false),
+        Direction::Output => device
+            .supported_output_configs()
+            .map(|mut configs| configs.next().is_some())
+            .unwrap_or(false),
+    }
+}
+
+/// Identifiers of the devices of a host, given the names of all its devices in the order the host
+/// lists them.
+///
+/// The identifier is `<host>/<device name>`, lower case host; a name shared by several devices
+/// gets a `#2`, `#3`... suffix on its second, third... occurrence. The host gives nothing more
+/// persistent to tell such devices apart, so the suffix follows the order in which the host
+/// enumerates them: plugging in or removing one of them may change the identifiers of the others.
+pub fn device_ids(host: &str, names: &[String]) -> Vec<String> {
+    let host = host.to_lowercase();
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let occurrence = names[..i].iter().filter(|other| *other == name).count() + 1;
+            if occurrence == 1 {
+                format!("{}/{}", host, name)
+            } else {
+                format!("{}/{}#{}", host, name, occurrence)
+            }
+        })
+        .collect()
+}
+
+/// Finds the device of `host` described by `selector` among those supporting `direction`.
+pub fn find_device(
+    host: &cpal::Host,
+    selector: &str,
+    direction: Direction,
+) -> Result<cpal::Device, anyhow::Error> {
+    // an identifier from `raec devices` names a single device, duplicates included
+    let mut all_devices: Vec<cpal::Device> = host.devices()?.collect();
+    let all_names = all_devices
+        .iter()
+        .map(|device| device.name())
+        .collect::<Result<Vec<String>, _>>()?;
+    let ids = device_ids(host.id().name(), &all_names);
+    if let Some(index) = ids.iter().position(|id| id == selector) {
+        let device = all_devices.swap_remove(index);
+        if !supports(&device, direction) {
+            return Err(anyhow::anyhow!(
+                "The device {} does not support {}",
+                selector,
+                direction
+            ));
+        }
+        return Ok(device);
+    }
+
+    let selector: DeviceSelector = selector.parse()?;
+    let mut devices: Vec<cpal::Device> = host
+        .devices()?
+        .filter(|device| supports(device, direction))
+        .collect();
+    let names = devices
+        .iter()
+        .map(|device| device.name())
+        .collect::<Result<Vec<String>, _>>()?;
+    let default_device = match selector {
+        DeviceSelector::DefaultInput => host.default_input_device(),
+        DeviceSelector::DefaultOutput => host.default_output_device(),
+        _ => None,
+    };
+    let default_name = default_device.map(|device| device.name()).transpose()?;
+    let index = selector
+        .select(&names, default_name.as_deref())
+        .map_err(|e| anyhow::anyhow!("Selecting {} device: {}", direction, e))?;
+    Ok(devices.swap_remove(index))
+}
+
+/// A stream configuration in the device listing; the default configuration of a device has a
+/// single sample rate.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// `f32`, `i16` or `u16`
+    pub sample_format: String,
+    /// Range of buffer sizes in frames, when the host knows it
+    pub min_buffer_frames: Option<u32>,
+    pub max_buffer_frames: Option<u32>,
+}
+
+impl From<&ConfigRange> for ConfigInfo {
+    fn from(range: &ConfigRange) -> Self {
+        let (min_buffer_frames, max_buffer_frames) = match range.buffer_size {
+            cpal::SupportedBufferSize::Range { min, max } => (Some(min), Some(max)),
+            cpal::SupportedBufferSize::Unknown => (None, None),
+        };
+        ConfigInfo {
+            channels: range.channels,
+            min_sample_rate: range.min_sample_rate,
+            max_sample_rate: range.max_sample_rate,
+            sample_format: format!("{:?}", range.sample_format).to_lowercase(),
+            min_buffer_frames,
+            max_buffer_frames,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {} channel(s) at ",
+            self.sample_format, self.channels
+        )?;
+        if self.min_sample_rate == self.max_sample_rate {
+            write!(f, "{} Hz", self.min_sample_rate)?;
+        } else {
+            write!(f, "{}-{} Hz", self.min_sample_rate, self.max_sample_rate)?;
+        }
+        if let (Some(min), Some(max)) = (self.min_buffer_frames, self.max_buffer_frames) {
+            write!(f, ", buffers of {}-{} frames", min, max)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a device offers in one direction.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// Whether the device is the default of the host for this direction
+    pub is_default: bool,
+    pub default_config: Option<ConfigInfo>,
+    pub supported_configs: Vec<ConfigInfo>,
+}
+
+/// A device in the listing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    /// Identifier, usable as a device selector; see `device_ids` for when it changes
+    pub id: String,
+    pub name: String,
+    /// The directions with at least one supported configuration
+    pub directions: Vec<Direction>,
+    pub input: Option<Capabilities>,
+    pub output: Option<Capabilities>,
+}
+
+/// A host and its devices in the listing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HostInfo {
+    /// Name to give to `--host`
+    pub name: String,
+    /// Identifiers of the default devices of the host
+    pub default_input: Option<String>,
+    pub default_output: Option<String>,
+    pub devices: Vec<DeviceInfo>,
+}
+
+fn capabilities(
+    device: &cpal::Device,
+    direction: Direction,
+    is_default: bool,
+) -> Option<Capabilities> {
+    let (supported, default): (Vec<ConfigRange>, _) = match direction {
+        Direction::Input => (
+            device
+                .supported_input_configs()
+                .map(|configs| configs.map(|range| ConfigRange::from(&range)).collect())
+                .unwrap_or_default(),
+            device.default_input_config().ok(),
+        ),
+        Direction::Output => (
+            device
+                .supported_output_configs()
+                .map(|configs| configs.map(|range| ConfigRange::from(&range)).collect())
+                .unwrap_or_default(),
+            device.default_output_config().ok(),
+        ),
+    };
+    if supported.is_empty() {
+        return None;
+    }
+    let default_config = default.map(|config| {
+        ConfigInfo::from(&ConfigRange {
+            channels: config.channels(),
+            min_sample_rate: config.sample_rate().0,
+            max_sample_rate: config.sample_rate().0,
+            buffer_size: config.buffer_size().clone(),
+            sample_format: config.sample_format(),
+        })
+    });
+    Some(Capabilities {
+        is_default,
+        default_config,
+        supported_configs: supported.iter().map(ConfigInfo::from).collect(),
+    })
+}
+
+/// Lists the devices of the host and what they support.
+pub fn host_info(host: &cpal::Host) -> Result<HostInfo, anyhow::Error> {
+    let devices: Vec<cpal::Device> = host.devices()?.collect();
+    let names = devices
+        .iter()
+        .map(|device| device.name())
+        .collect::<Result<Vec<String>, _>>()?;
+    let ids = device_ids(host.id().name(), &names);
+    // the default devices are told apart from namesakes by their direction, as in `find_device`
+    let default_id = |default: Option<cpal::Device>, direction| -> Result<_, anyhow::Error> {
+        let default_name = match default {
+            Some(device) => device.name()?,
+            None => return Ok(None),
+        };
+        Ok((0..devices.len())
+            .find(|&i| names[i] == default_name && supports(&devices[i], direction))
+            .map(|i| ids[i].clone()))
+    };
+    let default_input = default_id(host.default_input_device(), Direction::Input)?;
+    let default_output = default_id(host.default_output_device(), Direction::Output)?;
+
+    let devices = devices
+        .iter()
+        .zip(names)
+        .zip(&ids)
+        .map(|((device, name), id)| {
+            let input = capabilities(device, Direction::Input, default_input.as_ref() == Some(id));
+            let output = capabilities(
+                device,
+                Direction::Output,
+                default_output.as_ref() == Some(id),
+            );
+            let directions = [(Direction::Input, &input), (Direction::Output, &output)]
+                .iter()
+                .filter(|(_, capabilities)| capabilities.is_some())
+                .map(|(direction, _)| *direction)
+                .collect();
+            DeviceInfo {
+                id: id.clone(),
+                name,
+                directions,
+                input,
+                output,
+            }
+        })
+        .collect();
+    Ok(HostInfo {
+        name: host.id().name().to_string(),
+        default_input,
+        default_output,
+        devices,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec![
+            "Speakers (Realtek Audio)".to_string(),
+            "Microphone (USB Headset)".to_string(),
+            "Headphones (USB Headset)".to_string(),
+            "CABLE Output (VB-Audio Virtual Cable)".to_string(),
+            "USB".to_string(),
+        ]
+    }
+
+    fn select(selector: &str) -> Result<usize, anyhow::Error> {
+        selector
+            .parse::<DeviceSelector>()?
+            .select(&names(), Some("Speakers (Realtek Audio)"))
+    }
+
+    #[test]
+    fn test_select_by_name() {
+        assert_eq!(select("Speakers (Realtek Audio)").unwrap(), 0);
+        assert_eq!(select("realtek").unwrap(), 0);
+        assert_eq!(select("cable output").unwrap(), 3);
+        // an exact name wins over the substring matches
+        assert_eq!(select("USB").unwrap(), 4);
+        assert_eq!(select("default-output").unwrap(), 0);
+        assert_eq!(select("/^Micro.*Headset\\)$/").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_select_errors() {
+        let ambiguous = select("headset").unwrap_err().to_string();
+        assert!(ambiguous.contains("Microphone (USB Headset)"));
+        assert!(ambiguous.contains("Headphones (USB Headset)"));
+        assert!(!ambiguous.contains("Realtek"));
+
+        let missing = select("bluetooth").unwrap_err().to_string();
+        assert!(missing.contains("Realtek"));
+
+        assert!(select("/[unclosed/").is_err());
+        assert!(DeviceSelector::DefaultInput.select(&names(), None).is_err());
+    }
+
+    #[test]
+    fn test_device_ids() {
+        let mut names = names();
+        names.insert(2, "USB".to_string());
+        names.push("USB".to_string());
+        assert_eq!(
+            device_ids("ALSA", &names),
+            vec![
+                "alsa/Speakers (Realtek Audio)",
+                "alsa/Microphone (USB Headset)",
+                "alsa/USB",
+                "alsa/Headphones (USB Headset)",
+                "alsa/CABLE Output (VB-Audio Virtual Cable)",
+                "alsa/USB#2",
+                "alsa/USB#3",
+            ]
+        );
+    }
+}
@@ -0,0 +1,731 @@
+//! Scoring of the echo cancellation over a corpus, to tell whether a change helps or hurts.
+//!
+//! A corpus is a directory holding one case per entry:
+//!
+//! - a directory with `mic.wav` and `reference.wav`, recorded or prepared elsewhere, and
+//!   optionally `near_end.wav`, the near-end talk alone as picked up by the microphone;
+//! - a `.toml` file describing a `simulation::Scenario`, generated on the fly.
+//!
+//! Each case is run through `AECFiltering::process_offline` and scored with:
+//!
+//! - the echo return loss enhancement (ERLE), over the frames where the far end talks alone;
+//! - the convergence time, from which the ERLE stays within `CONVERGENCE_MARGIN_DB` of its final
+//!   value;
+//! - the segmental SNR of the output against the clean near-end talk, when it is known, which
+//!   measures how much of the near-end talk the cancellation damages;
+//! - the real-time factor, the processing time over the duration of the signals.
+//!
+//! A case without a clean near-end signal is assumed to hold no near-end talk at all.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::processing::{AECFiltering, AECParameters, OutputMode, WeightInit};
+use crate::simulation::{self, EchoPathChange, NearEnd, Room, Scenario, Source};
+
+pub const MICROPHONE_FILE: &str = "mic.wav";
+pub const REFERENCE_FILE: &str = "reference.wav";
+pub const NEAR_END_FILE: &str = "near_end.wav";
+
+/// Length of the frames the signals are scored over (ms)
+const FRAME_MS: f32 = 20.0;
+/// A frame more than this below the loudest frame of its signal is considered silent (dB)
+const SILENCE_DB: f32 = -40.0;
+/// Range the SNR of each frame is clamped to before averaging, so that a silent or perfect frame
+/// does not dominate (dB)
+const SEGMENTAL_SNR_RANGE_DB: (f64, f64) = (-10.0, 35.0);
+/// The filter is converged once the ERLE stays within this of its final value (dB)
+const CONVERGENCE_MARGIN_DB: f64 = 3.0;
+/// Number of frames the ERLE is measured over when looking for convergence
+const CONVERGENCE_FRAMES: usize = 5;
+/// A drop of the ERLE or segmental SNR by more than this is a regression (dB)
+pub const REGRESSION_TOLERANCE_DB: f64 = 0.5;
+/// A convergence time longer by more than this fraction is a regression
+pub const REGRESSION_TOLERANCE_CONVERGENCE: f64 = 0.1;
+
+/// The signals of a case of a corpus, all of the same length.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub sample_rate: u32,
+    pub microphone: Vec<f32>,
+    pub reference: Vec<f32>,
+    /// The near-end talk alone as picked up by the microphone, when known
+    pub near_end: Option<Vec<f32>>,
+}
+
+impl Case {
+    pub fn from_scenario(name: &str, scenario: &Scenario) -> Result<Case, anyhow::Error> {
+        let simulation = scenario
+            .generate()
+            .map_err(|e| anyhow::anyhow!("In scenario {}: {}", name, e))?;
+        Ok(Case {
+            name: name.to_string(),
+            sample_rate: simulation.sample_rate,
+            microphone: simulation.microphone,
+            reference: simulation.far_end,
+            near_end: Some(simulation.near_end),
+        })
+    }
+
+    /// Reads the WAV files of a case directory; the signals are cut to the shortest of them.
+    pub fn load(directory: &Path) -> Result<Case, anyhow::Error> {
+        let read = |file: &str| -> Result<(Vec<f32>, u32), anyhow::Error> {
+            let path = directory.join(file);
+            let sample_rate = hound::WavReader::open(&path)
+                .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?
+                .spec()
+                .sample_rate;
+            Ok((simulation::read_wav(&path, sample_rate)?, sample_rate))
+        };
+        let (mut microphone, sample_rate) = read(MICROPHONE_FILE)?;
+        let (mut reference, reference_rate) = read(REFERENCE_FILE)?;
+        let mut near_end = match directory.join(NEAR_END_FILE).exists() {
+            true => Some(read(NEAR_END_FILE)?),
+            false => None,
+        };
+        let rates_differ = near_end
+            .as_ref()
+            .is_some_and(|(_, rate)| *rate != sample_rate);
+        if reference_rate != sample_rate || rates_differ {
+            return Err(anyhow::anyhow!(
+                "The files of {} do not all have the same sample rate",
+                directory.display()
+            ));
+        }
+        let length = microphone.len().min(reference.len()).min(
+            near_end
+                .as_ref()
+                .map_or(usize::MAX, |(signal, _)| signal.len()),
+        );
+        microphone.truncate(length);
+        reference.truncate(length);
+        if let Some((signal, _)) = near_end.as_mut() {
+            signal.truncate(length);
+        }
+        Ok(Case {
+            name: directory
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            sample_rate,
+            microphone,
+            reference,
+            near_end: near_end.map(|(signal, _)| signal),
+        })
+    }
+
+    pub fn duration_s(&self) -> f64 {
+        self.microphone.len() as f64 / self.sample_rate as f64
+    }
+}
+
+/// Reads the cases of a corpus directory, in the order of their names.
+pub fn load_corpus(directory: &Path) -> Result<Vec<Case>, anyhow::Error> {
+    let mut entries = std::fs::read_dir(directory)
+        .map_err(|e| anyhow::anyhow!("Could not read corpus {}: {}", directory.display(), e))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    let mut cases = vec![];
+    for path in entries {
+        if path.is_dir() {
+            cases.push(Case::load(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            let text = std::fs::read_to_string(&path)?;
+            let scenario: Scenario = toml::from_str(&text)
+                .map_err(|e| anyhow::anyhow!("In scenario {}: {}", path.display(), e))?;
+            // SAFETY: a path with an extension has a file name
+            let name = path.file_stem().unwrap().to_string_lossy();
+            cases.push(Case::from_scenario(&name, &scenario)?);
+        }
+    }
+    if cases.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No case found in corpus {}; expected directories with {} and {}, or scenario .toml \
+             files",
+            directory.display(),
+            MICROPHONE_FILE,
+            REFERENCE_FILE
+        ));
+    }
+    Ok(cases)
+}
+
+/// The scenarios evaluated when no corpus is given: single talk in a few rooms, noise, double
+/// talk, an echo path change and a loudspeaker played into saturation.
+pub fn default_scenarios() -> Vec<(&'static str, Scenario)> {
+    let base = Scenario {
+        duration_s: 8.0,
+        ..Scenario::default()
+    };
+    vec![
+        ("single-talk", base.clone()),
+        (
+            "reverberant",
+            Scenario {
+                room: Room {
+                    dimensions: [8.0, 6.0, 3.5],
+                    rt60_s: 0.6,
+                },
+                ..base.clone()
+            },
+        ),
+        (
+            "delayed",
+            Scenario {
+                delay_ms: 40.0,
+                ..base.clone()
+            },
+        ),
+        (
+            "noisy",
+            Scenario {
+                snr_db: Some(15.0),
+                ..base.clone()
+            },
+        ),
+        (
+            "double-talk",
+            Scenario {
+                near_end: Some(NearEnd {
+                    start_s: 4.0,
+                    ..NearEnd::default()
+                }),
+                ..base.clone()
+            },
+        ),
+        (
+            "echo-path-change",
+            Scenario {
+                far_end: Source::WhiteNoise,
+                echo_path_change: Some(EchoPathChange {
+                    at_s: 4.0,
+                    ..EchoPathChange::default()
+                }),
+                ..base.clone()
+            },
+        ),
+        (
+            "clipped-loudspeaker",
+            Scenario {
+                far_end_level_db: -12.0,
+                loudspeaker_saturation: Some(0.3),
+                ..base
+            },
+        ),
+    ]
+}
+
+/// The scores of a case; the measures which could not be taken are `None`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaseReport {
+    pub name: String,
+    pub duration_s: f64,
+    /// Echo return loss enhancement over the frames with far-end talk alone (dB)
+    pub erle_db: Option<f64>,
+    /// Time until the ERLE stays within `CONVERGENCE_MARGIN_DB` of its final value (s)
+    pub convergence_time_s: Option<f64>,
+    /// Average SNR of the output against the clean near-end talk, over the frames with near-end
+    /// talk (dB)
+    pub segmental_snr_db: Option<f64>,
+    /// Processing time over the duration of the signals
+    pub real_time_factor: f64,
+}
+
+/// The scores of a corpus.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub cases: Vec<CaseReport>,
+    /// The mean of the scores of the cases, over those where they could be taken; its duration
+    /// is the total
+    pub mean: CaseReport,
+}
+
+fn mean(values: impl Iterator<Item = Option<f64>>) -> Option<f64> {
+    let values: Vec<f64> = values.flatten().collect();
+    match values.len() {
+        0 => None,
+        n => Some(values.iter().sum::<f64>() / n as f64),
+    }
+}
+
+impl Report {
+    pub fn new(cases: Vec<CaseReport>) -> Self {
+        let mean = CaseReport {
+            name: "mean".to_string(),
+            duration_s: cases.iter().map(|case| case.duration_s).sum(),
+            erle_db: mean(cases.iter().map(|case| case.erle_db)),
+            convergence_time_s: mean(cases.iter().map(|case| case.convergence_time_s)),
+            segmental_snr_db: mean(cases.iter().map(|case| case.segmental_snr_db)),
+            real_time_factor: mean(cases.iter().map(|case| Some(case.real_time_factor)))
+                .unwrap_or_default(),
+        };
+        Report { cases, mean }
+    }
+
+    /// One line per case, then one for the mean, under a header; unknown scores are left empty.
+    pub fn to_csv(&self) -> String {
+        let optional = |value: Option<f64>| value.map_or(String::new(), |v| format!("{:.3}", v));
+        let mut csv =
+            "name,duration_s,erle_db,convergence_time_s,segmental_snr_db,real_time_factor\n"
+                .to_string();
+        for case in self.cases.iter().chain(std::iter::once(&self.mean)) {
+            csv += &format!(
+                "{},{:.3},{},{},{},{:.4}\n",
+                case.name,
+                case.duration_s,
+                optional(case.erle_db),
+                optional(case.convergence_time_s),
+                optional(case.segmental_snr_db),
+                case.real_time_factor
+            );
+        }
+        csv
+    }
+}
+
+/// Energy of each frame of a signal.
+fn frame_energies(signal: &[f32], frame: usize) -> Vec<f64> {
+    signal
+        .chunks(frame)
+        .map(|chunk| chunk.iter().map(|&x| x as f64 * x as f64).sum())
+        .collect()
+}
+
+/// Which frames are not silent.
+fn active_frames(energies: &[f64]) -> Vec<bool> {
+    let loudest = energies.iter().cloned().fold(0.0, f64::max);
+    let threshold = loudest * 10.0_f64.powf(SILENCE_DB as f64 / 10.0);
+    energies
+        .iter()
+        .map(|&e| loudest > 0.0 && e > threshold)
+        .collect()
+}
+
+fn ratio_db(signal: f64, noise: f64) -> f64 {
+    10.0 * (signal / noise.max(f64::MIN_POSITIVE)).log10()
+}
+
+/// Scores the output of the canceller for a case; the real-time factor is left to the caller.
+pub fn score(case: &Case, output: &[f32]) -> CaseReport {
+    let frame = ((FRAME_MS / 1_000.0 * case.sample_rate as f32) as usize).max(1);
+    let subtract = |a: &[f32], b: Option<&Vec<f32>>| -> Vec<f32> {
+        match b {
+            Some(b) => a.iter().zip(b).map(|(x, y)| x - y).collect(),
+            None => a.to_vec(),
+        }
+    };
+    let echo = frame_energies(&subtract(&case.microphone, case.near_end.as_ref()), frame);
+    let residual = frame_energies(&subtract(output, case.near_end.as_ref()), frame);
+    let far_end_active = active_frames(&frame_energies(&case.reference, frame));
+    let near_end_active = match &case.near_end {
+        Some(near_end) => active_frames(&frame_energies(near_end, frame)),
+        None => vec![false; echo.len()],
+    };
+
+    // the frames with far-end talk alone: (index, echo energy, residual energy)
+    let single_talk: Vec<(usize, f64, f64)> = (0..echo.len())
+        .filter(|&i| far_end_active[i] && !near_end_active[i])
+        .map(|i| (i, echo[i], residual[i]))
+        .collect();
+    let erle_of = |frames: &[(usize, f64, f64)]| {
+        let echo: f64 = frames.iter().map(|f| f.1).sum();
+        let residual: f64 = frames.iter().map(|f| f.2).sum();
+        ratio_db(echo, residual)
+    };
+    let erle_db = match single_talk.is_empty() {
+        true => None,
+        false => Some(erle_of(&single_talk)),
+    };
+
+    // the final ERLE is that of the last quarter of the signal
+    let last_quarter = single_talk.partition_point(|f| 4 * f.0 < 3 * echo.len());
+    let convergence_time_s = match &single_talk[last_quarter..] {
+        [] => None,
+        end => {
+            let target = erle_of(end) - CONVERGENCE_MARGIN_DB;
+            let last_below = (0..single_talk.len()).rev().find(|&k| {
+                let window = &single_talk[k..(k + CONVERGENCE_FRAMES).min(single_talk.len())];
+                erle_of(window) < target
+            });
+            let converged_at = match last_below {
+                None => Some(single_talk[0].0),
+                Some(k) => single_talk.get(k + 1).map(|f| f.0),
+            };
+            // a filter which never settles has not converged
+            converged_at
+                .filter(|_| target > 0.0)
+                .map(|i| (i * frame) as f64 / case.sample_rate as f64)
+        }
+    };
+
+    let segmental_snr_db = case.near_end.as_ref().and_then(|near_end| {
+        let clean = frame_energies(near_end, frame);
+        let snrs = (0..clean.len()).filter(|&i| near_end_active[i]).map(|i| {
+            let (min, max) = SEGMENTAL_SNR_RANGE_DB;
+            Some(ratio_db(clean[i], residual[i]).clamp(min, max))
+        });
+        mean(snrs)
+    });
+
+    CaseReport {
+        name: case.name.clone(),
+        duration_s: case.duration_s(),
+        erle_db,
+        convergence_time_s,
+        segmental_snr_db,
+        real_time_factor: 0.0,
+    }
+}
+
+/// Runs a case through a canceller set up from `config` and scores it.
+///
+/// The processed signal is always scored, whatever the output mode of the configuration, and
+/// random initial weights are seeded so that two evaluations of the same code agree.
+pub fn evaluate(case: &Case, config: &Config) -> Result<CaseReport, anyhow::Error> {
+    let parameters = AECParameters {
+        output_mode: OutputMode::Processing,
+        ..config.aec_parameters(case.sample_rate as f32)
+    };
+    let initial_weights = match &config.filter.initial_weights {
+        WeightInit::Random => WeightInit::SeededRandom(0),
+        other => other.clone(),
+    };
+    let mut filter = AECFiltering::offline(&parameters, initial_weights.weights(parameters.taps)?);
+    // flushes what a canceller working on frames still holds, and lines its output up with the
+    // input
+    let padding = vec![0.0; filter.delay()];
+    let start = Instant::now();
+    let mut output = filter.process_offline(
+        &[&case.microphone[..], &padding].concat(),
+        &[&case.reference[..], &padding].concat(),
+    );
+    let elapsed = start.elapsed().as_secs_f64();
+    output.drain(..padding.len());
+    Ok(CaseReport {
+        real_time_factor: elapsed / case.duration_s(),
+        ..score(case, &output)
+    })
+}
+
+/// A score of a case in two reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub case: String,
+    pub measure: &'static str,
+    pub baseline: f64,
+    /// `None` when the measure is lost, e.g. the case no longer converges
+    pub current: Option<f64>,
+    /// Whether the change is worse than the tolerance, or the measure is lost; the real-time
+    /// factor depends too much on the machine to ever count as a regression
+    pub regression: bool,
+}
+
+/// How a report differs from a baseline.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Comparison {
+    pub changes: Vec<Change>,
+    /// Cases of the baseline missing from the report
+    pub missing: Vec<String>,
+    /// Cases of the report missing from the baseline
+    pub added: Vec<String>,
+}
+
+impl Comparison {
+    pub fn new(baseline: &Report, current: &Report) -> Self {
+        let find = |report: &Report, name: &str| -> Option<CaseReport> {
+            report.cases.iter().find(|case| case.name == name).cloned()
+        };
+        let mut comparison = Comparison::default();
+        for case in &baseline.cases {
+            if find(current, &case.name).is_none() {
+                comparison.missing.push(case.name.clone());
+            }
+        }
+        for case in current.cases.iter().chain(std::iter::once(&current.mean)) {
+            let old = match find(baseline, &case.name) {
+                Some(old) => old,
+                None if case.name == current.mean.name => baseline.mean.clone(),
+                None => {
+                    comparison.added.push(case.name.clone());
+                    continue;
+                }
+            };
+            let measures = [
+                ("ERLE (dB)", old.erle_db, case.erle_db),
+                (
+                    "convergence time (s)",
+                    old.convergence_time_s,
+                    case.convergence_time_s,
+                ),
+                (
+                    "segmental SNR (dB)",
+                    old.segmental_snr_db,
+                    case.segmental_snr_db,
+                ),
+                (
+                    "real-time factor",
+                    Some(old.real_time_factor),
+                    Some(case.real_time_factor),
+                ),
+            ];
+            for (measure, baseline, current) in measures.iter().copied() {
+                let baseline = match baseline {
+                    Some(baseline) => baseline,
+                    None => continue,
+                };
+                let regression = match (measure, current) {
+                    (_, None) => true,
+                    ("ERLE (dB)", Some(current)) | ("segmental SNR (dB)", Some(current)) => {
+                        current < baseline - REGRESSION_TOLERANCE_DB
+                    }
+                    ("convergence time (s)", Some(current)) => {
+                        current
+                            > baseline * (1.0 + REGRESSION_TOLERANCE_CONVERGENCE)
+                                + FRAME_MS as f64 / 1_000.0
+                    }
+                    _ => false,
+                };
+                comparison.changes.push(Change {
+                    case: case.name.clone(),
+                    measure,
+                    baseline,
+                    current,
+                    regression,
+                });
+            }
+        }
+        comparison
+    }
+
+    pub fn regressions(&self) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(|change| change.regression)
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.changes {
+            let current = match change.current {
+                Some(current) => format!("{:>10.3} ({:+.3})", current, current - change.baseline),
+                None => format!("{:>10}", "none"),
+            };
+            writeln!(
+                f,
+                "{:<24} {:<22} {:>10.3} -> {}{}",
+                change.case,
+                change.measure,
+                change.baseline,
+                current,
+                if change.regression {
+                    "  REGRESSION"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        for name in &self.missing {
+            writeln!(f, "{:<24} missing from this evaluation", name)?;
+        }
+        for name in &self.added {
+            writeln!(f, "{:<24} not in the baseline", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(microphone: Vec<f32>, reference: Vec<f32>, near_end: Option<Vec<f32>>) -> Case {
+        Case {
+            name: "test".to_string(),
+            sample_rate: 1_000,
+            microphone,
+            reference,
+            near_end,
+        }
+    }
+
+    fn report(name: &str, erle_db: f64, convergence_time_s: f64) -> CaseReport {
+        CaseReport {
+            name: name.to_string(),
+            duration_s: 1.0,
+            erle_db: Some(erle_db),
+            convergence_time_s: Some(convergence_time_s),
+            segmental_snr_db: None,
+            real_time_factor: 0.01,
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        // 4 s at 1 kHz: the residual echo falls from the full echo to a tenth of it after 1 s
+        let reference: Vec<f32> = (0..4_000).map(|i| (0.3 * i as f32).sin()).collect();
+        let echo: Vec<f32> = reference.iter().map(|x| 0.5 * x).collect();
+        let output: Vec<f32> = echo
+            .iter()
+            .enumerate()
+            .map(|(i, x)| if i < 1_000 { *x } else { 0.1 * x })
+            .collect();
+        let scores = score(&case(echo.clone(), reference.clone(), None), &output);
+        assert_eq!(scores.duration_s, 4.0);
+        // 1 s of 0 dB and 3 s of 20 dB
+        let expected_erle = 10.0 * (4.0 / (1.0 + 3.0 * 0.01_f64)).log10();
+        assert!((scores.erle_db.unwrap() - expected_erle).abs() < 0.01);
+        assert!((scores.convergence_time_s.unwrap() - 1.0).abs() < 0.021);
+        assert_eq!(scores.segmental_snr_db, None);
+
+        // near-end talk during the second half, with a residual echo 20 dB down; the frames with
+        // near-end talk do not count for the ERLE
+        let near_end: Vec<f32> = (0..4_000)
+            .map(|i| {
+                if i < 2_000 {
+                    0.0
+                } else {
+                    (0.05 * i as f32).sin()
+                }
+            })
+            .collect();
+        let microphone: Vec<f32> = echo.iter().zip(&near_end).map(|(e, n)| e + n).collect();
+        let output: Vec<f32> = echo
+            .iter()
+            .zip(&near_end)
+            .map(|(e, n)| 0.1 * e + n)
+            .collect();
+        let scores = score(&case(microphone, reference, Some(near_end)), &output);
+        assert!((scores.erle_db.unwrap() - 20.0).abs() < 0.01);
+        // the near-end talk is twice as loud as the echo: about 26 dB above the residual, a bit
+        // less on average over frames
+        let snr = scores.segmental_snr_db.unwrap();
+        assert!(24.0 < snr && snr < 26.1, "{}", snr);
+
+        // no far-end talk, no echo to score
+        let silence = vec![0.0; 4_000];
+        let scores = score(&case(silence.clone(), silence.clone(), None), &silence);
+        assert_eq!(scores.erle_db, None);
+        assert_eq!(scores.convergence_time_s, None);
+    }
+
+    #[test]
+    fn test_comparison() {
+        let baseline = Report::new(vec![
+            report("a", 20.0, 1.0),
+            report("b", 15.0, 2.0),
+            report("c", 10.0, 1.0),
+        ]);
+        let current = Report::new(vec![
+            report("a", 20.2, 1.05),
+            report("b", 14.0, 2.0),
+            report("d", 10.0, 1.0),
+        ]);
+        let comparison = Comparison::new(&baseline, &current);
+        assert_eq!(comparison.missing, vec!["c".to_string()]);
+        assert_eq!(comparison.added, vec!["d".to_string()]);
+        let regressions: Vec<(&str, &str)> = comparison
+            .regressions()
+            .map(|change| (change.case.as_str(), change.measure))
+            .collect();
+        assert_eq!(regressions, vec![("b", "ERLE (dB)")]);
+        // the means are compared too
+        assert!(comparison
+            .changes
+            .iter()
+            .any(|change| change.case == "mean"));
+
+        let csv = current.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "a,1.000,20.200,1.050,,0.0100");
+        assert!(lines[4].starts_with("mean,3.000,"));
+        let json = serde_json::to_string(&current).unwrap();
+        let parsed: Report = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.cases[2].name, "d");
+        assert_eq!(parsed.mean.segmental_snr_db, None);
+
+        // a case which no longer converges, or whose ERLE is lost, regresses; the mean
+        // convergence time is left to the slower cases
+        let lost = Report::new(vec![
+            CaseReport {
+                convergence_time_s: None,
+                ..report("a", 20.0, 1.0)
+            },
+            CaseReport {
+                erle_db: None,
+                ..report("b", 15.0, 2.0)
+            },
+            report("c", 10.0, 1.0),
+        ]);
+        let comparison = Comparison::new(&baseline, &lost);
+        let regressions: Vec<(&str, &str)> = comparison
+            .regressions()
+            .map(|change| (change.case.as_str(), change.measure))
+            .collect();
+        assert_eq!(
+            regressions,
+            vec![
+                ("a", "convergence time (s)"),
+                ("b", "ERLE (dB)"),
+                ("mean", "convergence time (s)")
+            ]
+        );
+        assert!(comparison.to_string().contains("none  REGRESSION"));
+    }
+
+    #[test]
+    fn test_corpus() {
+        let directory =
+            std::env::temp_dir().join(format!("raec-evaluation-test-{}", std::process::id()));
+        let triplet = directory.join("recorded");
+        std::fs::create_dir_all(&triplet).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        for (file, length) in &[(MICROPHONE_FILE, 8_000), (REFERENCE_FILE, 7_000)] {
+            let mut writer = hound::WavWriter::create(triplet.join(file), spec).unwrap();
+            for i in 0..*length {
+                writer
+                    .write_sample(((0.01 * i as f32).sin() * 10_000.0) as i16)
+                    .unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        std::fs::write(
+            directory.join("generated.toml"),
+            "sample_rate = 8000\nduration_s = 0.5\nfar_end = \"white-noise\"\n",
+        )
+        .unwrap();
+        std::fs::write(directory.join("notes.txt"), "ignored").unwrap();
+
+        let cases = load_corpus(&directory).unwrap();
+        let names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+        assert_eq!(names, vec!["generated", "recorded"]);
+        assert_eq!(cases[0].microphone.len(), 4_000);
+        assert!(cases[0].near_end.is_some());
+        assert_eq!(cases[1].microphone.len(), 7_000);
+        assert_eq!(cases[1].reference.len(), 7_000);
+        assert_eq!(cases[1].near_end, None);
+
+        let config = Config::from_toml("[filter]\ntaps = 64", None).unwrap();
+        let scores = evaluate(&cases[1], &config).unwrap();
+        // the random weights are seeded: the same code scores the same
+        assert_eq!(
+            evaluate(&cases[1], &config).unwrap().erle_db,
+            scores.erle_db
+        );
+        assert!(scores.erle_db.is_some());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(load_corpus(&directory).is_err());
+    }
+}
@@ -11,13 +11,18 @@
 //! A `Peak` filter will provide the specified gain around a center frequency,
 //! with the width of the peak determined by the Q. A higher Q means a narrower
 //! peak.
+//!
+//! `Filter::response` evaluates the frequency response of a filter, and
+//! `Filter::poles` tells whether it is stable.
 
+use rustfft::num_complex::Complex;
 use std::f32::consts::PI;
 
 const SAMPLE_RATE: i32 = 48000;
 
-fn decibel_to_ratio(db: f32) -> f32 {
-    10.0_f32.powf(db / 10.0_f32)
+/// The amplitude ratio of a gain of `db` decibels.
+fn decibel_to_amplitude(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0_f32)
 }
 
 /// Specifies the mode for a second order `Filter`.
@@ -49,28 +54,75 @@ pub struct Filter {
     b2: f32,
     a1: f32,
     a2: f32,
+    sample_rate: f32,
 }
 
 impl Filter {
     /// Creates a new second order filter with the provided mode. Each channel
     /// is filtered independently.
     pub fn new(mode: FilterMode) -> Self {
+        Filter::with_sample_rate(mode, SAMPLE_RATE as f32)
+    }
+
+    /// Creates a new second order filter with the provided mode, for a signal
+    /// sampled at `sample_rate` (Hz) instead of the default 48 kHz.
+    pub fn with_sample_rate(mode: FilterMode, sample_rate: f32) -> Self {
         // Compute the parameter values
-        let (b0, b1, b2, a1, a2) = compute_parameters(mode);
+        let (b0, b1, b2, a1, a2) = compute_parameters(mode, sample_rate);
 
         Filter {
             x_last1: 0.0_f32,
             x_last2: 0.0_f32,
             y_last1: 0.0_f32,
             y_last2: 0.0_f32,
-            b0: b0,
-            b1: b1,
-            b2: b2,
-            a1: a1,
-            a2: a2,
+            b0,
+            b1,
+            b2,
+            a1,
+            a2,
+            sample_rate,
         }
     }
 
+    /// The complex response of the filter at `frequency` (Hz), i.e. its
+    /// transfer function on the unit circle.
+    pub fn response(&self, frequency: f32) -> Complex<f32> {
+        let omega = 2.0 * std::f64::consts::PI * frequency as f64 / self.sample_rate as f64;
+        // z^-1 and z^-2, evaluated in double precision as the coefficients
+        // nearly cancel out for low cutoffs
+        let z1 = Complex::from_polar(1.0, -omega);
+        let z2 = z1 * z1;
+        let numerator = self.b0 as f64 + z1 * self.b1 as f64 + z2 * self.b2 as f64;
+        let denominator = 1.0 + z1 * self.a1 as f64 + z2 * self.a2 as f64;
+        let response = numerator / denominator;
+        Complex::new(response.re as f32, response.im as f32)
+    }
+
+    /// The gain of the filter at `frequency` (Hz), in decibels.
+    pub fn gain_db(&self, frequency: f32) -> f32 {
+        20.0 * self.response(frequency).norm().log10()
+    }
+
+    /// The group delay of the filter at `frequency` (Hz), in samples: how
+    /// long the envelope of a tone at that frequency takes to go through.
+    pub fn group_delay(&self, frequency: f32) -> f32 {
+        // derivative of the phase, over a small step around the frequency
+        let step = self.sample_rate * 1e-4;
+        let low = self.response((frequency - step).max(0.0));
+        let high = self.response(frequency + step);
+        let phase = (high * low.conj()).arg();
+        let omega = 2.0 * PI * (frequency + step - (frequency - step).max(0.0)) / self.sample_rate;
+        -phase / omega
+    }
+
+    /// The poles of the filter; it is stable if they lie inside the unit
+    /// circle.
+    pub fn poles(&self) -> [Complex<f32>; 2] {
+        // roots of z^2 + a1 z + a2
+        let root = Complex::new(self.a1 * self.a1 - 4.0 * self.a2, 0.0).sqrt();
+        [(-self.a1 + root) / 2.0, (-self.a1 - root) / 2.0]
+    }
+
     pub fn tick(&mut self, x: f32) -> f32 {
         // Run the all pass filter, and feedback the result
         let y = self.b0 * x + self.b1 * self.x_last1 + self.b2 * self.x_last2
@@ -88,7 +140,7 @@ impl Filter {
 
 /// Computes the parameters for our filter
 #[allow(non_snake_case)]
-fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
+fn compute_parameters(mode: FilterMode, sample_rate: f32) -> (f32, f32, f32, f32, f32) {
     let cutoff = match mode {
         LowPass(cutoff) => cutoff,
         HighPass(cutoff) => cutoff,
@@ -96,7 +148,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
         HighShelf(cutoff, _) => cutoff,
         Peak(center, _, _) => center,
     };
-    let K = (PI * cutoff / (SAMPLE_RATE as f32)).tan();
+    let K = (PI * cutoff / sample_rate).tan();
 
     match mode {
         LowPass(_) => {
@@ -120,7 +172,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
         LowShelf(_, gain) => {
             if gain < 0.0_f32 {
                 // cut
-                let V0 = 1.0_f32 / decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = 1.0_f32 / decibel_to_amplitude(gain);
                 let b0 = (1.0_f32 + 2.0_f32.sqrt() * K + K * K)
                     / (1.0_f32 + (2.0_f32 * V0).sqrt() * K + V0 * K * K);
                 let b1 = 2.0_f32 * (K * K - 1.0_f32)
@@ -134,7 +186,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
                 (b0, b1, b2, a1, a2)
             } else {
                 // boost
-                let V0 = decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = decibel_to_amplitude(gain);
                 let b0 = (1.0_f32 + (2.0_f32 * V0).sqrt() * K + V0 * K * K)
                     / (1.0_f32 + 2.0_f32.sqrt() * K + K * K);
                 let b1 = 2.0_f32 * (V0 * K * K - 1.0_f32) / (1.0_f32 + 2.0_f32.sqrt() * K + K * K);
@@ -149,7 +201,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
         HighShelf(_, gain) => {
             if gain < 0.0_f32 {
                 // cut
-                let V0 = 1.0_f32 / decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = 1.0_f32 / decibel_to_amplitude(gain);
                 let b0 = (1.0_f32 + 2.0_f32.sqrt() * K + K * K)
                     / (V0 + (2.0_f32 * V0).sqrt() * K + K * K);
                 let b1 = 2.0_f32 * (K * K - 1.0_f32) / (V0 + (2.0_f32 * V0).sqrt() * K + K * K);
@@ -162,7 +214,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
                 (b0, b1, b2, a1, a2)
             } else {
                 // boost
-                let V0 = decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = decibel_to_amplitude(gain);
                 let b0 = (V0 + (2.0_f32 * V0).sqrt() * K + K * K)
                     / (1.0_f32 + 2.0_f32.sqrt() * K + K * K);
                 let b1 = 2.0_f32 * (K * K - V0) / (1.0_f32 + 2.0_f32.sqrt() * K + K * K);
@@ -177,7 +229,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
         Peak(_, gain, Q) => {
             if gain < 0.0_f32 {
                 // cut
-                let V0 = 1.0_f32 / decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = 1.0_f32 / decibel_to_amplitude(gain);
                 let b0 = (1.0_f32 + K / Q + K * K) / (1.0_f32 + V0 * K / Q + K * K);
                 let b1 = 2.0_f32 * (K * K - 1.0_f32) / (1.0_f32 + V0 * K / Q + K * K);
                 let b2 = (1.0_f32 - K / Q + K * K) / (1.0_f32 + V0 * K / Q + K * K);
@@ -186,7 +238,7 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
                 (b0, b1, b2, a1, a2)
             } else {
                 // boost
-                let V0 = decibel_to_ratio(gain / 2.0_f32); // amplitude dB
+                let V0 = decibel_to_amplitude(gain);
                 let b0 = (1.0_f32 + V0 * K / Q + K * K) / (1.0_f32 + K / Q + K * K);
                 let b1 = 2.0_f32 * (K * K - 1.0_f32) / (1.0_f32 + K / Q + K * K);
                 let b2 = (1.0_f32 - V0 * K / Q + K * K) / (1.0_f32 + K / Q + K * K);
@@ -197,3 +249,192 @@ fn compute_parameters(mode: FilterMode) -> (f32, f32, f32, f32, f32) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Number of random filters checked by each test
+    const CASES: usize = 200;
+    const SAMPLE_RATES: [f32; 4] = [8_000.0, 16_000.0, 44_100.0, 48_000.0];
+    /// Tolerance on the gains, in decibels
+    const TOLERANCE_DB: f32 = 0.05;
+
+    /// A random filter of every mode, and the sample rate it runs at.
+    fn random_filters(seed: u64) -> Vec<(FilterMode, f32)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..CASES)
+            .flat_map(|_| {
+                let sample_rate = SAMPLE_RATES[rng.gen_range(0, SAMPLE_RATES.len())];
+                let cutoff = rng.gen_range(50.0, 0.45 * sample_rate);
+                let gain = rng.gen_range(-24.0, 24.0);
+                let q = rng.gen_range(0.3, 10.0);
+                vec![
+                    LowPass(cutoff),
+                    HighPass(cutoff),
+                    LowShelf(cutoff, gain),
+                    HighShelf(cutoff, gain),
+                    Peak(cutoff, gain, q),
+                ]
+                .into_iter()
+                .map(move |mode| (mode, sample_rate))
+            })
+            .collect()
+    }
+
+    fn assert_gain(filter: &Filter, frequency: f32, expected_db: f32, mode: FilterMode) {
+        let gain = filter.gain_db(frequency);
+        assert!(
+            (gain - expected_db).abs() < TOLERANCE_DB,
+            "{:?}: {} dB at {} Hz, expected {} dB",
+            mode,
+            gain,
+            frequency,
+            expected_db
+        );
+    }
+
+    #[test]
+    fn test_decibel_to_amplitude() {
+        assert!((decibel_to_amplitude(20.0) - 10.0).abs() < 1e-5);
+        assert!((decibel_to_amplitude(-6.0) - 0.501).abs() < 1e-3);
+        assert_eq!(decibel_to_amplitude(0.0), 1.0);
+    }
+
+    #[test]
+    fn test_stability() {
+        for (mode, sample_rate) in random_filters(1) {
+            let filter = Filter::with_sample_rate(mode, sample_rate);
+            for pole in filter.poles().iter() {
+                assert!(
+                    pole.norm() < 1.0,
+                    "{:?}: pole {} at {} Hz",
+                    mode,
+                    pole,
+                    sample_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_gains() {
+        for (mode, sample_rate) in random_filters(2) {
+            let filter = Filter::with_sample_rate(mode, sample_rate);
+            let nyquist = sample_rate / 2.0;
+            match mode {
+                LowPass(cutoff) => {
+                    assert_gain(&filter, 0.0, 0.0, mode);
+                    assert_gain(&filter, cutoff, -3.01, mode);
+                }
+                HighPass(cutoff) => {
+                    assert_gain(&filter, nyquist, 0.0, mode);
+                    assert_gain(&filter, cutoff, -3.01, mode);
+                }
+                LowShelf(_, gain) => {
+                    assert_gain(&filter, 0.0, gain, mode);
+                    assert_gain(&filter, nyquist, 0.0, mode);
+                }
+                HighShelf(_, gain) => {
+                    assert_gain(&filter, 0.0, 0.0, mode);
+                    assert_gain(&filter, nyquist, gain, mode);
+                }
+                Peak(center, gain, _) => {
+                    assert_gain(&filter, 0.0, 0.0, mode);
+                    assert_gain(&filter, center, gain, mode);
+                    assert_gain(&filter, nyquist, 0.0, mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_matches_tick() {
+        let sample_rate = 16_000.0;
+        let modes = [
+            LowPass(1_000.0),
+            HighPass(300.0),
+            LowShelf(500.0, -12.0),
+            HighShelf(2_000.0, 6.0),
+            Peak(1_500.0, 9.0, 2.0),
+        ];
+        for &mode in modes.iter() {
+            for &frequency in [200.0, 1_000.0, 1_500.0, 5_000.0].iter() {
+                let mut filter = Filter::with_sample_rate(mode, sample_rate);
+                let omega = 2.0 * PI * frequency / sample_rate;
+                let output: Vec<f32> = (0..8_000)
+                    .map(|n| filter.tick((omega * n as f32).sin()))
+                    .collect();
+                // the amplitude of the steady state, from its power
+                let steady = &output[4_000..];
+                let power = steady.iter().map(|y| y * y).sum::<f32>() / steady.len() as f32;
+                let amplitude = (2.0 * power).sqrt();
+                let expected = filter.response(frequency).norm();
+                assert!(
+                    (amplitude - expected).abs() < 1e-2 * expected.max(1.0),
+                    "{:?} at {} Hz: amplitude {}, response {}",
+                    mode,
+                    frequency,
+                    amplitude,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_delay() {
+        // near DC, the group delay is the centroid of the impulse response
+        for &mode in [
+            LowPass(500.0),
+            LowShelf(200.0, 6.0),
+            Peak(1_000.0, -9.0, 1.0),
+        ]
+        .iter()
+        {
+            let mut filter = Filter::with_sample_rate(mode, 16_000.0);
+            let impulse: Vec<f32> = (0..16_000)
+                .map(|n| filter.tick(if n == 0 { 1.0 } else { 0.0 }))
+                .collect();
+            let centroid = impulse
+                .iter()
+                .enumerate()
+                .map(|(n, h)| n as f32 * h)
+                .sum::<f32>()
+                / impulse.iter().sum::<f32>();
+            let delay = filter.group_delay(1.0);
+            assert!(
+                (delay - centroid).abs() < 0.05 * centroid.abs().max(1.0),
+                "{:?}: group delay {}, centroid {}",
+                mode,
+                delay,
+                centroid
+            );
+        }
+    }
+
+    #[test]
+    fn test_bounded_output() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for (mode, sample_rate) in random_filters(4).into_iter().take(CASES / 4) {
+            // the output is bounded by the sum of the magnitudes of the impulse response
+            let mut impulse = Filter::with_sample_rate(mode, sample_rate);
+            let bound: f32 = (0..20_000)
+                .map(|n| impulse.tick(if n == 0 { 1.0 } else { 0.0 }).abs())
+                .sum();
+            let mut filter = Filter::with_sample_rate(mode, sample_rate);
+            for _ in 0..20_000 {
+                let y = filter.tick(rng.gen_range(-1.0, 1.0));
+                assert!(
+                    y.is_finite() && y.abs() <= 1.01 * bound,
+                    "{:?}: output {} above {}",
+                    mode,
+                    y,
+                    bound
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! An oversampled DFT filter bank, to split a signal into subbands and put it back together.
+//!
+//! The signal is cut into frames of `frame` samples, one every `hop` samples, each multiplied by
+//! a Hann window and taken to the frequency domain by a DFT: every frame gives a value for each of
+//! the `frame / 2 + 1` bands, from DC to the Nyquist frequency. The synthesis takes the frames
+//! back to the time domain, multiplies them by the same window and overlap-adds them. The squared
+//! Hann window adds up to a constant when the frames overlap by at least three quarters, so an
+//! unmodified signal comes out of the synthesis as it went into the analysis, `frame - 1` samples
+//! late.
+//!
+//! The bands are `frame / hop` times oversampled, so that what leaks from one band into its
+//! neighbours stays small: each band can then be processed on its own, e.g. by an adaptive filter
+//! (see the `subband` module), a noise suppressor or a residual echo suppressor, with a gain per
+//! band. The bands are scaled so that the power of white noise is the same in each band as in
+//! the signal.
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+/// Layout of the frames of a filter bank; cheap to clone, to build analyses and syntheses which
+/// share their windows and transforms.
+#[derive(Clone)]
+pub struct FilterBank {
+    frame: usize,
+    hop: usize,
+    /// Analysis window, scaled so that white noise keeps its power in every band
+    analysis_window: Arc<[f32]>,
+    /// Synthesis window, scaled so that the synthesis undoes the analysis
+    synthesis_window: Arc<[f32]>,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+}
+
+impl std::fmt::Debug for FilterBank {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterBank")
+            .field("frame", &self.frame)
+            .field("hop", &self.hop)
+            .finish()
+    }
+}
+
+impl FilterBank {
+    /// A filter bank with frames of `frame` samples, `oversampling` of which overlap each sample;
+    /// it must be at least 3 and divide `frame`, which must be even.
+    pub fn new(frame: usize, oversampling: usize) -> Result<Self, anyhow::Error> {
+        if oversampling < 3 {
+            return Err(anyhow::anyhow!(
+                "The filter bank must be oversampled at least 3 times to reconstruct its input"
+            ));
+        }
+        if frame == 0 || !frame.is_multiple_of(2) || !frame.is_multiple_of(oversampling) {
+            return Err(anyhow::anyhow!(
+                "The frames of the filter bank must be an even number of samples, and a multiple \
+                 of its oversampling; {} is not",
+                frame
+            ));
+        }
+        let hop = frame / oversampling;
+        let hann: Vec<f32> = (0..frame)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame as f32).cos())
+            .collect();
+        let energy: f32 = hann.iter().map(|w| w * w).sum();
+        let analysis_scale = 1.0 / energy.sqrt();
+        // the squared windows of the frames overlapping a sample add up to `energy / hop`, and the
+        // inverse transform is not normalised
+        let synthesis_scale = hop as f32 / (frame as f32 * energy.sqrt());
+        let mut planner = FftPlanner::new();
+        Ok(FilterBank {
+            frame,
+            hop,
+            analysis_window: hann.iter().map(|w| w * analysis_scale).collect(),
+            synthesis_window: hann.iter().map(|w| w * synthesis_scale).collect(),
+            forward: planner.plan_fft_forward(frame),
+            inverse: planner.plan_fft_inverse(frame),
+        })
+    }
+
+    /// Number of samples of a frame.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Number of samples between the starts of two frames.
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    /// Number of bands, from DC to the Nyquist frequency included.
+    pub fn bands(&self) -> usize {
+        self.frame / 2 + 1
+    }
+
+    /// Centre frequency of a band (Hz).
+    pub fn band_frequency(&self, band: usize, sample_rate: f32) -> f32 {
+        band as f32 * sample_rate / self.frame as f32
+    }
+
+    /// Number of samples by which the output of a synthesis lags the input of the analysis.
+    pub fn delay(&self) -> usize {
+        self.frame - 1
+    }
+
+    pub fn analysis(&self) -> Analysis {
+        Analysis {
+            bank: self.clone(),
+            input: vec![0.0; self.frame],
+            position: 0,
+            pending: 0,
+            spectrum: vec![Complex::new(0.0, 0.0); self.frame],
+            scratch: vec![Complex::new(0.0, 0.0); self.forward.get_inplace_scratch_len()],
+        }
+    }
+
+    pub fn synthesis(&self) -> Synthesis {
+        Synthesis {
+            bank: self.clone(),
+            accumulator: vec![0.0; self.frame],
+            position: 0,
+            output: vec![0.0; self.hop],
+            next: self.hop,
+            spectrum: vec![Complex::new(0.0, 0.0); self.frame],
+            scratch: vec![Complex::new(0.0, 0.0); self.inverse.get_inplace_scratch_len()],
+        }
+    }
+}
+
+/// Splits a signal into the bands of a `FilterBank`.
+pub struct Analysis {
+    bank: FilterBank,
+    /// The last `frame` samples, the oldest at `position`
+    input: Vec<f32>,
+    position: usize,
+    /// Samples taken in since the last frame
+    pending: usize,
+    /// The bands of the last frame, followed by the mirrored half of the spectrum
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Analysis {
+    /// Takes in a sample, and gives back the bands of the frame it completes every `hop`
+    /// samples.
+    pub fn push(&mut self, sample: f32) -> Option<&[Complex<f32>]> {
+        self.input[self.position] = sample;
+        self.position = (self.position + 1) % self.bank.frame;
+        self.pending += 1;
+        if self.pending < self.bank.hop {
+            return None;
+        }
+        self.pending = 0;
+        let (later, earlier) = self.input.split_at(self.position);
+        for ((bin, &sample), &w) in self
+            .spectrum
+            .iter_mut()
+            .zip(earlier.iter().chain(later))
+            .zip(self.bank.analysis_window.iter())
+        {
+            *bin = Complex::new(sample * w, 0.0);
+        }
+        self.bank
+            .forward
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        Some(&self.spectrum[..self.bank.bands()])
+    }
+}
+
+/// Puts a signal back together from the bands of a `FilterBank`.
+pub struct Synthesis {
+    bank: FilterBank,
+    /// Overlap-added frames, the oldest sample at `position`
+    accumulator: Vec<f32>,
+    position: usize,
+    /// The samples completed by the last frame
+    output: Vec<f32>,
+    /// Next sample of `output` to give out
+    next: usize,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Synthesis {
+    /// Adds the bands of a frame, `bands()` of them, completing the next `hop` samples of the
+    /// output.
+    pub fn push(&mut self, bands: &[Complex<f32>]) {
+        let frame = self.bank.frame;
+        assert_eq!(bands.len(), self.bank.bands(), "one value per band");
+        // the spectrum of a real signal is conjugate symmetric
+        self.spectrum[..bands.len()].copy_from_slice(bands);
+        for i in bands.len()..frame {
+            self.spectrum[i] = bands[frame - i].conj();
+        }
+        self.bank
+            .inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        for (i, (bin, &w)) in self
+            .spectrum
+            .iter()
+            .zip(self.bank.synthesis_window.iter())
+            .enumerate()
+        {
+            self.accumulator[(self.position + i) % frame] += bin.re * w;
+        }
+        for sample in self.output.iter_mut() {
+            *sample = std::mem::take(&mut self.accumulator[self.position]);
+            self.position = (self.position + 1) % frame;
+        }
+        self.next = 0;
+    }
+
+    /// Gives out the next sample of the output; to be called once for every sample taken in by the
+    /// analysis, after the frame it completes, if any, was pushed. The output then lags the
+    /// input by `FilterBank::delay`.
+    pub fn pop(&mut self) -> f32 {
+        match self.output.get(self.next) {
+            Some(&sample) => {
+                self.next += 1;
+                sample
+            }
+            // before the first frame
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_reconstruction() {
+        assert!(FilterBank::new(256, 2).is_err());
+        assert!(FilterBank::new(250, 4).is_err());
+        let bank = FilterBank::new(64, 4).unwrap();
+        assert_eq!((bank.bands(), bank.hop(), bank.delay()), (33, 16, 63));
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let input: Vec<f32> = (0..2_000).map(|_| rng.gen_range(-1.0, 1.0)).collect();
+        let mut analysis = bank.analysis();
+        let mut synthesis = bank.synthesis();
+        let mut power = vec![0.0; bank.bands()];
+        let mut output = vec![];
+        for &sample in &input {
+            if let Some(bands) = analysis.push(sample) {
+                for (power, band) in power.iter_mut().zip(bands) {
+                    *power += band.norm_sqr();
+                }
+                let bands = bands.to_vec();
+                synthesis.push(&bands);
+            }
+            output.push(synthesis.pop());
+        }
+        assert!(output[..bank.delay()].iter().all(|&x| x.abs() < 1e-5));
+        for (x, y) in input.iter().zip(&output[bank.delay()..]) {
+            assert!((x - y).abs() < 1e-5, "{} vs {}", x, y);
+        }
+        // white noise has the same power in every band as in the signal, i.e. a third on average
+        let frames = (input.len() / bank.hop()) as f32;
+        for &power in &power[1..bank.bands() - 1] {
+            assert!(
+                (power / frames - 1.0 / 3.0).abs() < 0.1,
+                "{}",
+                power / frames
+            );
+        }
+    }
+
+    #[test]
+    fn test_band_separation() {
+        // a sine in the middle of a band barely reaches the bands beyond its neighbours
+        let bank = FilterBank::new(128, 4).unwrap();
+        let mut analysis = bank.analysis();
+        let mut bands = vec![];
+        for i in 0..1_024 {
+            let phase = 2.0 * std::f32::consts::PI * bank.band_frequency(20, 16_000.0) / 16_000.0;
+            if let Some(frame) = analysis.push((phase * i as f32).sin()) {
+                bands = frame.to_vec();
+            }
+        }
+        assert_eq!(bank.band_frequency(20, 16_000.0), 2_500.0);
+        let peak = bands[20].norm();
+        assert!(bands[19].norm() > 0.4 * peak && bands[21].norm() > 0.4 * peak);
+        for (band, value) in bands.iter().enumerate() {
+            if (band as isize - 20).abs() > 1 {
+                assert!(
+                    value.norm() < 1e-3 * peak,
+                    "band {}: {}",
+                    band,
+                    value.norm()
+                );
+            }
+        }
+    }
+}
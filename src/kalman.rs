@@ -0,0 +1,429 @@
+//! Echo cancellation with a frequency domain adaptive Kalman filter.
+//!
+//! The echo path is modelled as a random walk: every block, the weights shrink by the transition
+//! factor `A` and change by a random amount, the process noise. The microphone signal is the
+//! echo through the current weights, plus whatever else the microphone picks up, the observation
+//! noise. The Kalman filter tracks the mean and the variance of the weights under that model, in
+//! the frequency domain where the variances of the frequency bins are taken to be independent of
+//! each other (Enzner and Vary, "Frequency-domain adaptive Kalman filter for acoustic echo
+//! control in hands-free telephones", 2006).
+//!
+//! No step size is needed: the Kalman gain of a bin weighs what the weights are still unsure of
+//! against the observation noise, which is estimated from the smoothed power of the error. It
+//! comes down by itself as the weights converge, and when the near-end speaker talks, as their
+//! speech is observation noise. The process noise of a bin is `(1 - A²)` times the power of its
+//! weight, so that the variances grow again, and the weights track changes of the echo path,
+//! as fast as `transition` lets them.
+//!
+//! The filter runs on blocks of `block` samples, with `taps / block` partitions of the weights
+//! and overlap-save convolutions over twice as many samples. The echo estimate of a block comes
+//! out once the block is complete, so it lags the input by `block - 1` samples. The weights are
+//! kept in the time domain too, oldest first as those of `NLMF`, which constrains the
+//! convolutions to be linear.
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Variance every weight starts out with, in the frequency domain
+const INITIAL_VARIANCE: f32 = 1.0;
+/// Smoothing factor of the estimated observation noise, from one block to the next
+const NOISE_SMOOTHING: f32 = 0.5;
+/// Lowest estimated observation noise, so that silence does not divide by zero
+const NOISE_FLOOR: f32 = 1e-10;
+
+/// Parameters of the `kalman` canceller.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KalmanParameters {
+    /// Number of samples of a block and of a partition of the weights
+    pub block: usize,
+    /// Factor by which the modelled echo path shrinks every block, above 0 and below 1; the
+    /// lower, the more the echo path is expected to change. At 1 the echo path would be expected
+    /// never to change, and the filter would stop tracking it once converged
+    pub transition: f32,
+}
+
+impl Default for KalmanParameters {
+    fn default() -> Self {
+        KalmanParameters {
+            block: 128,
+            transition: 0.999,
+        }
+    }
+}
+
+impl KalmanParameters {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.block == 0 {
+            return Err(anyhow::anyhow!("The blocks must hold at least one sample"));
+        }
+        if !(self.transition > 0.0 && self.transition < 1.0) {
+            return Err(anyhow::anyhow!(
+                "The transition factor must be above 0 and below 1"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Number of samples by which the echo estimate lags the input.
+    pub fn delay(&self) -> usize {
+        self.block - 1
+    }
+}
+
+/// A frequency domain adaptive Kalman filter estimating the echo of the reference in the
+/// microphone signal.
+pub struct KalmanCanceller {
+    parameters: KalmanParameters,
+    /// Number of taps of the modelled echo path
+    taps: usize,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// The reference samples of the last block, then those of the current one
+    reference: Vec<f32>,
+    /// The microphone samples of the current block
+    mic: Vec<f32>,
+    /// Samples taken in since the last block
+    pending: usize,
+    /// Spectra of the reference of the last blocks, one per partition; the newest at `newest`
+    spectra: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    /// The weights of the partitions, from the newest to the oldest, in the frequency domain;
+    /// their updates while a block is processed
+    partitions: Vec<Vec<Complex<f32>>>,
+    /// The variance of each weight of `partitions`
+    variances: Vec<Vec<f32>>,
+    /// The weights in the time domain, oldest first, `taps` of them after a padding of zeros to
+    /// the length of the partitions
+    weights: Vec<f32>,
+    /// Estimated power of the observation noise in each bin
+    noise: Vec<f32>,
+    /// Scratch space for the transforms
+    buffer: Vec<Complex<f32>>,
+    error: Vec<Complex<f32>>,
+    /// The echo estimate of the last block
+    output: Vec<f32>,
+    /// Next sample of `output` to give out
+    next: usize,
+}
+
+impl KalmanCanceller {
+    /// A canceller of echo paths as long as the initial weights, oldest first as those of `NLMF`.
+    /// Panics if the parameters do not validate.
+    pub fn new(parameters: &KalmanParameters, weights: impl Into<Vec<f32>>) -> Self {
+        parameters.validate().unwrap_or_else(|e| panic!("{}", e));
+        let weights = weights.into();
+        let taps = weights.len();
+        let block = parameters.block;
+        let bins = 2 * block;
+        let partitions = taps.div_ceil(block);
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(bins);
+        let inverse = planner.plan_fft_inverse(bins);
+        let scratch_len = forward
+            .get_inplace_scratch_len()
+            .max(inverse.get_inplace_scratch_len());
+        let zeros = vec![Complex::new(0.0, 0.0); bins];
+        let mut canceller = KalmanCanceller {
+            parameters: parameters.clone(),
+            taps,
+            forward,
+            inverse,
+            scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+            reference: vec![0.0; bins],
+            mic: vec![0.0; block],
+            pending: 0,
+            spectra: vec![zeros.clone(); partitions],
+            newest: 0,
+            partitions: vec![zeros.clone(); partitions],
+            variances: vec![vec![INITIAL_VARIANCE; bins]; partitions],
+            weights: [vec![0.0; partitions * block - taps], weights].concat(),
+            noise: vec![0.0; bins],
+            buffer: zeros.clone(),
+            error: zeros,
+            output: vec![0.0; block],
+            next: block,
+        };
+        for p in 0..partitions {
+            canceller.transform(p);
+        }
+        canceller
+    }
+
+    /// Takes in a sample of the reference and of the microphone, and gives back the echo
+    /// estimate, `delay` samples late, and a novelty of zero: the Kalman gain holds the weights
+    /// still by itself when the error is not echo, so the threshold of `NLMF::adapt` does not
+    /// apply.
+    pub fn adapt(&mut self, reference: f32, mic: f32) -> (f32, f32) {
+        let block = self.parameters.block;
+        self.reference[block + self.pending] = reference;
+        self.mic[self.pending] = mic;
+        self.pending += 1;
+        if self.pending == block {
+            self.pending = 0;
+            self.process_block();
+        }
+        let estimate = match self.output.get(self.next) {
+            Some(&sample) => {
+                self.next += 1;
+                sample
+            }
+            // before the first block
+            None => 0.0,
+        };
+        (estimate, 0.0)
+    }
+
+    fn process_block(&mut self) {
+        let block = self.parameters.block;
+        let bins = 2 * block;
+        let scale = 1.0 / bins as f32;
+        let partitions = self.partitions.len();
+
+        // the spectrum of the last two blocks of the reference
+        self.newest = (self.newest + 1) % partitions;
+        let spectrum = &mut self.spectra[self.newest];
+        for (bin, &x) in spectrum.iter_mut().zip(&self.reference) {
+            *bin = Complex::new(x, 0.0);
+        }
+        self.forward
+            .process_with_scratch(spectrum, &mut self.scratch);
+        self.reference.copy_within(block.., 0);
+
+        // the echo estimate
+        self.estimate();
+        for (y, bin) in self.output.iter_mut().zip(&self.buffer[block..]) {
+            *y = bin.re * scale;
+        }
+        self.next = 0;
+        self.error_spectrum();
+        for (noise, e) in self.noise.iter_mut().zip(&self.error) {
+            *noise = NOISE_SMOOTHING * *noise + (1.0 - NOISE_SMOOTHING) * e.norm_sqr();
+        }
+
+        // the Kalman gain weighs the uncertainty of the echo against the observation noise; the
+        // error holds half as many samples as the transforms
+        let transition = self.parameters.transition;
+        let (history, newest) = (&self.spectra, self.newest);
+        let spectra = |partition: usize| &history[(newest + partitions - partition) % partitions];
+        for k in 0..bins {
+            let uncertainty: f32 = (0..partitions)
+                .map(|p| spectra(p)[k].norm_sqr() * self.variances[p][k])
+                .sum();
+            let denominator = uncertainty + 2.0 * self.noise[k] + NOISE_FLOOR;
+            for p in 0..partitions {
+                let x = spectra(p)[k];
+                let variance = &mut self.variances[p][k];
+                self.partitions[p][k] = *variance * x.conj() * self.error[k] / denominator;
+                *variance *= 1.0 - 0.5 * x.norm_sqr() * *variance / denominator;
+            }
+        }
+
+        // the update of each partition, constrained to its taps in the time domain, and the
+        // prediction of the next block
+        let padding = self.weights.len() - self.taps;
+        for p in 0..partitions {
+            self.inverse
+                .process_with_scratch(&mut self.partitions[p], &mut self.scratch);
+            let offset = self.weights.len() - (p + 1) * block;
+            for (i, update) in self.partitions[p][..block].iter().enumerate() {
+                let index = offset + block - 1 - i;
+                // the padding stays at zero
+                if index >= padding {
+                    self.weights[index] = transition * (self.weights[index] + update.re * scale);
+                }
+            }
+            self.transform(p);
+            for (variance, w) in self.variances[p].iter_mut().zip(&self.partitions[p]) {
+                *variance = transition * transition * *variance
+                    + (1.0 - transition * transition) * w.norm_sqr();
+            }
+        }
+    }
+
+    /// Computes the echo estimate of the block into `buffer`, by overlap-save; the second half
+    /// of it, times the size of the transforms.
+    fn estimate(&mut self) {
+        let partitions = self.partitions.len();
+        let (history, newest, weights) = (&self.spectra, self.newest, &self.partitions);
+        for (k, bin) in self.buffer.iter_mut().enumerate() {
+            *bin = (0..partitions)
+                .map(|p| history[(newest + partitions - p) % partitions][k] * weights[p][k])
+                .sum();
+        }
+        self.inverse
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+    }
+
+    /// Computes the spectrum of the error of the estimate in `buffer` into `error`; its first
+    /// half is zero, as the estimate wraps around there.
+    fn error_spectrum(&mut self) {
+        let block = self.parameters.block;
+        let scale = 1.0 / (2 * block) as f32;
+        for (i, bin) in self.error.iter_mut().enumerate() {
+            *bin = match i.checked_sub(block) {
+                Some(i) => Complex::new(self.mic[i] - self.buffer[block + i].re * scale, 0.0),
+                None => Complex::new(0.0, 0.0),
+            };
+        }
+        self.forward
+            .process_with_scratch(&mut self.error, &mut self.scratch);
+    }
+
+    /// Takes the weights of a partition from the time domain to the frequency domain.
+    fn transform(&mut self, partition: usize) {
+        let block = self.parameters.block;
+        let offset = self.weights.len() - (partition + 1) * block;
+        let taps = &self.weights[offset..offset + block];
+        let spectrum = &mut self.partitions[partition];
+        // from the newest tap to the oldest, padded with zeros
+        for (i, bin) in spectrum.iter_mut().enumerate() {
+            *bin = match i < block {
+                true => Complex::new(taps[block - 1 - i], 0.0),
+                false => Complex::new(0.0, 0.0),
+            };
+        }
+        self.forward
+            .process_with_scratch(spectrum, &mut self.scratch);
+    }
+
+    /// Number of samples by which the echo estimate lags the input.
+    pub fn delay(&self) -> usize {
+        self.parameters.delay()
+    }
+
+    /// The weights of the modelled echo path, oldest first, as those of `NLMF`.
+    pub fn weights(&self) -> &[f32] {
+        &self.weights[self.weights.len() - self.taps..]
+    }
+
+    /// Sets all weights to zero and makes them as uncertain as at the start.
+    pub fn reset_weights(&mut self) {
+        for w in self.weights.iter_mut() {
+            *w = 0.0;
+        }
+        for partition in self.partitions.iter_mut() {
+            for w in partition.iter_mut() {
+                *w = Complex::new(0.0, 0.0);
+            }
+        }
+        for variances in self.variances.iter_mut() {
+            for variance in variances.iter_mut() {
+                *variance = INITIAL_VARIANCE;
+            }
+        }
+    }
+
+    /// Estimated bulk delay (in samples) of the modelled echo path, i.e. the position of the
+    /// largest tap.
+    pub fn bulk_delay(&self) -> usize {
+        let weights = self.weights();
+        let (position, _) = weights
+            .iter()
+            .enumerate()
+            .fold((0, 0.0_f32), |(best, max), (i, w)| {
+                if w.abs() > max {
+                    (i, w.abs())
+                } else {
+                    (best, max)
+                }
+            });
+        weights.len() - 1 - position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{AECParameters, Algorithm};
+    use crate::simulation::testing::{cancel, level_db, parameters, small_room};
+    use crate::simulation::{EchoPathChange, NearEnd, Scenario, Source};
+
+    /// The ERLE of the echo alone over each second of a scenario, whatever else the microphone
+    /// picks up.
+    fn erle_db(scenario: &Scenario, algorithm: Algorithm) -> Vec<f32> {
+        let simulation = scenario.generate().unwrap();
+        let parameters = AECParameters {
+            algorithm,
+            ..parameters()
+        };
+        let (_, output) = cancel(&parameters, &simulation);
+        let residual_echo: Vec<f32> = output
+            .iter()
+            .zip(&simulation.microphone)
+            .zip(&simulation.echo)
+            .map(|((y, d), echo)| y - (d - echo))
+            .collect();
+        simulation
+            .echo
+            .chunks(16_000)
+            .zip(residual_echo.chunks(16_000))
+            .map(|(echo, residual)| level_db(echo) - level_db(residual))
+            .collect()
+    }
+
+    #[test]
+    fn test_kalman_parameters() {
+        let parameters = KalmanParameters::default();
+        assert!(parameters.validate().is_ok());
+        let weights: Vec<f32> = (0..200).map(|i| i as f32).collect();
+        let canceller = KalmanCanceller::new(&parameters, weights.clone());
+        assert_eq!(canceller.delay(), 127);
+        // the partitions are padded, but the weights are as given
+        assert_eq!(canceller.weights(), &weights[..]);
+        assert_eq!(canceller.bulk_delay(), 0);
+        for transition in [0.0, 1.0, 1.5] {
+            assert!(KalmanParameters {
+                transition,
+                ..parameters.clone()
+            }
+            .validate()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_tracks_echo_path_change() {
+        let scenario = Scenario {
+            duration_s: 6.0,
+            far_end: Source::WhiteNoise,
+            snr_db: Some(30.0),
+            echo_path_change: Some(EchoPathChange {
+                at_s: 2.0,
+                ..EchoPathChange::default()
+            }),
+            ..small_room()
+        };
+        let nlmf = erle_db(&scenario, Algorithm::Nlmf);
+        let kalman = erle_db(&scenario, Algorithm::Kalman);
+        // converged, it leaves less echo than the NLMF; the change at 2 s takes it about two
+        // seconds to track
+        assert!(kalman[1] > nlmf[1] + 1.0, "{:?} vs {:?}", kalman, nlmf);
+        assert!(kalman[3] > 10.0, "{:?}", kalman);
+        assert!(kalman[4] > 25.0 && kalman[5] > 30.0, "{:?}", kalman);
+    }
+
+    #[test]
+    fn test_rides_out_double_talk() {
+        let scenario = Scenario {
+            duration_s: 6.0,
+            near_end: Some(NearEnd {
+                start_s: 3.0,
+                end_s: Some(5.0),
+                ..NearEnd::default()
+            }),
+            snr_db: Some(30.0),
+            ..small_room()
+        };
+        let nlmf = erle_db(&scenario, Algorithm::Nlmf);
+        let kalman = erle_db(&scenario, Algorithm::Kalman);
+        // the near-end speech from 3 s to 5 s is observation noise to the Kalman filter, which
+        // holds on to the echo path, while it throws the NLMF off
+        assert!(kalman[3] > 20.0 && kalman[4] > 20.0, "{:?}", kalman);
+        assert!(kalman[3] > nlmf[3] + 10.0, "{:?} vs {:?}", kalman, nlmf);
+        assert!(kalman[5] > 30.0, "{:?}", kalman);
+    }
+}
@@ -0,0 +1,171 @@
+//! Accounting of the delay from the microphone to the output.
+//!
+//! A sample of the microphone goes through, in order:
+//!
+//! - the input device, which hands it over a buffer after it was captured;
+//! - the microphone buffer, until the processing thread takes it;
+//! - the processing, i.e. the block delay of the canceller and the group delay of the enabled
+//!   post-processing stages. The `nlmf` filter models the echo from the reference sample by
+//!   sample and does not delay the microphone; the `subband` and `kalman` cancellers work on
+//!   blocks, and delay it by `AECFiltering::delay` samples in every output mode;
+//! - the output buffer, until the output stream takes it;
+//! - the output device, which plays it a buffer after its callback.
+//!
+//! The processing thread publishes the levels of its buffers and its group delay in a
+//! `ProcessingLatency`; the streams publish the delay of their device in their `StreamHealth`,
+//! from the timestamps of their callbacks. A `LatencyReport` puts both together.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Frequency at which the group delay of the processing is measured (Hz), in the middle of the
+/// speech band
+pub const GROUP_DELAY_HZ: f32 = 1_000.0;
+
+/// What the processing thread knows of the latency, read lock-free from other threads.
+#[derive(Debug, Default)]
+pub struct ProcessingLatency {
+    /// Samples waiting in the microphone buffer
+    mic_samples: AtomicUsize,
+    /// Samples waiting in the reference buffer
+    capture_samples: AtomicUsize,
+    /// Samples waiting in the output buffer
+    output_samples: AtomicUsize,
+    /// Delay of the processing (samples), block delay of the canceller included, as the bits of
+    /// an f32
+    group_delay: AtomicU32,
+}
+
+impl ProcessingLatency {
+    pub(crate) fn record_levels(&self, mic: usize, capture: usize, output: usize) {
+        self.mic_samples.store(mic, Ordering::Relaxed);
+        self.capture_samples.store(capture, Ordering::Relaxed);
+        self.output_samples.store(output, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_group_delay(&self, samples: f32) {
+        self.group_delay.store(samples.to_bits(), Ordering::Relaxed);
+    }
+
+    /// The samples waiting in the microphone, reference and output buffers, as last seen by the
+    /// processing thread.
+    pub fn levels(&self) -> (usize, usize, usize) {
+        (
+            self.mic_samples.load(Ordering::Relaxed),
+            self.capture_samples.load(Ordering::Relaxed),
+            self.output_samples.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Delay of the processing (samples): the block delay of the canceller plus the group delay
+    /// of the post-processing at `GROUP_DELAY_HZ`.
+    pub fn group_delay(&self) -> f32 {
+        f32::from_bits(self.group_delay.load(Ordering::Relaxed))
+    }
+
+    /// The current latency, given the delays of the input and output devices when they are
+    /// known.
+    pub fn report(
+        &self,
+        sample_rate: f32,
+        input_device: Option<Duration>,
+        output_device: Option<Duration>,
+    ) -> LatencyReport {
+        let ms = |samples: f32| 1_000.0 * samples / sample_rate;
+        let (mic, capture, output) = self.levels();
+        LatencyReport {
+            input_device_ms: input_device.map(|delay| 1_000.0 * delay.as_secs_f32()),
+            mic_buffer_ms: ms(mic as f32),
+            processing_ms: ms(self.group_delay()),
+            output_buffer_ms: ms(output as f32),
+            output_device_ms: output_device.map(|delay| 1_000.0 * delay.as_secs_f32()),
+            reference_lead_ms: ms(capture as f32) - ms(mic as f32),
+        }
+    }
+}
+
+/// Where the delay from the microphone to the output comes from (ms).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct LatencyReport {
+    /// From the capture of a sample to the callback handing it over; `None` when the device does
+    /// not tell
+    pub input_device_ms: Option<f32>,
+    /// Waiting in the microphone buffer
+    pub mic_buffer_ms: f32,
+    /// Block delay of the canceller, plus the group delay of the post-processing stages at
+    /// `GROUP_DELAY_HZ`
+    pub processing_ms: f32,
+    /// Waiting in the output buffer
+    pub output_buffer_ms: f32,
+    /// From the callback taking a sample to its playback; `None` when the device does not tell
+    pub output_device_ms: Option<f32>,
+    /// How much more is waiting in the reference buffer than in the microphone buffer; not part
+    /// of the latency, but the echo path modelled by the adaptive filter is shortened by as much
+    pub reference_lead_ms: f32,
+}
+
+impl LatencyReport {
+    /// The delay due to the algorithm, whatever the buffering.
+    pub fn algorithmic_ms(&self) -> f32 {
+        self.processing_ms
+    }
+
+    /// The delay due to the buffers, which changes as the streams drift.
+    pub fn buffered_ms(&self) -> f32 {
+        self.mic_buffer_ms + self.output_buffer_ms
+    }
+
+    /// The whole delay from the microphone to the output, counting unknown device delays as
+    /// zero.
+    pub fn total_ms(&self) -> f32 {
+        self.input_device_ms.unwrap_or(0.0)
+            + self.buffered_ms()
+            + self.algorithmic_ms()
+            + self.output_device_ms.unwrap_or(0.0)
+    }
+}
+
+impl std::fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let device = |delay: Option<f32>| match delay {
+            Some(delay) => format!("{:.1} ms", delay),
+            None => "unknown".to_string(),
+        };
+        write!(
+            f,
+            "{:.1} ms: input device {}, microphone buffer {:.1} ms, processing {:.1} ms, output \
+             buffer {:.1} ms, output device {}",
+            self.total_ms(),
+            device(self.input_device_ms),
+            self.mic_buffer_ms,
+            self.processing_ms,
+            self.output_buffer_ms,
+            device(self.output_device_ms)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let latency = ProcessingLatency::default();
+        latency.record_levels(80, 240, 800);
+        latency.record_group_delay(8.0);
+        let report = latency.report(8_000.0, Some(Duration::from_millis(5)), None);
+        assert_eq!(report.mic_buffer_ms, 10.0);
+        assert_eq!(report.output_buffer_ms, 100.0);
+        assert_eq!(report.processing_ms, 1.0);
+        assert_eq!(report.reference_lead_ms, 20.0);
+        assert_eq!(report.buffered_ms(), 110.0);
+        assert!((report.total_ms() - 116.0).abs() < 1e-3);
+        assert_eq!(
+            report.to_string(),
+            "116.0 ms: input device 5.0 ms, microphone buffer 10.0 ms, processing 1.0 ms, \
+             output buffer 100.0 ms, output device unknown"
+        );
+    }
+}
@@ -1,4 +1,22 @@
+pub mod backend;
+pub mod config;
+pub mod devices;
+pub mod evaluation;
 pub mod filter;
+pub mod filterbank;
+pub mod kalman;
+pub mod latency;
 pub mod nlmf;
+pub mod nonlinear;
+pub mod path_change;
+pub mod pipeline;
 pub mod plot;
+pub mod probe;
 pub mod processing;
+pub mod recorder;
+pub mod simulation;
+pub mod step_size;
+pub mod streams;
+pub mod subband;
+pub mod supervisor;
+pub mod virtual_devices;
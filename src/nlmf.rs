@@ -2,6 +2,7 @@ use circular_queue::CircularQueue;
 use itertools::Itertools;
 use packed_simd::f32x8;
 
+/// Default number of taps of the filter
 pub const N_TAPS: usize = 1024;
 
 // TODO: Either remove generic definition over some numeric type, or write the code to properly support this.
@@ -10,17 +11,25 @@ pub const N_TAPS: usize = 1024;
 pub struct NLMF<T> {
     inputs: CircularQueue<T>,
     inputs_dot: CircularQueue<T>,
-    pub weights: [T; N_TAPS],
+    pub weights: Vec<T>,
+    /// Scratch space for the weight updates, to avoid allocating on every sample
+    dws: Vec<T>,
     mu: T,
     eps: T,
 }
 
 impl NLMF<f32> {
-    pub fn new(n: usize, mu: f32, eps: f32, weights: [f32; N_TAPS]) -> NLMF<f32> {
+    pub fn new(n: usize, mu: f32, eps: f32, weights: impl Into<Vec<f32>>) -> NLMF<f32> {
         assert!(
             n % 8 == 0,
             "Number of taps in NLMF filter must be divisible by 8 for SIMD optimization"
         );
+        let weights = weights.into();
+        assert_eq!(
+            weights.len(),
+            n,
+            "NLMF filter needs one initial weight per tap"
+        );
         let mut initial_inputs = CircularQueue::with_capacity(n);
         let mut inputs_dot = CircularQueue::with_capacity(n);
         for _ in 0..n {
@@ -31,6 +40,7 @@ impl NLMF<f32> {
             inputs: initial_inputs,
             inputs_dot: inputs_dot,
             weights,
+            dws: vec![0.0; n],
             mu,
             eps,
         }
@@ -65,8 +75,7 @@ impl NLMF<f32> {
         let nu: f32 = self.mu / (self.eps + input_dot);
         //self.w += nu * x * e**3
         let mut novelty: f32 = 0.0;
-        let mut dws: [f32; N_TAPS] = [0.0; N_TAPS];
-        for (w, x) in dws.iter_mut().zip(self.inputs.asc_iter()) {
+        for (w, x) in self.dws.iter_mut().zip(self.inputs.asc_iter()) {
             let dw: f32 = nu * error * x;
             let nov = (dw * error).abs();
             if nov > novelty {
@@ -75,13 +84,53 @@ impl NLMF<f32> {
             *w = dw;
         }
         if novelty < novelty_threshold {
-            for (w, dw) in self.weights.iter_mut().zip(dws.iter()) {
+            for (w, dw) in self.weights.iter_mut().zip(self.dws.iter()) {
                 *w = *w + dw;
                 assert!(!(w.is_nan()));
             }
         };
         (output, novelty)
     }
+
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    pub fn set_mu(&mut self, mu: f32) {
+        self.mu = mu;
+    }
+
+    pub fn eps(&self) -> f32 {
+        self.eps
+    }
+
+    pub fn set_eps(&mut self, eps: f32) {
+        self.eps = eps;
+    }
+
+    /// Sets all weights to zero, forgetting everything learned so far
+    pub fn reset_weights(&mut self) {
+        for w in self.weights.iter_mut() {
+            *w = 0.0;
+        }
+    }
+
+    /// Estimated bulk delay (in samples) of the modelled echo path, i.e. the position of the
+    /// largest tap. The last weight multiplies the newest input sample.
+    pub fn bulk_delay(&self) -> usize {
+        let (position, _) =
+            self.weights
+                .iter()
+                .enumerate()
+                .fold((0, 0.0_f32), |(best, max), (i, w)| {
+                    if w.abs() > max {
+                        (i, w.abs())
+                    } else {
+                        (best, max)
+                    }
+                });
+        self.weights.len() - 1 - position
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,281 @@
+//! Nonlinear echo paths, e.g. small loudspeakers driven into distortion.
+//!
+//! The adaptive filter only models linear echo paths. A loudspeaker driven hard saturates: it
+//! distorts the reference before the room filters it. This is modelled as a Hammerstein system,
+//! a memoryless nonlinearity followed by the linear filter; the `NonlinearPath` shapes the
+//! reference before the adaptive filter sees it, according to its `NonlinearModel`:
+//!
+//! - `linear`: the reference is left as is;
+//! - `soft-clip`: the reference saturates as `c tanh(x / c)`, from the clipping level `c`.
+//!
+//! The clipping level adapts along with the weights of the filter, on the same error, unless its
+//! step size is zero. With the weights `w`, the echo estimate is `y = sum_i w_i f(x_{n-i})`, so
+//! its gradient with respect to `ln c` is `h = c sum_i w_i df/dc(x_{n-i})`. The level follows a
+//! normalised least mean squares update in the logarithmic domain, so that it moves in
+//! proportion to its value and stays positive: `ln c += mu e h / P`, where `P` is the smoothed
+//! power of `h`. The derivatives are kept from when each sample came in, for the level moves
+//! slowly.
+//!
+//! The gradient vanishes once the level is well above the reference, where the model is linear
+//! anyway; the level is then held below `MAX_CLIP_LEVEL` so that it can still come back down
+//! should the loudspeaker start to saturate.
+
+use circular_queue::CircularQueue;
+use serde::{Deserialize, Serialize};
+
+/// Lowest clipping level the adaptation may reach (full scale)
+const MIN_CLIP_LEVEL: f32 = 1e-3;
+/// Highest clipping level the adaptation may reach; the model is all but linear from there on
+/// (full scale)
+const MAX_CLIP_LEVEL: f32 = 4.0;
+/// Largest change of the logarithm of the clipping level in one sample, i.e. about 1%
+const MAX_LOG_STEP: f32 = 0.01;
+/// Smoothing factor of the power of the gradient the steps are normalised with
+const POWER_SMOOTHING: f32 = 1e-3;
+/// Regularisation of the normalisation of the steps
+const POWER_FLOOR: f32 = 1e-9;
+
+/// The nonlinear part of the echo path model; see the module documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonlinearModel {
+    #[default]
+    Linear,
+    SoftClip,
+}
+
+impl NonlinearModel {
+    pub const ALL: [NonlinearModel; 2] = [NonlinearModel::Linear, NonlinearModel::SoftClip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NonlinearModel::Linear => "linear",
+            NonlinearModel::SoftClip => "soft-clip",
+        }
+    }
+}
+
+impl std::fmt::Display for NonlinearModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for NonlinearModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NonlinearModel::ALL
+            .iter()
+            .copied()
+            .find(|model| model.name() == s.to_lowercase())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown nonlinear model \"{}\"; use one of {}",
+                    s,
+                    NonlinearModel::ALL
+                        .iter()
+                        .map(|model| model.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Parameters of the nonlinear part of the echo path model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Nonlinearity {
+    pub model: NonlinearModel,
+    /// Level at which the `soft-clip` model starts out saturating (full scale), between
+    /// `MIN_CLIP_LEVEL` and `MAX_CLIP_LEVEL`
+    pub clip_level: f32,
+    /// Step size of the adaptation of the clipping level; zero keeps it at `clip_level`, as do
+    /// the `subband` and `kalman` algorithms
+    pub mu: f32,
+}
+
+impl Default for Nonlinearity {
+    fn default() -> Self {
+        Nonlinearity {
+            model: NonlinearModel::Linear,
+            clip_level: 1.0,
+            mu: 1e-3,
+        }
+    }
+}
+
+impl Nonlinearity {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(MIN_CLIP_LEVEL..=MAX_CLIP_LEVEL).contains(&self.clip_level) {
+            return Err(anyhow::anyhow!(
+                "The clipping level must be between {} and {}",
+                MIN_CLIP_LEVEL,
+                MAX_CLIP_LEVEL
+            ));
+        }
+        if self.mu < 0.0 {
+            return Err(anyhow::anyhow!(
+                "The step size of the clipping level must not be negative"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Shapes the reference as the loudspeaker is thought to play it, and adapts that shape.
+#[derive(Clone, Debug)]
+pub struct NonlinearPath {
+    parameters: Nonlinearity,
+    /// The current clipping level of the `soft-clip` model
+    clip_level: f32,
+    /// Derivative of the shaped reference samples with respect to the clipping level, oldest
+    /// first, over the taps of the filter; only kept while the level adapts
+    derivatives: Option<CircularQueue<f32>>,
+    /// Smoothed power of the gradient with respect to the logarithm of the clipping level
+    gradient_power: f32,
+}
+
+impl NonlinearPath {
+    /// A model for a filter of `taps` taps.
+    pub fn new(parameters: &Nonlinearity, taps: usize) -> Self {
+        let adapts = parameters.model == NonlinearModel::SoftClip && parameters.mu > 0.0;
+        let derivatives = if adapts {
+            let mut derivatives = CircularQueue::with_capacity(taps);
+            for _ in 0..taps {
+                derivatives.push(0.0);
+            }
+            Some(derivatives)
+        } else {
+            None
+        };
+        NonlinearPath {
+            parameters: parameters.clone(),
+            clip_level: parameters.clip_level,
+            derivatives,
+            gradient_power: 0.0,
+        }
+    }
+
+    pub fn model(&self) -> NonlinearModel {
+        self.parameters.model
+    }
+
+    /// The clipping level the model has adapted to; that it starts out with while it does not
+    /// adapt.
+    pub fn clip_level(&self) -> f32 {
+        self.clip_level
+    }
+
+    /// Takes in a reference sample and returns it as the loudspeaker is thought to play it.
+    pub fn shape(&mut self, x: f32) -> f32 {
+        match self.parameters.model {
+            NonlinearModel::Linear => x,
+            NonlinearModel::SoftClip => {
+                let u = x / self.clip_level;
+                let saturation = u.tanh();
+                if let Some(derivatives) = self.derivatives.as_mut() {
+                    derivatives.push(saturation - u * (1.0 - saturation * saturation));
+                }
+                self.clip_level * saturation
+            }
+        }
+    }
+
+    /// Adapts the model to the error of the echo estimate of the last sample, given the weights
+    /// of the filter, oldest first.
+    pub fn adapt(&mut self, error: f32, weights: &[f32]) {
+        let derivatives = match self.derivatives.as_ref() {
+            Some(derivatives) => derivatives,
+            None => return,
+        };
+        let gradient = self.clip_level
+            * derivatives
+                .asc_iter()
+                .zip(weights)
+                .map(|(d, w)| d * w)
+                .sum::<f32>();
+        self.gradient_power += POWER_SMOOTHING * (gradient * gradient - self.gradient_power);
+        let step = (self.parameters.mu * error * gradient / (POWER_FLOOR + self.gradient_power))
+            .clamp(-MAX_LOG_STEP, MAX_LOG_STEP);
+        self.clip_level = (self.clip_level * step.exp()).clamp(MIN_CLIP_LEVEL, MAX_CLIP_LEVEL);
+    }
+
+    /// Goes back to the clipping level the model started out with.
+    pub fn reset(&mut self) {
+        self.clip_level = self.parameters.clip_level;
+        self.gradient_power = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::AECParameters;
+    use crate::simulation::testing::{cancel, level_db, parameters, small_room};
+    use crate::simulation::{Scenario, Source};
+
+    #[test]
+    fn test_models() {
+        assert_eq!(
+            "soft-clip".parse::<NonlinearModel>().unwrap(),
+            NonlinearModel::SoftClip
+        );
+        assert!("cubic".parse::<NonlinearModel>().is_err());
+        let fixed = Nonlinearity {
+            model: NonlinearModel::SoftClip,
+            clip_level: 0.5,
+            mu: 0.0,
+        };
+        let mut path = NonlinearPath::new(&fixed, 8);
+        assert!((path.shape(0.01) - 0.01).abs() < 1e-5);
+        assert!((path.shape(10.0) - 0.5).abs() < 1e-5);
+        assert_eq!(path.shape(-0.3), -path.shape(0.3));
+        let mut linear = NonlinearPath::new(&Nonlinearity::default(), 8);
+        assert_eq!(linear.shape(10.0), 10.0);
+        assert!(Nonlinearity {
+            clip_level: 0.0,
+            ..fixed.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(Nonlinearity { mu: -1.0, ..fixed }.validate().is_err());
+    }
+
+    #[test]
+    fn test_cancels_clipped_echo() {
+        let scenario = Scenario {
+            duration_s: 4.0,
+            far_end: Source::WhiteNoise,
+            far_end_level_db: -12.0,
+            loudspeaker_saturation: Some(0.3),
+            ..small_room()
+        };
+        let simulation = scenario.generate().unwrap();
+        let last_second = simulation.microphone.len() - 16_000..;
+        let run = |model| {
+            let parameters = AECParameters {
+                nonlinearity: Nonlinearity {
+                    model,
+                    ..Nonlinearity::default()
+                },
+                ..parameters()
+            };
+            let (filter, output) = cancel(&parameters, &simulation);
+            let erle_db = level_db(&simulation.microphone[last_second.clone()])
+                - level_db(&output[last_second.clone()]);
+            (erle_db, filter.nonlinear_path().clip_level())
+        };
+        let (linear_db, _) = run(NonlinearModel::Linear);
+        let (soft_clip_db, clip_level) = run(NonlinearModel::SoftClip);
+        // the linear filter is limited by the distortion; the level is found from 1.0
+        assert!(
+            soft_clip_db > linear_db + 15.0,
+            "{} vs {}",
+            soft_clip_db,
+            linear_db
+        );
+        assert!((clip_level - 0.3).abs() < 0.03, "{}", clip_level);
+    }
+}
@@ -0,0 +1,387 @@
+//! Detection of changes of the echo path, with a shadow filter.
+//!
+//! Once the `NLMF` has converged, its updates are small; when the echo path changes, e.g. the
+//! laptop was moved, the error and with it the novelty jump, and the novelty threshold then
+//! holds the weights still just as it does when the near-end speaker talks. The `ShadowFilter`
+//! runs a second `NLMF` next to it, on the same signals, which adapts on every sample whatever
+//! its novelty. Its error is compared with that of the filter in use, both smoothed over
+//! `smoothing_ms`:
+//!
+//! - after a change of the echo path, the shadow filter re-converges while the filter in use
+//!   stays stuck: once the error of the shadow is `margin_db` below both the other and the
+//!   microphone signal for `hold_ms`, its weights are copied into the filter in use, and the
+//!   change is counted;
+//! - while the near-end speaker talks, the shadow filter is thrown off by their speech while the
+//!   filter in use holds on: its error gets above the other, and once it is `margin_db` above
+//!   for `hold_ms`, it is reset to the weights of the filter in use.
+//!
+//! Near-end speech louder than the echo leaves the error of the shadow filter close to the
+//! microphone signal, whatever its weights: double talk is thus not taken for a change of the
+//! echo path, even when it throws off the filter in use. Once it is over, the filter in use is
+//! as stuck as after a change of the echo path though, and the shadow filter takes over just the
+//! same. Such a takeover is told apart by what came before it: while neither filter cancels the
+//! echo and the shadow filter does worse than the other for `hold_ms`, the near-end speaker
+//! talks, and the next takeover is a recovery, counted apart from the changes; after a change of
+//! the echo path, the shadow filter does better from the start. Once both filters cancel the
+//! echo for `hold_ms`, the double talk is forgotten.
+//!
+//! The shadow filter doubles the cost of the `nlmf` algorithm, and does not apply to the others.
+
+use serde::{Deserialize, Serialize};
+
+use crate::nlmf::NLMF;
+
+/// Lowest error power compared, so that silence does not look like a difference
+const POWER_FLOOR: f32 = 1e-10;
+
+/// Parameters of the echo path change detection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathChangeDetection {
+    pub enabled: bool,
+    /// Step size of the shadow filter
+    pub mu: f32,
+    /// How much lower the error of one filter must be than that of the other (dB)
+    pub margin_db: f32,
+    /// How long the error of one filter must stay lower than that of the other (ms)
+    pub hold_ms: f32,
+    /// Time constant of the smoothing of the errors of both filters (ms)
+    pub smoothing_ms: f32,
+}
+
+impl Default for PathChangeDetection {
+    fn default() -> Self {
+        PathChangeDetection {
+            enabled: false,
+            mu: 0.5,
+            margin_db: 3.0,
+            hold_ms: 50.0,
+            smoothing_ms: 20.0,
+        }
+    }
+}
+
+impl PathChangeDetection {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.mu <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "The step size of the shadow filter must be positive"
+            ));
+        }
+        if self.margin_db < 0.0 {
+            return Err(anyhow::anyhow!("The margin must not be negative"));
+        }
+        if self.hold_ms < 0.0 || self.smoothing_ms <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "The hold time must not be negative, and the smoothing time constant must be \
+                 positive"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A second filter adapting without a novelty threshold, to tell when the echo path changed.
+pub struct ShadowFilter {
+    filter: NLMF<f32>,
+    /// Smoothing factor of the error powers
+    smoothing: f32,
+    /// Power ratio between the errors of the filters which makes one better than the other
+    margin: f32,
+    /// Number of samples one filter must stay better than the other
+    hold: usize,
+    /// Smoothed power of the microphone signal
+    mic_power: f32,
+    /// Smoothed power of the error of the filter in use
+    error_power: f32,
+    /// Smoothed power of the error of the shadow filter
+    shadow_power: f32,
+    /// Number of samples the shadow filter has been better, or worse when negative
+    better_for: isize,
+    /// Number of samples the near-end speaker has seemed to talk, or both filters to cancel the
+    /// echo when negative
+    talking_for: isize,
+    /// Whether the near-end speaker talked since both filters last cancelled the echo, which
+    /// makes the next takeover a recovery from double talk rather than a change of the echo path
+    talked: bool,
+    /// Number of changes of the echo path detected
+    changes: usize,
+    /// Number of takeovers after double talk threw off the filter in use
+    recoveries: usize,
+}
+
+/// Why the shadow filter took over from the filter in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Takeover {
+    /// The echo path changed
+    PathChange,
+    /// Double talk threw off the filter in use, which then stayed stuck
+    Recovery,
+}
+
+impl ShadowFilter {
+    /// A shadow of a filter starting out with `weights`, oldest first.
+    pub fn new(
+        parameters: &PathChangeDetection,
+        eps: f32,
+        weights: impl Into<Vec<f32>>,
+        sample_rate: f32,
+    ) -> Self {
+        let weights = weights.into();
+        let per_ms = sample_rate / 1_000.0;
+        ShadowFilter {
+            filter: NLMF::new(weights.len(), parameters.mu, eps, weights),
+            smoothing: 1.0 - (-1.0 / (parameters.smoothing_ms * per_ms)).exp(),
+            margin: 10.0_f32.powf(parameters.margin_db / 10.0),
+            hold: (parameters.hold_ms * per_ms) as usize,
+            mic_power: 0.0,
+            error_power: 0.0,
+            shadow_power: 0.0,
+            better_for: 0,
+            talking_for: 0,
+            talked: false,
+            changes: 0,
+            recoveries: 0,
+        }
+    }
+
+    /// Adapts the shadow filter to the same reference and microphone samples as `foreground`,
+    /// given the error of `foreground` on them, and copies the weights of the better filter into
+    /// the other one once it is better enough for long enough. Returns why the shadow filter
+    /// took over, if it did.
+    pub fn adapt(
+        &mut self,
+        foreground: &mut NLMF<f32>,
+        reference: f32,
+        mic: f32,
+        foreground_error: f32,
+    ) -> Option<Takeover> {
+        let (estimate, _) = self.filter.adapt(reference, mic, f32::INFINITY);
+        let error = mic - estimate;
+        self.mic_power += self.smoothing * (mic.powi(2) - self.mic_power);
+        self.error_power += self.smoothing * (foreground_error.powi(2) - self.error_power);
+        self.shadow_power += self.smoothing * (error.powi(2) - self.shadow_power);
+        let (shadow, foreground_power) = (
+            self.shadow_power + POWER_FLOOR,
+            self.error_power + POWER_FLOOR,
+        );
+        self.better_for = if shadow * self.margin < foreground_power.min(self.mic_power) {
+            self.better_for.max(0) + 1
+        } else if shadow > foreground_power * self.margin {
+            self.better_for.min(0) - 1
+        } else {
+            0
+        };
+        // neither filter cancels the echo, and the one adapting freely does worse: the near-end
+        // speaker talks, whatever the margin between the filters
+        let cancelling = foreground_power * self.margin < self.mic_power;
+        self.talking_for = if !cancelling && shadow > foreground_power {
+            self.talking_for.max(0) + 1
+        } else if cancelling && self.better_for == 0 {
+            self.talking_for.min(0) - 1
+        } else {
+            0
+        };
+        if self.talking_for.unsigned_abs() > self.hold {
+            self.talked = self.talking_for > 0;
+        }
+        if self.better_for.unsigned_abs() <= self.hold {
+            return None;
+        }
+        let takeover = if self.better_for > 0 {
+            foreground.weights.copy_from_slice(&self.filter.weights);
+            self.error_power = self.shadow_power;
+            Some(match self.talked {
+                true => {
+                    self.recoveries += 1;
+                    Takeover::Recovery
+                }
+                false => {
+                    self.changes += 1;
+                    Takeover::PathChange
+                }
+            })
+        } else {
+            self.filter.weights.copy_from_slice(&foreground.weights);
+            self.shadow_power = self.error_power;
+            None
+        };
+        self.better_for = 0;
+        self.talked = false;
+        takeover
+    }
+
+    /// The weights of the shadow filter, oldest first.
+    pub fn weights(&self) -> &[f32] {
+        &self.filter.weights
+    }
+
+    /// Number of changes of the echo path detected so far.
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    /// Number of takeovers so far after double talk threw off the filter in use; not counted as
+    /// changes of the echo path.
+    pub fn recoveries(&self) -> usize {
+        self.recoveries
+    }
+
+    pub fn set_eps(&mut self, eps: f32) {
+        self.filter.set_eps(eps);
+    }
+
+    /// Sets all weights of the shadow filter to zero, along with the filter in use.
+    pub fn reset_weights(&mut self) {
+        self.filter.reset_weights();
+        self.mic_power = 0.0;
+        self.error_power = 0.0;
+        self.shadow_power = 0.0;
+        self.better_for = 0;
+        self.talking_for = 0;
+        self.talked = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    const TAPS: usize = 32;
+    const SAMPLE_RATE: f32 = 16_000.0;
+    /// Novelty threshold of the filter in use, low enough to hold it still after the change
+    const NOVELTY_THRESHOLD: f32 = 0.0025;
+
+    /// Two echo paths of `TAPS` taps, oldest first
+    fn paths() -> (Vec<f32>, Vec<f32>) {
+        let path = |delay: usize, gain: f32| {
+            (0..TAPS)
+                .map(|i| match TAPS - 1 - i {
+                    d if d < delay => 0.0,
+                    d => gain * (-((d - delay) as f32) / 4.0).exp(),
+                })
+                .collect::<Vec<f32>>()
+        };
+        (path(2, 1.0), path(9, -0.8))
+    }
+
+    fn distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| (x - y).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// Runs the filters on `samples` samples of white noise played through `path`, with white
+    /// noise of `near_end` amplitude from the near end, and returns the samples at which the
+    /// echo path changed.
+    fn run(
+        mut shadow: Option<&mut ShadowFilter>,
+        foreground: &mut NLMF<f32>,
+        seed: u64,
+        path: &[f32],
+        near_end: f32,
+        samples: usize,
+    ) -> Vec<usize> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut inputs = vec![0.0; TAPS];
+        let mut changes = vec![];
+        for n in 0..samples {
+            let reference = rng.gen_range(-1.0, 1.0);
+            inputs.remove(0);
+            inputs.push(reference);
+            let echo: f32 = path.iter().zip(&inputs).map(|(w, x)| w * x).sum();
+            let mic = echo + near_end * rng.gen_range(-1.0, 1.0) + 1e-3 * rng.gen_range(-1.0, 1.0);
+            let (estimate, _) = foreground.adapt(reference, mic, NOVELTY_THRESHOLD);
+            if let Some(shadow) = shadow.as_mut() {
+                if shadow.adapt(foreground, reference, mic, mic - estimate)
+                    == Some(Takeover::PathChange)
+                {
+                    changes.push(n);
+                }
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn test_path_change_detection_parameters() {
+        assert!(PathChangeDetection::default().validate().is_ok());
+        for parameters in &[
+            PathChangeDetection {
+                mu: 0.0,
+                ..PathChangeDetection::default()
+            },
+            PathChangeDetection {
+                margin_db: -1.0,
+                ..PathChangeDetection::default()
+            },
+            PathChangeDetection {
+                smoothing_ms: 0.0,
+                ..PathChangeDetection::default()
+            },
+        ] {
+            assert!(parameters.validate().is_err(), "{:?}", parameters);
+        }
+    }
+
+    #[test]
+    fn test_takes_over_after_path_change() {
+        let (before, after) = paths();
+        let parameters = PathChangeDetection::default();
+        let mut foreground = NLMF::new(TAPS, 1.0, 1.0, before.clone());
+        let mut shadow = ShadowFilter::new(&parameters, 1.0, before.clone(), SAMPLE_RATE);
+        let changes = run(Some(&mut shadow), &mut foreground, 0, &before, 0.0, 8_000);
+        assert!(changes.is_empty(), "{:?}", changes);
+
+        // without the shadow filter, the novelty threshold holds the weights still
+        let mut alone = NLMF::new(TAPS, 1.0, 1.0, before.clone());
+        run(None, &mut alone, 1, &after, 0.0, 8_000);
+        assert!(
+            distance(&alone.weights, &after) > 0.5,
+            "{:?}",
+            alone.weights
+        );
+
+        let changes = run(Some(&mut shadow), &mut foreground, 1, &after, 0.0, 8_000);
+        // once, within a quarter of a second
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert!(changes[0] < 4_000, "{:?}", changes);
+        assert_eq!((shadow.changes(), shadow.recoveries()), (1, 0));
+        assert!(
+            distance(&foreground.weights, &after) < 0.05,
+            "{:?}",
+            foreground.weights
+        );
+    }
+
+    #[test]
+    fn test_ignores_double_talk() {
+        let (path, _) = paths();
+        let mut foreground = NLMF::new(TAPS, 1.0, 1.0, path.clone());
+        let mut shadow = ShadowFilter::new(
+            &PathChangeDetection::default(),
+            1.0,
+            path.clone(),
+            SAMPLE_RATE,
+        );
+        run(Some(&mut shadow), &mut foreground, 0, &path, 0.0, 8_000);
+        // the near-end speaker talks for a second, louder than the echo
+        let changes = run(Some(&mut shadow), &mut foreground, 1, &path, 2.0, 16_000);
+        assert!(changes.is_empty(), "{:?}", changes);
+        assert_eq!(shadow.changes(), 0);
+        // the filter in use was thrown off nonetheless, and is as stuck as after a change of the
+        // echo path, until the shadow filter takes over: a recovery, not a change
+        assert!(distance(&foreground.weights, &path) > 0.5);
+        let changes = run(Some(&mut shadow), &mut foreground, 2, &path, 0.0, 8_000);
+        assert!(changes.is_empty(), "{:?}", changes);
+        assert_eq!((shadow.changes(), shadow.recoveries()), (0, 1));
+        assert!(
+            distance(&foreground.weights, &path) < 0.05,
+            "{:?}",
+            foreground.weights
+        );
+    }
+}
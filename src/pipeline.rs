@@ -0,0 +1,85 @@
+//! Wires the buffers of the live processing together.
+//!
+//! The microphone and reference (capture) streams each push into a buffer read by the processing
+//! thread, which pushes into the buffer played by the output stream. Every buffer starts with
+//! `latency_samples` of silence, so that the output stream has something to play while the input
+//! streams start. The microphone stream wakes the processing thread up whenever it brings new
+//! samples.
+
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+use ringbuf::RingBuffer;
+
+use crate::processing::{
+    AECFiltering, AECParameters, Mono2StereoOutput, RunningAECFiltering, Stereo2MonoCapture,
+};
+
+/// The ends of the buffers of the live processing, to hand to the streams and to the processing
+/// thread.
+pub struct Pipeline {
+    /// Feeds the microphone signal, waking the processing thread up
+    pub microphone: Stereo2MonoCapture,
+    /// Feeds the reference signal, i.e. what the loudspeaker plays
+    pub capture: Stereo2MonoCapture,
+    /// Plays the processed signal
+    pub output: Mono2StereoOutput,
+    pub filter: AECFiltering,
+    /// Where the microphone stream finds the processing thread to wake it up
+    pub wakeup: Wakeup,
+}
+
+impl Pipeline {
+    /// Creates buffers of `buffer_samples` samples, each holding `latency_samples` of silence.
+    ///
+    /// # Panics
+    ///
+    /// If the latency does not fit in the buffers.
+    pub fn new(
+        parameters: &AECParameters,
+        weights: Vec<f32>,
+        latency_samples: usize,
+        buffer_samples: usize,
+    ) -> Self {
+        assert!(
+            latency_samples <= buffer_samples,
+            "The buffers must be larger than the latency"
+        );
+        let (mut mic_producer, mic_consumer) = RingBuffer::new(buffer_samples).split();
+        let (mut capture_producer, capture_consumer) = RingBuffer::new(buffer_samples).split();
+        let (mut output_producer, output_consumer) = RingBuffer::new(buffer_samples).split();
+        for _ in 0..latency_samples {
+            // SAFETY: checked above that there is room for the latency
+            mic_producer.push(0.0).unwrap();
+            capture_producer.push(0.0).unwrap();
+            output_producer.push(0.0).unwrap();
+        }
+
+        let wakeup = Wakeup(Arc::new(Mutex::new(None)));
+        Pipeline {
+            microphone: Stereo2MonoCapture::new_with_parking(mic_producer, wakeup.0.clone()),
+            capture: Stereo2MonoCapture::new(capture_producer),
+            output: Mono2StereoOutput::new(output_consumer),
+            filter: AECFiltering::with_weights(
+                mic_consumer,
+                capture_consumer,
+                output_producer,
+                parameters,
+                weights,
+            ),
+            wakeup,
+        }
+    }
+}
+
+/// The processing thread, once started, as seen by the microphone stream.
+pub struct Wakeup(Arc<Mutex<Option<Thread>>>);
+
+impl Wakeup {
+    /// Starts the processing thread, and lets the microphone stream wake it up.
+    pub fn start(self, filter: AECFiltering) -> RunningAECFiltering {
+        let (running, thread) = filter.start_thread();
+        *self.0.lock().unwrap() = Some(thread);
+        running
+    }
+}
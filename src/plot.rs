@@ -1,136 +1,1286 @@
+//! Live and offline plots of the processing pipeline.
+//!
+//! Every plot is a `Chart`, which only knows how to draw itself onto a plotters drawing area. A
+//! chart can be shown in a `ChartWindow` or written to a PNG or SVG file with `render_to_file`,
+//! so the same graphs are available on machines without a display.
+
 use circular_queue::CircularQueue;
 use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use plotters::coord::Shift;
 use plotters::prelude::*;
 use plotters_bitmap::bitmap_pixel::BGRXPixel;
 use plotters_bitmap::BitMapBackend;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 
-const W: usize = 480;
-const H: usize = 320;
+use crate::processing::{AECFiltering, Control, OutputMode, SignalFrame, Telemetry};
 
 //const SAMPLE_RATE: f64 = 10_000.0;
 const FRAME_RATE: f64 = 30.0;
 //const WINDOW_TIME: f32 = 5.0;
 
-pub struct Plotter {
+/// Factor applied by one zoom or step size key press.
+const KEY_STEP: f32 = 1.25;
+
+/// Short description of the keyboard controls of the plot windows.
+pub const KEY_HELP: &str = "keys: =/- zoom amplitude, 0/9 zoom time, space pause, \
+1-8 toggle series, up/down change mu, r reset filter weights, b switch between processing and \
+bypass, m cycle through the output modes";
+
+/// What a key press in one of the plot windows asks for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyAction {
+    /// Multiply the time span shown by this factor
+    ZoomTime(f32),
+    /// Multiply the amplitude range shown by this factor
+    ZoomAmplitude(f32),
+    /// Freeze or unfreeze the display
+    TogglePause,
+    /// Show or hide the series with this index
+    ToggleSeries(usize),
+    /// Reset the adaptive filter weights
+    ResetWeights,
+    /// Multiply the adaptive filter step size by this factor
+    ScaleMu(f32),
+    /// Switch between the echo cancelled and the untouched microphone signal
+    ToggleBypass,
+    /// Switch to the next output mode
+    CycleOutputMode,
+}
+
+impl KeyAction {
+    pub fn from_key(key: Key) -> Option<KeyAction> {
+        let action = match key {
+            Key::Equal => KeyAction::ZoomAmplitude(1.0 / KEY_STEP),
+            Key::Minus => KeyAction::ZoomAmplitude(KEY_STEP),
+            Key::Key0 => KeyAction::ZoomTime(1.0 / KEY_STEP),
+            Key::Key9 => KeyAction::ZoomTime(KEY_STEP),
+            Key::Space => KeyAction::TogglePause,
+            Key::Key1 => KeyAction::ToggleSeries(0),
+            Key::Key2 => KeyAction::ToggleSeries(1),
+            Key::Key3 => KeyAction::ToggleSeries(2),
+            Key::Key4 => KeyAction::ToggleSeries(3),
+            Key::Key5 => KeyAction::ToggleSeries(4),
+            Key::Key6 => KeyAction::ToggleSeries(5),
+            Key::Key7 => KeyAction::ToggleSeries(6),
+            Key::Key8 => KeyAction::ToggleSeries(7),
+            Key::Up => KeyAction::ScaleMu(KEY_STEP),
+            Key::Down => KeyAction::ScaleMu(1.0 / KEY_STEP),
+            Key::R => KeyAction::ResetWeights,
+            Key::B => KeyAction::ToggleBypass,
+            Key::M => KeyAction::CycleOutputMode,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// Something that can draw itself onto a drawing area, whichever backend is behind it.
+pub trait Chart {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), anyhow::Error>
+    where
+        DB::ErrorType: 'static;
+}
+
+/// File formats supported by `render_to_file`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "svg" => Ok(ImageFormat::Svg),
+            _ => Err(anyhow::anyhow!(
+                "Unknown image format \"{}\"; use png or svg",
+                s
+            )),
+        }
+    }
+}
+
+/// Renders a chart to an image file of the given size (in pixels).
+pub fn render_to_file<C: Chart>(
+    chart: &C,
+    path: &Path,
+    format: ImageFormat,
+    size: (u32, u32),
+) -> Result<(), anyhow::Error> {
+    match format {
+        ImageFormat::Png => {
+            let root = BitMapBackend::new(path, size).into_drawing_area();
+            root.fill(&BLACK)?;
+            chart.draw(&root)?;
+            root.present()?;
+        }
+        ImageFormat::Svg => {
+            let root = SVGBackend::new(path, size).into_drawing_area();
+            root.fill(&BLACK)?;
+            chart.draw(&root)?;
+            root.present()?;
+        }
+    }
+    Ok(())
+}
+
+/// A window displaying a chart, redrawn at most `FRAME_RATE` times per second.
+pub struct ChartWindow {
     buf: Vec<u8>,
     pub window: Window,
-    cs: plotters::chart::ChartState<
-        Cartesian2d<plotters::coord::types::RangedCoordf32, plotters::coord::types::RangedCoordf32>,
-    >,
+    width: usize,
+    height: usize,
     last_flushed: std::time::Instant,
-    pub data: CircularQueue<(f32, f32, f32, f32)>,
-    window_time: f32,
 }
 
-impl Plotter {
-    pub fn new(
-        window_time: f32,
-        minz: f32,
-        maxz: f32,
-        data_size: usize,
-    ) -> Result<Plotter, anyhow::Error> {
-        let mut buf = vec![0u8; W * H * 4];
-        let root =
-            BitMapBackend::<BGRXPixel>::with_buffer_and_format(&mut buf[..], (W as u32, H as u32))?
+impl ChartWindow {
+    pub fn new(title: &str, width: usize, height: usize) -> Result<ChartWindow, anyhow::Error> {
+        Ok(ChartWindow {
+            buf: vec![0u8; width * height * 4],
+            window: Window::new(title, width, height, WindowOptions::default())?,
+            width,
+            height,
+            last_flushed: std::time::Instant::now(),
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    /// Actions of the keys pressed since the last call to `ChartWindow::show`.
+    pub fn key_actions(&self) -> Vec<KeyAction> {
+        self.window
+            .get_keys_pressed(KeyRepeat::Yes)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(KeyAction::from_key)
+            .collect()
+    }
+
+    /// Keeps the window responsive without drawing a new frame, e.g. while the display is paused.
+    pub fn idle(&mut self) {
+        if self.frame_due() {
+            self.window.update();
+            self.last_flushed = std::time::Instant::now();
+        }
+    }
+
+    /// Whether enough time has passed since the last redraw to draw a new frame.
+    fn frame_due(&self) -> bool {
+        self.last_flushed.elapsed().as_millis() > ((1000.0 / FRAME_RATE) as u128)
+    }
+
+    /// Redraws the chart if a new frame is due.
+    pub fn show<C: Chart>(&mut self, chart: &C) -> Result<(), anyhow::Error> {
+        if self.frame_due() {
+            let (w, h) = (self.width, self.height);
+            {
+                let root = BitMapBackend::<BGRXPixel>::with_buffer_and_format(
+                    &mut self.buf[..],
+                    (w as u32, h as u32),
+                )?
                 .into_drawing_area();
-        root.fill(&BLACK)?;
-        let mut chart = ChartBuilder::on(&root)
+                root.fill(&BLACK)?;
+                chart.draw(&root)?;
+            }
+            let buf2 =
+                unsafe { std::slice::from_raw_parts(&self.buf[0] as *const _ as *const _, h * w) };
+            self.window.update_with_buffer(buf2)?;
+            self.last_flushed = std::time::Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// A single named trace drawn by a `TimeSeriesChart`.
+#[derive(Clone, Debug)]
+pub struct Series {
+    /// Name shown in the legend
+    pub name: String,
+    /// Colour of the trace
+    pub color: RGBColor,
+    /// Whether the series is drawn against the secondary (right hand side) y axis
+    pub secondary_axis: bool,
+}
+
+impl Series {
+    pub fn new(name: &str, color: RGBColor) -> Self {
+        Series {
+            name: name.to_string(),
+            color,
+            secondary_axis: false,
+        }
+    }
+
+    /// Draw this series against the secondary y axis instead of the primary one.
+    pub fn on_secondary_axis(mut self) -> Self {
+        self.secondary_axis = true;
+        self
+    }
+}
+
+/// Options for the `Plotter` window and axes.
+#[derive(Clone, Debug)]
+pub struct PlotterConfig {
+    /// Window title
+    pub title: String,
+    /// Window width in pixels
+    pub width: usize,
+    /// Window height in pixels
+    pub height: usize,
+    /// Time span (s) shown on the x axis before wrapping around
+    pub window_time: f32,
+    /// Range of the primary y axis; used as is unless `autoscale` is set
+    pub y_range: Range<f32>,
+    /// Range of the secondary y axis; used as is unless `autoscale` is set
+    pub secondary_y_range: Range<f32>,
+    /// Fit the y axes to the data currently held by the plotter
+    pub autoscale: bool,
+    /// Number of data points kept for plotting
+    pub data_size: usize,
+}
+
+impl Default for PlotterConfig {
+    fn default() -> Self {
+        PlotterConfig {
+            title: "raec".to_string(),
+            width: 480,
+            height: 320,
+            window_time: 5.0,
+            y_range: 0.0..1.0,
+            secondary_y_range: 0.0..1.0,
+            autoscale: false,
+            data_size: 500,
+        }
+    }
+}
+
+/// Named series over time; the x axis wraps around every `PlotterConfig::window_time` seconds
+/// and older points fade out.
+pub struct TimeSeriesChart {
+    config: PlotterConfig,
+    series: Vec<Series>,
+    /// Whether each series is currently hidden
+    hidden: Vec<bool>,
+    /// Factor applied to `PlotterConfig::window_time`
+    time_zoom: f32,
+    /// Factor applied to the span of the y axes
+    amplitude_zoom: f32,
+    /// Data points as (time (s), one value per series); newest first when iterated.
+    pub data: CircularQueue<(f32, Vec<f32>)>,
+}
+
+impl TimeSeriesChart {
+    pub fn new(config: PlotterConfig, series: Vec<Series>) -> Self {
+        TimeSeriesChart {
+            data: CircularQueue::with_capacity(config.data_size),
+            hidden: vec![false; series.len()],
+            time_zoom: 1.0,
+            amplitude_zoom: 1.0,
+            series,
+            config,
+        }
+    }
+
+    /// Multiplies the time span shown by `factor`.
+    pub fn zoom_time(&mut self, factor: f32) {
+        self.time_zoom *= factor;
+    }
+
+    /// Multiplies the span of the y axes by `factor`, keeping them centred.
+    pub fn zoom_amplitude(&mut self, factor: f32) {
+        self.amplitude_zoom *= factor;
+    }
+
+    /// Shows or hides the series with the given index; out of range indices are ignored.
+    pub fn toggle_series(&mut self, index: usize) {
+        if let Some(hidden) = self.hidden.get_mut(index) {
+            *hidden = !*hidden;
+        }
+    }
+
+    /// Applies the display related key actions; returns whether the action was one of them.
+    pub fn apply_key_action(&mut self, action: KeyAction) -> bool {
+        match action {
+            KeyAction::ZoomTime(factor) => self.zoom_time(factor),
+            KeyAction::ZoomAmplitude(factor) => self.zoom_amplitude(factor),
+            KeyAction::ToggleSeries(index) => self.toggle_series(index),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn config(&self) -> &PlotterConfig {
+        &self.config
+    }
+
+    /// Adds a data point; `values` must hold one value per series, in the order they were given
+    /// to `TimeSeriesChart::new`.
+    pub fn push(&mut self, time: f32, values: &[f32]) {
+        assert_eq!(
+            values.len(),
+            self.series.len(),
+            "Plotter expects one value per series"
+        );
+        self.data.push((time, values.to_vec()));
+    }
+
+    /// Computes the y range to use for the series on the given axis.
+    fn y_range(&self, secondary_axis: bool) -> Range<f32> {
+        let range = self.unzoomed_y_range(secondary_axis);
+        let center = 0.5 * (range.start + range.end);
+        let half_span = 0.5 * (range.end - range.start) * self.amplitude_zoom;
+        (center - half_span)..(center + half_span)
+    }
+
+    fn unzoomed_y_range(&self, secondary_axis: bool) -> Range<f32> {
+        let fixed = if secondary_axis {
+            self.config.secondary_y_range.clone()
+        } else {
+            self.config.y_range.clone()
+        };
+        if !self.config.autoscale {
+            return fixed;
+        }
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        for (_, values) in self.data.iter() {
+            for ((series, &value), &hidden) in self
+                .series
+                .iter()
+                .zip(values.iter())
+                .zip(self.hidden.iter())
+            {
+                if series.secondary_axis == secondary_axis && !hidden && value.is_finite() {
+                    min = min.min(value);
+                    max = max.max(value);
+                }
+            }
+        }
+        if min > max {
+            // no data on this axis yet
+            return fixed;
+        }
+        let margin = if max - min > f32::EPSILON {
+            0.05 * (max - min)
+        } else {
+            0.5 * max.abs().max(1.0)
+        };
+        (min - margin)..(max + margin)
+    }
+}
+
+impl Chart for TimeSeriesChart {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), anyhow::Error>
+    where
+        DB::ErrorType: 'static,
+    {
+        let window_time = self.config.window_time * self.time_zoom;
+        let has_secondary = self.series.iter().any(|s| s.secondary_axis);
+
+        let mut chart = ChartBuilder::on(root)
             .margin(10)
             .set_all_label_area_size(30)
-            .build_cartesian_2d(0.0_f32..window_time, minz..maxz)?;
+            .build_cartesian_2d(0.0_f32..window_time, self.y_range(false))?
+            .set_secondary_coord(0.0_f32..window_time, self.y_range(true));
 
         chart
             .configure_mesh()
             .label_style(("sans-serif", 15).into_font().color(&GREEN))
-            .axis_style(&GREEN)
+            .axis_style(GREEN)
+            .bold_line_style(GREEN.mix(0.2))
+            .light_line_style(TRANSPARENT)
             .draw()?;
+        if has_secondary {
+            chart
+                .configure_secondary_axes()
+                .label_style(("sans-serif", 15).into_font().color(&GREEN))
+                .axis_style(GREEN)
+                .draw()?;
+        }
 
-        let cs = chart.into_chart_state();
-        drop(root);
+        let latest_time = self.data.iter().next().map(|x| x.0).unwrap_or_default();
+        for (index, series) in self.series.iter().enumerate() {
+            if self.hidden[index] {
+                continue;
+            }
+            let color = series.color;
+            // a series may have no value, e.g. the step size of the `kalman` algorithm
+            let segments = self
+                .data
+                .iter()
+                .zip(self.data.iter().skip(1))
+                .filter(|((_, y0), (_, y1))| y0[index].is_finite() && y1[index].is_finite())
+                .map(|((x0, y0), (x1, y1))| {
+                    PathElement::new(
+                        vec![
+                            (x0 % window_time, y0[index]),
+                            (x0 % window_time + (x1 - x0), y1[index]),
+                        ],
+                        color.mix(((x0 - latest_time) * 2.0).exp().into()),
+                    )
+                });
+            let annotation = if series.secondary_axis {
+                chart.draw_secondary_series(segments)?
+            } else {
+                chart.draw_series(segments)?
+            };
+            annotation
+                .label(series.name.as_str())
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 15, y)], color));
+        }
+
+        chart
+            .configure_series_labels()
+            .label_font(("sans-serif", 15).into_font().color(&GREEN))
+            .background_style(BLACK.mix(0.8))
+            .border_style(GREEN)
+            .draw()?;
+        Ok(())
+    }
+}
+
+/// Window plotting a `TimeSeriesChart`; reacts to the display related keys of `KeyAction`.
+pub struct Plotter {
+    window: ChartWindow,
+    pub chart: TimeSeriesChart,
+    paused: bool,
+}
+
+impl Plotter {
+    pub fn new(config: PlotterConfig, series: Vec<Series>) -> Result<Plotter, anyhow::Error> {
         Ok(Plotter {
-            window: Window::new("Test window", W, H, WindowOptions::default())?,
-            buf: buf,
-            cs: cs,
-            last_flushed: std::time::Instant::now(),
-            data: CircularQueue::with_capacity(data_size),
-            window_time: window_time,
+            window: ChartWindow::new(&config.title, config.width, config.height)?,
+            chart: TimeSeriesChart::new(config, series),
+            paused: false,
         })
     }
 
+    /// Adds a data point; see `TimeSeriesChart::push`.
+    pub fn push(&mut self, time: f32, values: &[f32]) {
+        self.chart.push(time, values);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
     pub fn tick(&mut self) -> Result<(), anyhow::Error> {
-        if self.last_flushed.elapsed().as_millis() > ((1000.0 / FRAME_RATE) as u128) {
-            let root = BitMapBackend::<BGRXPixel>::with_buffer_and_format(
-                &mut self.buf[..],
-                (W as u32, H as u32),
-            )?
-            .into_drawing_area();
-            let mut chart = self.cs.clone().restore(&root);
-            chart.plotting_area().fill(&BLACK)?;
+        for action in self.window.key_actions() {
+            if action == KeyAction::TogglePause {
+                self.paused = !self.paused;
+            } else {
+                self.chart.apply_key_action(action);
+            }
+        }
+        if self.paused {
+            self.window.idle();
+            Ok(())
+        } else {
+            self.window.show(&self.chart)
+        }
+    }
+}
+
+/// Options for the `SpectrogramChart`.
+#[derive(Clone, Debug)]
+pub struct SpectrogramConfig {
+    /// Window title
+    pub title: String,
+    /// Window width in pixels
+    pub width: usize,
+    /// Window height in pixels
+    pub height: usize,
+    /// Sample rate of the tapped signals (Hz)
+    pub sample_rate: f32,
+    /// Length of the analysis window (samples)
+    pub fft_size: usize,
+    /// Number of samples between two consecutive columns
+    pub hop_size: usize,
+    /// Number of columns kept on screen
+    pub history: usize,
+    /// Highest frequency shown (Hz)
+    pub max_frequency: f32,
+    /// Magnitudes at or below this level are drawn black (dB)
+    pub min_db: f32,
+    /// Magnitudes at or above this level are drawn with the hottest colour (dB)
+    pub max_db: f32,
+    /// Frequencies (Hz) marked with a horizontal line, e.g. the band-pass corners
+    pub markers: Vec<f32>,
+}
+
+impl Default for SpectrogramConfig {
+    fn default() -> Self {
+        SpectrogramConfig {
+            title: "raec: spectrogram".to_string(),
+            width: 960,
+            height: 320,
+            sample_rate: 48_000.0,
+            fft_size: 1024,
+            hop_size: 512,
+            history: 200,
+            max_frequency: 8_000.0,
+            min_db: -100.0,
+            max_db: -20.0,
+            markers: vec![],
+        }
+    }
+}
+
+/// Names of the panels of the `SpectrogramChart`, in the order they are drawn.
+const SPECTROGRAM_PANELS: [&str; 3] = ["microphone", "reference", "output"];
+
+/// Scrolling spectrogram of the microphone, reference and output signals, drawn side by side.
+/// Fed with the frames read from `AECFiltering::signal_tap`.
+pub struct SpectrogramChart {
+    config: SpectrogramConfig,
+    fft: std::sync::Arc<dyn Fft<f32>>,
+    analysis_window: Vec<f32>,
+    /// Samples not yet analysed, one queue per panel
+    pending: [VecDeque<f32>; 3],
+    /// Magnitude columns (dB) one queue per panel; newest at the back
+    columns: [VecDeque<Vec<f32>>; 3],
+}
+
+impl SpectrogramChart {
+    pub fn new(config: SpectrogramConfig) -> Self {
+        assert!(
+            config.hop_size > 0 && config.hop_size <= config.fft_size,
+            "Spectrogram hop size must be between 1 and the FFT size"
+        );
+        let fft = FftPlanner::new().plan_fft_forward(config.fft_size);
+        // Hann window
+        let analysis_window = (0..config.fft_size)
+            .map(|i| {
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / config.fft_size as f32).cos()
+            })
+            .collect();
+        SpectrogramChart {
+            fft,
+            analysis_window,
+            pending: Default::default(),
+            columns: Default::default(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &SpectrogramConfig {
+        &self.config
+    }
 
+    /// Adds one frame of samples; columns are computed once enough samples are available.
+    pub fn push(&mut self, frame: SignalFrame) {
+        self.pending[0].push_back(frame.mic);
+        self.pending[1].push_back(frame.reference);
+        self.pending[2].push_back(frame.output);
+        if self.pending[0].len() >= self.config.fft_size {
+            self.analyse();
+        }
+    }
+
+    /// Computes the spectrogram columns for all complete analysis windows.
+    fn analyse(&mut self) {
+        let fft_size = self.config.fft_size;
+        let n_bins = fft_size / 2 + 1;
+        // normalise so that a full scale sine reads roughly 0 dB
+        let scale = 2.0 / self.analysis_window.iter().sum::<f32>();
+        let mut spectrum = vec![Complex::new(0.0, 0.0); fft_size];
+        for (pending, columns) in self.pending.iter_mut().zip(self.columns.iter_mut()) {
+            while pending.len() >= fft_size {
+                for ((bin, &sample), &w) in spectrum
+                    .iter_mut()
+                    .zip(pending.iter())
+                    .zip(self.analysis_window.iter())
+                {
+                    *bin = Complex::new(sample * w, 0.0);
+                }
+                self.fft.process(&mut spectrum);
+                columns.push_back(
+                    spectrum[..n_bins]
+                        .iter()
+                        .map(|c| 20.0 * (c.norm() * scale + 1e-12).log10())
+                        .collect(),
+                );
+                if columns.len() > self.config.history {
+                    columns.pop_front();
+                }
+                pending.drain(..self.config.hop_size);
+            }
+        }
+    }
+
+    /// Maps a magnitude in dB to a colour: black below `min_db`, then blue through red to yellow.
+    fn heat_color(&self, db: f32) -> HSLColor {
+        let level = ((db - self.config.min_db) / (self.config.max_db - self.config.min_db))
+            .clamp(0.0, 1.0) as f64;
+        HSLColor(0.66 - 0.5 * level, 1.0, 0.5 * level.sqrt())
+    }
+}
+
+impl Chart for SpectrogramChart {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), anyhow::Error>
+    where
+        DB::ErrorType: 'static,
+    {
+        let nyquist = self.config.sample_rate / 2.0;
+        let max_frequency = self.config.max_frequency.min(nyquist);
+        let n_bins = self.config.fft_size / 2 + 1;
+        let history = self.config.history;
+        let history_time = (history * self.config.hop_size) as f32 / self.config.sample_rate;
+
+        let panels = root.split_evenly((1, SPECTROGRAM_PANELS.len()));
+        for ((panel, name), columns) in panels
+            .iter()
+            .zip(SPECTROGRAM_PANELS.iter())
+            .zip(self.columns.iter())
+        {
+            let mut chart = ChartBuilder::on(panel)
+                .caption(*name, ("sans-serif", 15).into_font().color(&GREEN))
+                .margin(5)
+                .x_label_area_size(25)
+                .y_label_area_size(40)
+                .build_cartesian_2d(-history_time..0.0_f32, 0.0_f32..max_frequency)?;
             chart
                 .configure_mesh()
-                .bold_line_style(&GREEN.mix(0.2))
-                .light_line_style(&TRANSPARENT)
+                .disable_mesh()
+                .x_labels(3)
+                .label_style(("sans-serif", 12).into_font().color(&GREEN))
+                .axis_style(GREEN)
                 .draw()?;
 
-            let latest_time = self.data.iter().next().map(|x| x.0).unwrap_or_default();
-            let window_time = self.window_time;
-            chart.draw_series(self.data.iter().zip(self.data.iter().skip(1)).map(
-                |(&(x0, y0, _, _), &(x1, y1, _, _))| {
-                    PathElement::new(
-                        vec![(x0 % window_time, y0), (x0 % window_time + (x1 - x0), y1)],
-                        &RED.mix(((x0 - latest_time) * 2.0).exp().into()),
-                    )
-                },
-            ))?;
-            chart.draw_series(self.data.iter().zip(self.data.iter().skip(1)).map(
-                |(&(x0, _, y0, _), &(x1, _, y1, _))| {
-                    PathElement::new(
-                        vec![(x0 % window_time, y0), (x0 % window_time + (x1 - x0), y1)],
-                        &GREEN.mix(((x0 - latest_time) * 2.0).exp().into()),
-                    )
-                },
-            ))?;
-            chart.draw_series(self.data.iter().zip(self.data.iter().skip(1)).map(
-                |(&(x0, _, _, y0), &(x1, _, _, y1))| {
-                    PathElement::new(
-                        vec![(x0 % window_time, y0), (x0 % window_time + (x1 - x0), y1)],
-                        &BLUE.mix(((x0 - latest_time) * 2.0).exp().into()),
-                    )
-                },
-            ))?;
-
-            drop(root);
-            drop(chart);
-
-            if let Some(keys) = self.window.get_keys_pressed(KeyRepeat::Yes) {
-                for key in keys {
-                    match key {
-                        Key::Equal => {
-                            //fy += 0.1;
-                        }
-                        Key::Minus => {
-                            //fy -= 0.1;
-                        }
-                        Key::Key0 => {
-                            //fx += 0.1;
-                        }
-                        Key::Key9 => {
-                            //fx -= 0.1;
-                        }
-                        _ => {
-                            continue;
-                        }
+            // paint the cells pixel by pixel; far cheaper than one rectangle per bin
+            let area = chart.plotting_area().strip_coord_spec();
+            let (area_w, area_h) = area.dim_in_pixel();
+            let offset = history - columns.len().min(history);
+            for px in 0..area_w {
+                let column = (px as usize * history / area_w as usize)
+                    .checked_sub(offset)
+                    .and_then(|c| columns.get(c));
+                if let Some(column) = column {
+                    for py in 0..area_h {
+                        let frequency = max_frequency * (1.0 - py as f32 / area_h as f32);
+                        let bin = ((frequency / nyquist) * (n_bins - 1) as f32) as usize;
+                        area.draw_pixel(
+                            (px as i32, py as i32),
+                            &self.heat_color(column[bin.min(n_bins - 1)]),
+                        )?;
                     }
-                    break;
                 }
             }
-            let buf2 =
-                unsafe { std::slice::from_raw_parts(&self.buf[0] as *const _ as *const _, H * W) };
-            self.window.update_with_buffer(&buf2)?;
-            self.last_flushed = std::time::Instant::now();
+
+            for &marker in self.config.markers.iter() {
+                if marker < max_frequency {
+                    chart.draw_series(std::iter::once(PathElement::new(
+                        vec![(-history_time, marker), (0.0, marker)],
+                        WHITE.mix(0.5),
+                    )))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for the `WeightsChart`.
+#[derive(Clone, Debug)]
+pub struct WeightsViewConfig {
+    /// Window title
+    pub title: String,
+    /// Window width in pixels
+    pub width: usize,
+    /// Window height in pixels
+    pub height: usize,
+    /// Sample rate the filter runs at (Hz)
+    pub sample_rate: f32,
+    /// Number of taps in one snapshot
+    pub n_taps: usize,
+    /// Label the impulse response in milliseconds instead of tap indices
+    pub time_in_ms: bool,
+    /// Lowest level shown in the magnitude response (dB)
+    pub min_db: f32,
+}
+
+impl Default for WeightsViewConfig {
+    fn default() -> Self {
+        WeightsViewConfig {
+            title: "raec: adaptive filter".to_string(),
+            width: 640,
+            height: 480,
+            sample_rate: 48_000.0,
+            n_taps: crate::nlmf::N_TAPS,
+            time_in_ms: true,
+            min_db: -60.0,
+        }
+    }
+}
+
+/// Impulse response and frequency magnitude response of the adaptive filter, with the
+/// estimated bulk delay marked.
+pub struct WeightsChart {
+    config: WeightsViewConfig,
+    fft: std::sync::Arc<dyn Fft<f32>>,
+    /// Latest snapshot, ordered by delay (first element multiplies the newest input)
+    impulse_response: Vec<f32>,
+    /// Magnitude response of the latest snapshot (dB), from 0 Hz to the Nyquist frequency
+    magnitude_response: Vec<f32>,
+}
+
+impl WeightsChart {
+    pub fn new(config: WeightsViewConfig) -> Self {
+        // zero padded for a smoother magnitude response
+        let fft = FftPlanner::new().plan_fft_forward((2 * config.n_taps).next_power_of_two());
+        WeightsChart {
+            fft,
+            impulse_response: vec![0.0; config.n_taps],
+            magnitude_response: vec![],
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &WeightsViewConfig {
+        &self.config
+    }
+
+    /// Replaces the displayed filter by the given weights, in the order of `NLMF::weights`.
+    pub fn set_weights(&mut self, weights: &[f32]) {
+        // the last weight multiplies the newest input sample, i.e. has zero delay
+        self.impulse_response = weights.iter().rev().cloned().collect();
+
+        let mut spectrum = vec![Complex::new(0.0, 0.0); self.fft.len()];
+        for (bin, &w) in spectrum.iter_mut().zip(self.impulse_response.iter()) {
+            *bin = Complex::new(w, 0.0);
+        }
+        self.fft.process(&mut spectrum);
+        self.magnitude_response = spectrum[..=self.fft.len() / 2]
+            .iter()
+            .map(|c| 20.0 * (c.norm() + 1e-12).log10())
+            .collect();
+    }
+
+    /// Reads all complete snapshots available on the consumer end of `AECFiltering::weights_tap`
+    /// and keeps the newest one.
+    pub fn read_snapshots(&mut self, tap: &mut ringbuf::Consumer<f32>) {
+        let n_taps = self.config.n_taps;
+        let mut snapshot = vec![0.0; n_taps];
+        let mut updated = false;
+        while tap.len() >= n_taps {
+            let _ = tap.pop_slice(&mut snapshot);
+            updated = true;
+        }
+        if updated {
+            self.set_weights(&snapshot);
+        }
+    }
+}
+
+impl Chart for WeightsChart {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), anyhow::Error>
+    where
+        DB::ErrorType: 'static,
+    {
+        let x_scale = if self.config.time_in_ms {
+            1_000.0 / self.config.sample_rate
+        } else {
+            1.0
+        };
+        let (x_label, unit) = if self.config.time_in_ms {
+            ("delay (ms)", "ms")
+        } else {
+            ("tap", "taps")
         };
+        let x_max = self.config.n_taps as f32 * x_scale;
+        let peak = self
+            .impulse_response
+            .iter()
+            .fold(0.0_f32, |max, w| max.max(w.abs()));
+        let y_max = if peak > 0.0 { 1.1 * peak } else { 1.0 };
+        let (delay, _) =
+            self.impulse_response
+                .iter()
+                .enumerate()
+                .fold((0, 0.0_f32), |(best, max), (i, w)| {
+                    if w.abs() > max {
+                        (i, w.abs())
+                    } else {
+                        (best, max)
+                    }
+                });
+        let delay = delay as f32 * x_scale;
+        let nyquist = self.config.sample_rate / 2.0;
+        let db_max = self
+            .magnitude_response
+            .iter()
+            .fold(self.config.min_db + 10.0, |max, &db| max.max(db))
+            + 5.0;
 
+        let (_, h) = root.dim_in_pixel();
+        let (upper, lower) = root.split_vertically(h / 2);
+
+        let mut chart = ChartBuilder::on(&upper)
+            .caption(
+                format!("impulse response (bulk delay {:.1} {})", delay, unit),
+                ("sans-serif", 15).into_font().color(&GREEN),
+            )
+            .margin(10)
+            .x_label_area_size(35)
+            .y_label_area_size(45)
+            .build_cartesian_2d(0.0_f32..x_max, -y_max..y_max)?;
+        chart
+            .configure_mesh()
+            .x_desc(x_label)
+            .label_style(("sans-serif", 12).into_font().color(&GREEN))
+            .axis_style(GREEN)
+            .bold_line_style(GREEN.mix(0.2))
+            .light_line_style(TRANSPARENT)
+            .draw()?;
+        chart.draw_series(LineSeries::new(
+            self.impulse_response
+                .iter()
+                .enumerate()
+                .map(|(i, &w)| (i as f32 * x_scale, w)),
+            YELLOW,
+        ))?;
+        chart.draw_series(std::iter::once(PathElement::new(
+            vec![(delay, -y_max), (delay, y_max)],
+            RED.mix(0.7),
+        )))?;
+
+        let mut chart = ChartBuilder::on(&lower)
+            .caption(
+                "magnitude response",
+                ("sans-serif", 15).into_font().color(&GREEN),
+            )
+            .margin(10)
+            .x_label_area_size(35)
+            .y_label_area_size(45)
+            .build_cartesian_2d(0.0_f32..nyquist, self.config.min_db..db_max)?;
+        chart
+            .configure_mesh()
+            .x_desc("frequency (Hz)")
+            .y_desc("dB")
+            .label_style(("sans-serif", 12).into_font().color(&GREEN))
+            .axis_style(GREEN)
+            .bold_line_style(GREEN.mix(0.2))
+            .light_line_style(TRANSPARENT)
+            .draw()?;
+        let bin_width = nyquist / (self.magnitude_response.len().max(2) - 1) as f32;
+        let min_db = self.config.min_db;
+        chart.draw_series(LineSeries::new(
+            self.magnitude_response
+                .iter()
+                .enumerate()
+                .map(|(i, &db)| (i as f32 * bin_width, db.max(min_db))),
+            CYAN,
+        ))?;
         Ok(())
     }
 }
+
+/// Which views a `Dashboard` should collect.
+#[derive(Clone, Debug, Default)]
+pub struct DashboardOptions {
+    /// Buffer levels and ERLE over time
+    pub telemetry: bool,
+    /// Spectrograms of microphone, reference and output
+    pub spectrogram: bool,
+    /// Impulse and magnitude response of the adaptive filter
+    pub weights: bool,
+    /// Show the views in windows; otherwise they are only collected, e.g. for `Dashboard::save`
+    pub windows: bool,
+    /// Sample rate of the processing (Hz)
+    pub sample_rate: f32,
+    /// Step size the adaptive filter starts with; changed with the up and down keys
+    pub mu: f32,
+}
+
+/// A chart together with the window it is shown in, if any.
+struct View<C> {
+    chart: C,
+    window: Option<ChartWindow>,
+    size: (usize, usize),
+}
+
+impl<C: Chart> View<C> {
+    fn new(
+        chart: C,
+        title: &str,
+        size: (usize, usize),
+        windowed: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let window = if windowed {
+            Some(ChartWindow::new(title, size.0, size.1)?)
+        } else {
+            None
+        };
+        Ok(View {
+            chart,
+            window,
+            size,
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.as_ref().map(|w| w.is_open()).unwrap_or(false)
+    }
+
+    fn key_actions(&self) -> Vec<KeyAction> {
+        match self.window.as_ref() {
+            Some(window) if window.is_open() => window.key_actions(),
+            _ => vec![],
+        }
+    }
+
+    fn show(&mut self, paused: bool) -> Result<(), anyhow::Error> {
+        match self.window.as_mut() {
+            Some(window) if window.is_open() => {
+                if paused {
+                    window.idle();
+                    Ok(())
+                } else {
+                    window.show(&self.chart)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self, path: PathBuf, format: ImageFormat) -> Result<PathBuf, anyhow::Error> {
+        let path = path.with_extension(format.extension());
+        render_to_file(
+            &self.chart,
+            &path,
+            format,
+            (self.size.0 as u32, self.size.1 as u32),
+        )?;
+        Ok(path)
+    }
+}
+
+/// The live views of an `AECFiltering`: owns the receiving ends of its taps, keeps the charts up
+/// to date and shows them in windows and/or renders them to files.
+pub struct Dashboard {
+    telemetry: Option<(mpsc::Receiver<Telemetry>, View<TimeSeriesChart>)>,
+    spectrogram: Option<(ringbuf::Consumer<SignalFrame>, View<SpectrogramChart>)>,
+    weights: Option<(ringbuf::Consumer<f32>, View<WeightsChart>)>,
+    /// Whether the windows are frozen; data is still collected while paused
+    paused: bool,
+    /// Current step size of the adaptive filter, as set from the keyboard or followed with
+    /// `Dashboard::follow`
+    mu: f32,
+    /// What the filter outputs, as set from the keyboard or followed
+    output_mode: OutputMode,
+}
+
+impl Dashboard {
+    /// Creates the requested views and connects the corresponding taps of `filter`.
+    pub fn attach(
+        filter: &mut AECFiltering,
+        options: &DashboardOptions,
+    ) -> Result<Dashboard, anyhow::Error> {
+        let parameters = filter.parameters().clone();
+        let telemetry = if options.telemetry {
+            let (sender, receiver) = mpsc::channel();
+            filter.debug_channel = Some(sender);
+            let config = PlotterConfig {
+                title: "raec: buffer levels and ERLE".to_string(),
+                secondary_y_range: -10.0..40.0,
+                autoscale: true,
+                ..PlotterConfig::default()
+            };
+            let (title, size) = (config.title.clone(), (config.width, config.height));
+            let chart = TimeSeriesChart::new(
+                config,
+                vec![
+                    Series::new("microphone buffer", RED),
+                    Series::new("capture buffer", GREEN),
+                    Series::new("output buffer", BLUE),
+                    Series::new("step size", MAGENTA),
+                    Series::new("ERLE (dB)", YELLOW).on_secondary_axis(),
+                ],
+            );
+            Some((receiver, View::new(chart, &title, size, options.windows)?))
+        } else {
+            None
+        };
+
+        let spectrogram = if options.spectrogram {
+            // one second worth of samples is plenty for the plotting thread to catch up
+            let (producer, consumer) =
+                ringbuf::RingBuffer::new(options.sample_rate as usize).split();
+            filter.signal_tap = Some(producer);
+            let config = SpectrogramConfig {
+                sample_rate: options.sample_rate,
+                markers: [parameters.highpass_hz, parameters.lowpass_hz]
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .collect(),
+                ..SpectrogramConfig::default()
+            };
+            let (title, size) = (config.title.clone(), (config.width, config.height));
+            let view = View::new(SpectrogramChart::new(config), &title, size, options.windows)?;
+            Some((consumer, view))
+        } else {
+            None
+        };
+
+        let weights = if options.weights {
+            if filter.weights().is_empty() {
+                return Err(anyhow::anyhow!(
+                    "The {} algorithm has no time domain weights to show",
+                    parameters.algorithm
+                ));
+            }
+            let config = WeightsViewConfig {
+                sample_rate: options.sample_rate,
+                n_taps: parameters.taps,
+                ..WeightsViewConfig::default()
+            };
+            let (producer, consumer) = ringbuf::RingBuffer::new(4 * config.n_taps).split();
+            filter.weights_tap = Some(producer);
+            let (title, size) = (config.title.clone(), (config.width, config.height));
+            let view = View::new(WeightsChart::new(config), &title, size, options.windows)?;
+            Some((consumer, view))
+        } else {
+            None
+        };
+
+        Ok(Dashboard {
+            telemetry,
+            spectrogram,
+            weights,
+            paused: false,
+            mu: options.mu,
+            output_mode: parameters.output_mode,
+        })
+    }
+
+    /// Whether any of the views is shown in a window which is still open.
+    pub fn windows_open(&self) -> bool {
+        self.telemetry.as_ref().is_some_and(|(_, v)| v.is_open())
+            || self.spectrogram.as_ref().is_some_and(|(_, v)| v.is_open())
+            || self.weights.as_ref().is_some_and(|(_, v)| v.is_open())
+    }
+
+    /// Handles the keys pressed in any of the windows, reads everything available on the taps
+    /// and redraws the open windows. Returns the control messages requested from the keyboard,
+    /// to be forwarded to the running filter.
+    pub fn update(&mut self) -> Result<Vec<Control>, anyhow::Error> {
+        let mut actions = vec![];
+        if let Some((_, view)) = self.telemetry.as_ref() {
+            actions.extend(view.key_actions());
+        }
+        if let Some((_, view)) = self.spectrogram.as_ref() {
+            actions.extend(view.key_actions());
+        }
+        if let Some((_, view)) = self.weights.as_ref() {
+            actions.extend(view.key_actions());
+        }
+        let controls = self.handle_keys(actions);
+
+        let paused = self.paused;
+        if let Some((receiver, view)) = self.telemetry.as_mut() {
+            for telemetry in receiver.try_iter() {
+                view.chart.push(
+                    telemetry.time,
+                    &[
+                        telemetry.mic_level,
+                        telemetry.capture_level,
+                        telemetry.output_level,
+                        telemetry.mu,
+                        telemetry.erle_db,
+                    ],
+                );
+            }
+            view.show(paused)?;
+        }
+        if let Some((tap, view)) = self.spectrogram.as_mut() {
+            while let Ok(frame) = tap.pop() {
+                view.chart.push(frame);
+            }
+            view.show(paused)?;
+        }
+        if let Some((tap, view)) = self.weights.as_mut() {
+            view.chart.read_snapshots(tap);
+            view.show(paused)?;
+        }
+        Ok(controls)
+    }
+
+    /// Acts on the keys pressed; returns the control messages they request.
+    fn handle_keys(&mut self, actions: Vec<KeyAction>) -> Vec<Control> {
+        let mut controls = vec![];
+        for action in actions {
+            match action {
+                KeyAction::TogglePause => self.paused = !self.paused,
+                KeyAction::ResetWeights => controls.push(Control::ResetWeights),
+                KeyAction::ScaleMu(factor) => {
+                    self.mu *= factor;
+                    controls.push(Control::SetMu(self.mu));
+                }
+                KeyAction::ToggleBypass | KeyAction::CycleOutputMode => {
+                    self.output_mode = match (action, self.output_mode) {
+                        (KeyAction::CycleOutputMode, mode) => mode.next(),
+                        (_, OutputMode::Bypass) => OutputMode::Processing,
+                        _ => OutputMode::Bypass,
+                    };
+                    controls.push(Control::SetOutputMode(self.output_mode));
+                }
+                _ => {
+                    if let Some((_, view)) = self.telemetry.as_mut() {
+                        view.chart.apply_key_action(action);
+                    }
+                }
+            }
+        }
+        controls
+    }
+
+    /// Takes note of a control message sent to the filter from elsewhere, e.g. on a reload of the
+    /// configuration, so that the keys change the step size and output mode from their current
+    /// values.
+    pub fn follow(&mut self, control: &Control) {
+        match *control {
+            Control::SetMu(mu) => self.mu = mu,
+            Control::SetOutputMode(mode) => self.output_mode = mode,
+            _ => (),
+        }
+    }
+
+    /// Renders every view to a file in `directory`; returns the paths written.
+    pub fn save(
+        &self,
+        directory: &Path,
+        format: ImageFormat,
+    ) -> Result<Vec<PathBuf>, anyhow::Error> {
+        std::fs::create_dir_all(directory)?;
+        let mut written = vec![];
+        if let Some((_, view)) = self.telemetry.as_ref() {
+            written.push(view.save(directory.join("telemetry"), format)?);
+        }
+        if let Some((_, view)) = self.spectrogram.as_ref() {
+            written.push(view.save(directory.join("spectrogram"), format)?);
+        }
+        if let Some((_, view)) = self.weights.as_ref() {
+            written.push(view.save(directory.join("weights"), format)?);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_all(format: ImageFormat) -> Vec<PathBuf> {
+        let directory = std::env::temp_dir().join("raec_plot_tests");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut time_series = TimeSeriesChart::new(
+            PlotterConfig {
+                autoscale: true,
+                ..PlotterConfig::default()
+            },
+            vec![
+                Series::new("level", RED),
+                Series::new("ERLE (dB)", YELLOW).on_secondary_axis(),
+            ],
+        );
+        let mut spectrogram = SpectrogramChart::new(SpectrogramConfig::default());
+        let mut weights = WeightsChart::new(WeightsViewConfig::default());
+        for i in 0..48_000 {
+            let t = i as f32 / 48_000.0;
+            let tone = (2.0 * std::f32::consts::PI * 1_000.0 * t).sin();
+            spectrogram.push(SignalFrame {
+                mic: tone,
+                reference: 0.5 * tone,
+                output: 0.01 * tone,
+                ..SignalFrame::default()
+            });
+            if i % 1_000 == 0 {
+                time_series.push(t, &[0.5, 20.0 * t]);
+            }
+        }
+        let mut filter = vec![0.0; crate::nlmf::N_TAPS];
+        filter[crate::nlmf::N_TAPS - 100] = 0.8;
+        weights.set_weights(&filter);
+
+        let paths = vec![
+            directory
+                .join("time_series")
+                .with_extension(format.extension()),
+            directory
+                .join("spectrogram")
+                .with_extension(format.extension()),
+            directory.join("weights").with_extension(format.extension()),
+        ];
+        render_to_file(&time_series, &paths[0], format, (480, 320)).unwrap();
+        render_to_file(&spectrogram, &paths[1], format, (960, 320)).unwrap();
+        render_to_file(&weights, &paths[2], format, (640, 480)).unwrap();
+        paths
+    }
+
+    #[test]
+    fn test_render_png() {
+        for path in render_all(ImageFormat::Png) {
+            let bytes = std::fs::read(&path).unwrap();
+            assert!(
+                bytes.starts_with(b"\x89PNG"),
+                "{} is not a PNG",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_svg() {
+        for path in render_all(ImageFormat::Svg) {
+            let text = std::fs::read_to_string(&path).unwrap();
+            assert!(text.contains("<svg"), "{} is not an SVG", path.display());
+        }
+    }
+
+    #[test]
+    fn test_keys_follow_reloaded_settings() {
+        let parameters = crate::processing::AECParameters::default();
+        let mut filter = AECFiltering::offline(&parameters, vec![0.0; parameters.taps]);
+        let options = DashboardOptions {
+            mu: 1.0,
+            ..DashboardOptions::default()
+        };
+        let mut dashboard = Dashboard::attach(&mut filter, &options).unwrap();
+        assert_eq!(
+            dashboard.handle_keys(vec![KeyAction::ScaleMu(0.5)]),
+            vec![Control::SetMu(0.5)]
+        );
+        // a reload set other values meanwhile
+        dashboard.follow(&Control::SetMu(0.2));
+        dashboard.follow(&Control::SetOutputMode(OutputMode::Bypass));
+        assert_eq!(
+            dashboard.handle_keys(vec![KeyAction::ScaleMu(2.0), KeyAction::ToggleBypass]),
+            vec![
+                Control::SetMu(0.4),
+                Control::SetOutputMode(OutputMode::Processing)
+            ]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Measures how a device actually delivers its buffers.
+//!
+//! `probe` opens a short stream on a device, the input discarded and the output silent, and
+//! records when each callback ran and how many frames it carried. The report tells the buffer
+//! sizes the host really uses and how regularly the callbacks come, which matters more for the
+//! latency setting than what the device advertises.
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::SampleFormat;
+use ringbuf::{Producer, RingBuffer};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::devices::Direction;
+use crate::streams::{self, NegotiatedConfig, StreamHealth};
+
+/// Most callbacks recorded by one probe; later ones are only counted
+const MAX_RECORDED_CALLBACKS: usize = 1 << 16;
+
+/// A data callback of the probed stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CallbackTiming {
+    /// When the callback ran, from the start of the stream
+    pub at: Duration,
+    /// Number of frames it carried
+    pub frames: usize,
+}
+
+/// Smallest, largest and average of a measure.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Spread {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl Spread {
+    fn of(values: impl Iterator<Item = f64> + Clone) -> Option<Self> {
+        let count = values.clone().count();
+        if count == 0 {
+            return None;
+        }
+        Some(Spread {
+            min: values.clone().fold(f64::INFINITY, f64::min),
+            max: values.clone().fold(f64::NEG_INFINITY, f64::max),
+            mean: values.sum::<f64>() / count as f64,
+        })
+    }
+}
+
+impl std::fmt::Display for Spread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3} min, {:.3} mean, {:.3} max",
+            self.min, self.mean, self.max
+        )
+    }
+}
+
+/// Statistics of the callbacks of a stream.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CallbackStatistics {
+    pub callbacks: usize,
+    /// Frames per callback
+    pub buffer_frames: Option<Spread>,
+    /// Time between two callbacks (ms)
+    pub period_ms: Option<Spread>,
+    /// Standard deviation of the time between two callbacks (ms)
+    pub jitter_ms: Option<f64>,
+    /// Largest gap between the end of the audio delivered so far and the next callback (ms), i.e.
+    /// how much buffering is needed to never run dry
+    pub max_lateness_ms: Option<f64>,
+}
+
+impl CallbackStatistics {
+    /// Summarises the timings of consecutive callbacks of a stream running at `sample_rate`.
+    pub fn from_timings(timings: &[CallbackTiming], sample_rate: u32) -> Self {
+        let periods = timings
+            .windows(2)
+            .map(|pair| (pair[1].at - pair[0].at).as_secs_f64() * 1_000.0);
+        let period_ms = Spread::of(periods.clone());
+        let jitter_ms = period_ms.map(|period| {
+            let variance = periods
+                .clone()
+                .map(|p| (p - period.mean).powi(2))
+                .sum::<f64>()
+                / (timings.len() - 1) as f64;
+            variance.sqrt()
+        });
+        // audio delivered up to a callback covers this much time after the first callback
+        let mut delivered_ms = 0.0;
+        let mut max_lateness_ms: Option<f64> = None;
+        if let Some(first) = timings.first() {
+            for timing in timings {
+                let at_ms = (timing.at - first.at).as_secs_f64() * 1_000.0;
+                let lateness = at_ms - delivered_ms;
+                max_lateness_ms = Some(max_lateness_ms.map_or(lateness, |max| max.max(lateness)));
+                delivered_ms += timing.frames as f64 * 1_000.0 / sample_rate as f64;
+            }
+        }
+        CallbackStatistics {
+            callbacks: timings.len(),
+            buffer_frames: Spread::of(timings.iter().map(|timing| timing.frames as f64)),
+            period_ms,
+            jitter_ms,
+            max_lateness_ms,
+        }
+    }
+}
+
+/// What `probe` found.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProbeReport {
+    pub device: String,
+    pub direction: Direction,
+    /// The configuration the stream was opened with
+    pub config: String,
+    pub sample_rate: u32,
+    /// How long the stream ran (s)
+    pub duration_s: f64,
+    /// Callbacks which ran after the recording was full
+    pub unrecorded_callbacks: usize,
+    pub errors: usize,
+    pub statistics: CallbackStatistics,
+}
+
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let statistics = &self.statistics;
+        writeln!(f, "{} device \"{}\"", self.direction, self.device)?;
+        writeln!(f, "  configuration: {}", self.config)?;
+        writeln!(
+            f,
+            "  {} callbacks in {:.2} s, {} error(s)",
+            statistics.callbacks + self.unrecorded_callbacks,
+            self.duration_s,
+            self.errors
+        )?;
+        if let Some(frames) = statistics.buffer_frames {
+            writeln!(f, "  buffer size (frames): {}", frames)?;
+        }
+        if let Some(period) = statistics.period_ms {
+            writeln!(f, "  callback period (ms): {}", period)?;
+        }
+        if let Some(jitter) = statistics.jitter_ms {
+            writeln!(f, "  jitter (ms): {:.3}", jitter)?;
+        }
+        if let Some(lateness) = statistics.max_lateness_ms {
+            writeln!(f, "  largest lateness (ms): {:.3}", lateness)?;
+        }
+        Ok(())
+    }
+}
+
+fn record(start: Instant, frames: usize, timings: &mut Producer<CallbackTiming>) {
+    let _ = timings.push(CallbackTiming {
+        at: start.elapsed(),
+        frames,
+    });
+}
+
+fn build_stream(
+    device: &cpal::Device,
+    direction: Direction,
+    negotiated: &NegotiatedConfig,
+    timings: Producer<CallbackTiming>,
+    health: Arc<StreamHealth>,
+) -> Result<cpal::Stream, anyhow::Error> {
+    fn build<T: cpal::Sample>(
+        device: &cpal::Device,
+        direction: Direction,
+        config: &cpal::StreamConfig,
+        mut timings: Producer<CallbackTiming>,
+        health: Arc<StreamHealth>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let channels = config.channels as usize;
+        let start = Instant::now();
+        let callback_health = health.clone();
+        match direction {
+            Direction::Input => device.build_input_stream(
+                config,
+                move |data: &[T], _: &cpal::InputCallbackInfo| {
+                    record(start, data.len() / channels, &mut timings);
+                    callback_health.record_callback();
+                },
+                health.error_callback(),
+            ),
+            Direction::Output => device.build_output_stream(
+                config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    let silence = T::from(&0.0_f32);
+                    data.iter_mut().for_each(|sample| *sample = silence);
+                    record(start, data.len() / channels, &mut timings);
+                    callback_health.record_callback();
+                },
+                health.error_callback(),
+            ),
+        }
+    }
+
+    let config = &negotiated.config;
+    let stream = match negotiated.sample_format {
+        SampleFormat::F32 => build::<f32>(device, direction, config, timings, health),
+        SampleFormat::I16 => build::<i16>(device, direction, config, timings, health),
+        SampleFormat::U16 => build::<u16>(device, direction, config, timings, health),
+    }?;
+    Ok(stream)
+}
+
+/// Runs a stream on `device` for `duration` and reports on its callbacks.
+///
+/// The stream runs at `sample_rate`, or else at the default rate of the device, with the
+/// configuration `streams::negotiate` would choose for the real streams.
+pub fn probe(
+    device: &cpal::Device,
+    direction: Direction,
+    duration: Duration,
+    sample_rate: Option<u32>,
+    buffer_frames: Option<u32>,
+) -> Result<ProbeReport, anyhow::Error> {
+    let sample_rate = match sample_rate {
+        Some(rate) => rate,
+        None => streams::default_sample_rate(device, direction)?,
+    };
+    let negotiated = streams::negotiate(device, direction, sample_rate, buffer_frames)?;
+    let (producer, mut consumer) = RingBuffer::new(MAX_RECORDED_CALLBACKS).split();
+    let health = Arc::new(StreamHealth::default());
+    let stream = build_stream(device, direction, &negotiated, producer, health.clone())?;
+    let start = Instant::now();
+    stream.play()?;
+    std::thread::sleep(duration);
+    drop(stream);
+    let duration_s = start.elapsed().as_secs_f64();
+
+    let mut timings = Vec::with_capacity(consumer.len());
+    while let Ok(timing) = consumer.pop() {
+        timings.push(timing);
+    }
+    Ok(ProbeReport {
+        device: device.name()?,
+        direction,
+        config: negotiated.to_string(),
+        sample_rate,
+        duration_s,
+        unrecorded_callbacks: health.callbacks() - timings.len(),
+        errors: health.errors(),
+        statistics: CallbackStatistics::from_timings(&timings, sample_rate),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(callbacks: &[(u64, usize)]) -> Vec<CallbackTiming> {
+        callbacks
+            .iter()
+            .map(|&(at_ms, frames)| CallbackTiming {
+                at: Duration::from_millis(at_ms),
+                frames,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_callback_statistics() {
+        // buffers of 10 ms at 48 kHz, every 10 ms
+        let regular = CallbackStatistics::from_timings(
+            &timings(&[(0, 480), (10, 480), (20, 480), (30, 480)]),
+            48_000,
+        );
+        assert_eq!(regular.callbacks, 4);
+        assert_eq!(
+            regular.buffer_frames,
+            Some(Spread {
+                min: 480.0,
+                max: 480.0,
+                mean: 480.0
+            })
+        );
+        assert!((regular.period_ms.unwrap().mean - 10.0).abs() < 1e-9);
+        assert!(regular.jitter_ms.unwrap() < 1e-9);
+        assert!(regular.max_lateness_ms.unwrap() < 1e-9);
+
+        // a callback 15 ms late, then two quick ones catching up
+        let irregular = CallbackStatistics::from_timings(
+            &timings(&[(0, 480), (25, 480), (26, 480), (30, 480)]),
+            48_000,
+        );
+        let period = irregular.period_ms.unwrap();
+        assert!((period.min - 1.0).abs() < 1e-9);
+        assert!((period.max - 25.0).abs() < 1e-9);
+        assert!((period.mean - 10.0).abs() < 1e-9);
+        assert!(irregular.jitter_ms.unwrap() > 10.0);
+        assert!((irregular.max_lateness_ms.unwrap() - 15.0).abs() < 1e-9);
+
+        let empty = CallbackStatistics::from_timings(&[], 48_000);
+        assert_eq!(empty.callbacks, 0);
+        assert_eq!(empty.period_ms, None);
+        assert_eq!(empty.jitter_ms, None);
+        assert_eq!(empty.max_lateness_ms, None);
+    }
+}
@@ -1,17 +1,34 @@
 use circular_queue::CircularQueue;
-use rand::thread_rng;
+use log::{error, info, warn};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::Thread;
 
 use crate::filter;
+use crate::kalman::{KalmanCanceller, KalmanParameters};
+use crate::latency::{ProcessingLatency, GROUP_DELAY_HZ};
 use crate::nlmf;
+use crate::nonlinear::{NonlinearPath, Nonlinearity};
+use crate::path_change::{PathChangeDetection, ShadowFilter, Takeover};
+use crate::recorder::RecorderTap;
+use crate::step_size::{StepSize, StepSizeController};
+use crate::subband::{SubbandCanceller, SubbandParameters};
 
+/// Number of channels the stream callbacks expect unless told otherwise
+const DEFAULT_CHANNELS: usize = 2;
+
+/// Receives the samples of an input stream, whatever their format and number of channels, and
+/// pushes them as mono f32 samples into the buffer.
 pub struct Stereo2MonoCapture {
     output_buffer: ringbuf::Producer<f32>,
     parked_thread: Option<Arc<Mutex<Option<Thread>>>>,
+    channels: usize,
 }
 
 impl Stereo2MonoCapture {
@@ -20,6 +37,7 @@ impl Stereo2MonoCapture {
         Stereo2MonoCapture {
             output_buffer: buffer,
             parked_thread: None,
+            channels: DEFAULT_CHANNELS,
         }
     }
 
@@ -30,46 +48,60 @@ impl Stereo2MonoCapture {
         Stereo2MonoCapture {
             output_buffer: buffer,
             parked_thread: Some(parked_thread),
+            channels: DEFAULT_CHANNELS,
         }
     }
 
-    pub fn callback(&mut self, data: &[f32]) {
-        let mut output_fell_behind = false;
-        // iterate over couple of values
-        for (input_l, input_r) in data.iter().step_by(2).zip(data.iter().step_by(2).skip(1)) {
-            let merged_sample = 0.5 * (input_l + input_r);
-            if self.output_buffer.push(merged_sample).is_err() {
-                output_fell_behind = true;
-            }
-        }
-        if output_fell_behind {
-            eprintln!("(capture) output stream fell behind: try increasing latency");
-        }
+    /// Sets the number of interleaved channels of the stream; they are averaged into one.
+    pub fn with_channels(mut self, channels: usize) -> Self {
+        self.set_channels(channels);
+        self
+    }
+
+    pub fn set_channels(&mut self, channels: usize) {
+        assert!(channels > 0, "A stream needs at least one channel");
+        self.channels = channels;
+    }
+
+    /// Whether `callback_and_unpark` should be used rather than `callback`
+    pub fn unparks(&self) -> bool {
+        self.parked_thread.is_some()
     }
 
-    pub fn callback_and_unpark(&mut self, data: &[f32]) {
-        let mut output_fell_behind = false;
-        // iterate over couple of values
-        for (input_l, input_r) in data.iter().step_by(2).zip(data.iter().step_by(2).skip(1)) {
-            let merged_sample = 0.5 * (input_l + input_r);
+    /// Pushes the samples of a buffer; returns the number of samples which did not fit.
+    pub fn callback<T: cpal::Sample>(&mut self, data: &[T]) -> usize {
+        let mut dropped = 0;
+        let scale = 1.0 / self.channels as f32;
+        // iterate over frames, i.e. one value per channel
+        for frame in data.chunks_exact(self.channels) {
+            let merged_sample = scale * frame.iter().map(|s| s.to_f32()).sum::<f32>();
             if self.output_buffer.push(merged_sample).is_err() {
-                output_fell_behind = true;
+                dropped += 1;
             }
         }
-        if output_fell_behind {
-            eprintln!("(capture) output stream fell behind: try increasing latency");
+        if dropped > 0 {
+            warn!("(capture) output stream fell behind: try increasing latency");
         }
+        dropped
+    }
+
+    pub fn callback_and_unpark<T: cpal::Sample>(&mut self, data: &[T]) -> usize {
+        let dropped = self.callback(data);
         let parked_thread_handle_lock = self.parked_thread.as_ref().unwrap().try_lock();
         if let Ok(maybe_parked_thread_handle) = parked_thread_handle_lock {
             if let Some(parked_thread_handle) = maybe_parked_thread_handle.as_ref() {
                 parked_thread_handle.unpark();
             }
         }
+        dropped
     }
 }
 
+/// Pops mono f32 samples from the buffer and writes them to every channel of an output stream,
+/// whatever its format.
 pub struct Mono2StereoOutput {
     input_buffer: ringbuf::Consumer<f32>,
+    channels: usize,
 }
 
 impl Mono2StereoOutput {
@@ -77,39 +109,619 @@ impl Mono2StereoOutput {
     pub fn new(buffer: ringbuf::Consumer<f32>) -> Self {
         Mono2StereoOutput {
             input_buffer: buffer,
+            channels: DEFAULT_CHANNELS,
         }
     }
 
-    pub fn callback(&mut self, data: &mut [f32]) {
-        let mut input_fell_behind = false;
+    /// Sets the number of interleaved channels of the stream; each gets the same signal.
+    pub fn with_channels(mut self, channels: usize) -> Self {
+        self.set_channels(channels);
+        self
+    }
 
-        // variables to replicate input to generate stereo from mono:
-        let mut flag = false;
-        let mut last_sample = 0.0_f32;
+    pub fn set_channels(&mut self, channels: usize) {
+        assert!(channels > 0, "A stream needs at least one channel");
+        self.channels = channels;
+    }
 
-        // iterate over samples to output
-        for sample in data {
-            let input: f32 = if !flag {
-                flag = true;
-                match self.input_buffer.pop() {
-                    Ok(s) => {
-                        last_sample = s;
-                        s
-                    }
-                    Err(_err) => {
-                        input_fell_behind = true;
-                        0.0
-                    }
+    /// Fills a buffer; returns the number of samples missing from the buffer, played as silence.
+    pub fn callback<T: cpal::Sample>(&mut self, data: &mut [T]) -> usize {
+        let mut missing = 0;
+
+        // iterate over frames to output, replicating the input to every channel
+        for frame in data.chunks_mut(self.channels) {
+            let input: f32 = match self.input_buffer.pop() {
+                Ok(s) => s,
+                Err(_err) => {
+                    missing += 1;
+                    0.0
                 }
-            } else {
-                flag = false;
-                last_sample
             };
-            *sample = input;
+            let sample = T::from(&input);
+            for value in frame.iter_mut() {
+                *value = sample;
+            }
+        }
+
+        if missing > 0 {
+            warn!("(output) input stream fell behind: try increasing latency");
+        }
+        missing
+    }
+}
+
+/// Periodic snapshot of the state of the processing thread, sent through
+/// `AECFiltering::debug_channel`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Telemetry {
+    /// Time since the filter was created (s)
+    pub time: f32,
+    /// Microphone buffer usage level (fraction of its capacity)
+    pub mic_level: f32,
+    /// Reference buffer usage level (fraction of its capacity)
+    pub capture_level: f32,
+    /// Output buffer usage level (fraction of its capacity)
+    pub output_level: f32,
+    /// Largest novelty reported by the adaptive filter since the last message
+    pub novelty: f32,
+    /// Echo return loss enhancement (dB) since the last message
+    pub erle_db: f32,
+    /// Estimated bulk delay of the echo path (samples)
+    pub delay_samples: usize,
+    /// Step size of the adaptive filter, as set by the step size control; NaN for the `kalman`
+    /// algorithm, whose Kalman gain takes the place of the step size
+    pub mu: f32,
+    /// Changes of the echo path detected since the filter was created
+    pub path_changes: usize,
+    /// Samples waiting in the microphone and output buffers, i.e. the buffered part of the
+    /// latency; see the `latency` module
+    pub buffered_samples: usize,
+}
+
+/// The signals seen by the processing thread for a single sample; pushed into
+/// `AECFiltering::signal_tap` for visualisation and recorded by the `recorder` module.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SignalFrame {
+    /// Microphone sample
+    pub mic: f32,
+    /// Reference (capture) sample
+    pub reference: f32,
+    /// What the adaptive filter estimates the echo in the microphone sample to be
+    pub echo_estimate: f32,
+    /// Echo cancelled output sample
+    pub output: f32,
+}
+
+/// Maximum number of control messages waiting to be applied by the processing thread
+const CONTROL_QUEUE_CAPACITY: usize = 64;
+
+/// Default cutoff of the high pass filter applied to the output (Hz)
+const DEFAULT_HIGHPASS_HZ: f32 = 300.0;
+/// Default cutoff of the low pass filter applied to the output (Hz)
+const DEFAULT_LOWPASS_HZ: f32 = 3400.0;
+
+/// Length of the crossfade between two output modes (ms)
+const CROSSFADE_MS: f32 = 20.0;
+
+/// What the processing thread sends to the output; the filter keeps adapting in every mode, so
+/// that the modes can be compared while it runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    /// The microphone signal with the echo removed, through the post-processing stages
+    #[default]
+    Processing,
+    /// The microphone signal untouched, with the same latency as the processing
+    Bypass,
+    /// What the adaptive filter estimates the echo in the microphone signal to be
+    EchoEstimate,
+    /// The reference signal the echo is estimated from
+    ReferencePassthrough,
+}
+
+impl OutputMode {
+    pub const ALL: [OutputMode; 4] = [
+        OutputMode::Processing,
+        OutputMode::Bypass,
+        OutputMode::EchoEstimate,
+        OutputMode::ReferencePassthrough,
+    ];
+
+    /// The mode after this one in `ALL`, wrapping around
+    pub fn next(self) -> OutputMode {
+        let index = OutputMode::ALL
+            .iter()
+            .position(|&mode| mode == self)
+            .unwrap();
+        OutputMode::ALL[(index + 1) % OutputMode::ALL.len()]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputMode::Processing => "processing",
+            OutputMode::Bypass => "bypass",
+            OutputMode::EchoEstimate => "echo-estimate",
+            OutputMode::ReferencePassthrough => "reference-passthrough",
+        }
+    }
+
+    /// Picks the output sample of this mode among the signals of the processing thread.
+    fn select(self, mic: f32, reference: f32, echo_estimate: f32, processed: f32) -> f32 {
+        match self {
+            OutputMode::Processing => processed,
+            OutputMode::Bypass => mic,
+            OutputMode::EchoEstimate => echo_estimate,
+            OutputMode::ReferencePassthrough => reference,
+        }
+    }
+}
+
+impl std::fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutputMode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == s.to_lowercase())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown output mode \"{}\"; use one of {}",
+                    s,
+                    OutputMode::ALL
+                        .iter()
+                        .map(|mode| mode.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// The adaptive filters available to cancel the echo.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Normalised least mean fourth
+    #[default]
+    Nlmf,
+    /// Normalised least mean squares in the bands of a filter bank; see the `subband` module
+    Subband,
+    /// Frequency domain adaptive Kalman filter, without a step size; see the `kalman` module
+    Kalman,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 3] = [Algorithm::Nlmf, Algorithm::Subband, Algorithm::Kalman];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Nlmf => "nlmf",
+            Algorithm::Subband => "subband",
+            Algorithm::Kalman => "kalman",
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name() == s.to_lowercase())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown algorithm \"{}\"; use one of {}",
+                    s,
+                    Algorithm::ALL
+                        .iter()
+                        .map(|algorithm| algorithm.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Blends the output of the previous mode into that of the current one after a switch, so that
+/// switching does not click.
+#[derive(Clone, Debug)]
+struct Crossfade {
+    /// Length of a crossfade in samples
+    length: usize,
+    /// The mode faded out
+    from: OutputMode,
+    /// Samples left until the previous mode is silent
+    remaining: usize,
+}
+
+impl Crossfade {
+    fn new(length: usize) -> Self {
+        Crossfade {
+            length,
+            from: OutputMode::default(),
+            remaining: 0,
+        }
+    }
+
+    /// Starts fading out `from`. A switch during a crossfade starts a new one from the mode
+    /// which was fading in.
+    fn start(&mut self, from: OutputMode) {
+        self.from = from;
+        self.remaining = self.length;
+    }
+
+    /// Mixes the output of the previous mode into that of the current one, `select` giving the
+    /// output of a mode, and moves on by a sample.
+    fn mix(&mut self, current: f32, select: impl Fn(OutputMode) -> f32) -> f32 {
+        if self.remaining == 0 {
+            return current;
+        }
+        let gain = self.remaining as f32 / (self.length + 1) as f32;
+        self.remaining -= 1;
+        gain * select(self.from) + (1.0 - gain) * current
+    }
+}
+
+/// The post-processing stages applied to the output of the echo canceller.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    HighPass,
+    LowPass,
+}
+
+/// Messages to change the behaviour of a running `AECFiltering`; they are applied by the
+/// processing thread between two blocks of samples.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Control {
+    /// Set the step size of the adaptive filter; the largest one when it varies
+    SetMu(f32),
+    /// Set the regularisation of the normalisation of the step size
+    SetEps(f32),
+    /// Set the novelty above which the weights are not adapted
+    SetNoveltyThreshold(f32),
+    /// Set all the adaptive filter weights to zero
+    ResetWeights,
+    /// Choose what is sent to the output; the switch is crossfaded
+    SetOutputMode(OutputMode),
+    /// Switch a post-processing stage on or off
+    EnableStage(Stage, bool),
+    /// Copy the current weights for `RunningAECFiltering::weights_snapshot`; the subband canceller
+    /// has none to copy
+    SnapshotWeights,
+    /// Drop the buffered microphone and reference samples, e.g. after a stream was rebuilt, so
+    /// that both line up again
+    Resync,
+}
+
+/// The adaptive filter estimating the echo, as chosen by `AECParameters::algorithm`.
+enum Canceller {
+    Nlmf(nlmf::NLMF<f32>),
+    Subband(Box<SubbandCanceller>),
+    Kalman(Box<KalmanCanceller>),
+}
+
+impl Canceller {
+    /// Takes in a reference and a microphone sample, and gives back the echo estimate and the
+    /// novelty, `delay` samples late.
+    fn adapt(&mut self, reference: f32, mic: f32, novelty_threshold: f32) -> (f32, f32) {
+        match self {
+            Canceller::Nlmf(filter) => filter.adapt(reference, mic, novelty_threshold),
+            Canceller::Subband(filter) => filter.adapt(reference, mic, novelty_threshold),
+            Canceller::Kalman(filter) => filter.adapt(reference, mic),
+        }
+    }
+
+    /// Number of samples by which the echo estimate lags the input.
+    fn delay(&self) -> usize {
+        match self {
+            Canceller::Nlmf(_) => 0,
+            Canceller::Subband(filter) => filter.delay(),
+            Canceller::Kalman(filter) => filter.delay(),
+        }
+    }
+
+    /// The weights of the time domain filter; none for the subband canceller, whose weights are
+    /// complex and per band.
+    fn weights(&self) -> &[f32] {
+        match self {
+            Canceller::Nlmf(filter) => &filter.weights,
+            Canceller::Subband(_) => &[],
+            Canceller::Kalman(filter) => filter.weights(),
+        }
+    }
+
+    fn set_mu(&mut self, mu: f32) {
+        match self {
+            Canceller::Nlmf(filter) => filter.set_mu(mu),
+            Canceller::Subband(filter) => filter.set_mu(mu),
+            // the Kalman gain takes the place of the step size
+            Canceller::Kalman(_) => (),
+        }
+    }
+
+    fn set_eps(&mut self, eps: f32) {
+        match self {
+            Canceller::Nlmf(filter) => filter.set_eps(eps),
+            Canceller::Subband(filter) => filter.set_eps(eps),
+            Canceller::Kalman(_) => (),
+        }
+    }
+
+    fn reset_weights(&mut self) {
+        match self {
+            Canceller::Nlmf(filter) => filter.reset_weights(),
+            Canceller::Subband(filter) => filter.reset_weights(),
+            Canceller::Kalman(filter) => filter.reset_weights(),
+        }
+    }
+
+    fn bulk_delay(&self) -> usize {
+        match self {
+            Canceller::Nlmf(filter) => filter.bulk_delay(),
+            Canceller::Subband(filter) => filter.bulk_delay(),
+            Canceller::Kalman(filter) => filter.bulk_delay(),
+        }
+    }
+}
+
+/// A post-processing filter which can be switched on and off while running.
+struct PostFilter {
+    filter: filter::Filter,
+    cutoff_hz: f32,
+    enabled: bool,
+}
+
+impl PostFilter {
+    fn new(
+        mode: fn(f32) -> filter::FilterMode,
+        cutoff_hz: Option<f32>,
+        default_hz: f32,
+        sample_rate: f32,
+    ) -> Self {
+        let cutoff = cutoff_hz.unwrap_or(default_hz);
+        PostFilter {
+            filter: filter::Filter::with_sample_rate(mode(cutoff), sample_rate),
+            cutoff_hz: cutoff,
+            enabled: cutoff_hz.is_some(),
+        }
+    }
+
+    fn tick(&mut self, x: f32) -> f32 {
+        if self.enabled {
+            self.filter.tick(x)
+        } else {
+            x
         }
+    }
+
+    /// Group delay (samples) at `frequency`; none when disabled
+    fn group_delay(&self, frequency: f32) -> f32 {
+        if self.enabled {
+            self.filter.group_delay(frequency)
+        } else {
+            0.0
+        }
+    }
+
+    /// The cutoff as found in `AECParameters`
+    fn cutoff(&self) -> Option<f32> {
+        if self.enabled {
+            Some(self.cutoff_hz)
+        } else {
+            None
+        }
+    }
+}
+
+/// How the weights of the adaptive filter start out.
+///
+/// Serialized as the name of the variant when it has no value, and as a table with a single key,
+/// e.g. `{ seeded-random = 42 }`, when it has one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum WeightInit {
+    /// Normally distributed values from an unseeded generator; differs from run to run
+    #[default]
+    Random,
+    /// All zeros
+    Zeros,
+    /// Normally distributed values from a generator seeded with this value; the same seed gives
+    /// the same weights with the same build of raec
+    SeededRandom(u64),
+    /// Read from a `.npy` file of `f32`, e.g. the initial weights of a recording
+    File(PathBuf),
+    /// These very values
+    Vector(Vec<f32>),
+}
+
+impl WeightInit {
+    /// The initial weights of a filter with `taps` taps.
+    pub fn weights(&self, taps: usize) -> Result<Vec<f32>, anyhow::Error> {
+        let weights = match self {
+            WeightInit::Random => normal_weights(&mut thread_rng(), taps),
+            WeightInit::Zeros => vec![0.0; taps],
+            WeightInit::SeededRandom(seed) => {
+                normal_weights(&mut StdRng::seed_from_u64(*seed), taps)
+            }
+            WeightInit::File(path) => {
+                let bytes = std::fs::read(path).map_err(|e| {
+                    anyhow::anyhow!("Could not read weights from {}: {}", path.display(), e)
+                })?;
+                npy::NpyData::<f32>::from_bytes(&bytes)
+                    .map_err(|e| anyhow::anyhow!("Invalid weights file {}: {}", path.display(), e))?
+                    .to_vec()
+            }
+            WeightInit::Vector(weights) => weights.clone(),
+        };
+        if weights.len() != taps {
+            return Err(anyhow::anyhow!(
+                "{} initial weights given for a filter of {} taps",
+                weights.len(),
+                taps
+            ));
+        }
+        Ok(weights)
+    }
+}
+
+// The serde implementations are written by hand, as toml neither serializes enum variants with a
+// value nor deserializes them from a `toml::Value`
+impl Serialize for WeightInit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        fn table<S: serde::Serializer, T: Serialize>(
+            serializer: S,
+            key: &str,
+            value: &T,
+        ) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeMap;
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(key, value)?;
+            map.end()
+        }
+
+        match self {
+            WeightInit::Random => serializer.serialize_str("random"),
+            WeightInit::Zeros => serializer.serialize_str("zeros"),
+            WeightInit::SeededRandom(seed) => table(serializer, "seeded-random", seed),
+            WeightInit::File(path) => table(serializer, "file", path),
+            WeightInit::Vector(weights) => table(serializer, "vector", weights),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WeightInit {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = WeightInit;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("\"random\", \"zeros\" or a table with one of the keys seeded-random, file or vector")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<WeightInit, E> {
+                match name {
+                    "random" => Ok(WeightInit::Random),
+                    "zeros" => Ok(WeightInit::Zeros),
+                    _ => Err(E::unknown_variant(name, &["random", "zeros"])),
+                }
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<WeightInit, A::Error> {
+                use serde::de::Error;
+                let key: String = map
+                    .next_key()?
+                    .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                let init = match key.as_str() {
+                    "seeded-random" => WeightInit::SeededRandom(map.next_value()?),
+                    "file" => WeightInit::File(map.next_value()?),
+                    "vector" => WeightInit::Vector(map.next_value()?),
+                    _ => {
+                        return Err(A::Error::unknown_variant(
+                            &key,
+                            &["seeded-random", "file", "vector"],
+                        ))
+                    }
+                };
+                if map.next_key::<String>()?.is_some() {
+                    return Err(A::Error::invalid_length(2, &self));
+                }
+                Ok(init)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+fn normal_weights(rng: &mut impl Rng, taps: usize) -> Vec<f32> {
+    let normal = Normal::new(0.0, 0.5).unwrap();
+    normal.sample_iter(rng).take(taps).collect()
+}
+
+/// Tunable parameters of an `AECFiltering`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AECParameters {
+    /// Sample rate of the processed signals (Hz)
+    pub sample_rate: f32,
+    /// The adaptive filter cancelling the echo
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// Number of taps of the adaptive filter; must be a multiple of 8
+    pub taps: usize,
+    /// Step size of the adaptive filter
+    pub mu: f32,
+    /// Regularisation of the normalisation of the step size
+    pub eps: f32,
+    /// The weights are only adapted while the novelty is below this threshold
+    pub novelty_threshold: f32,
+    /// Cutoff of the high pass filter applied to the output (Hz); `None` disables it
+    pub highpass_hz: Option<f32>,
+    /// Cutoff of the low pass filter applied to the output (Hz); `None` disables it
+    pub lowpass_hz: Option<f32>,
+    /// Number of processed samples between two messages on the debug channel
+    pub telemetry_interval: usize,
+    /// Number of processed samples between two snapshots of the filter weights
+    pub weights_snapshot_interval: usize,
+    /// What is sent to the output
+    pub output_mode: OutputMode,
+    /// The nonlinear part of the model of the echo path; linear by default, and in recordings
+    /// made before it existed
+    #[serde(default)]
+    pub nonlinearity: Nonlinearity,
+    /// How the step size follows the residual; `mu` is the largest step size when it varies
+    #[serde(default)]
+    pub step_size: StepSize,
+    /// Detection of changes of the echo path with a shadow filter, for the `nlmf` algorithm
+    #[serde(default)]
+    pub path_change: PathChangeDetection,
+    /// The filter bank of the `subband` algorithm
+    #[serde(default)]
+    pub subband: SubbandParameters,
+    /// The blocks and echo path model of the `kalman` algorithm
+    #[serde(default)]
+    pub kalman: KalmanParameters,
+}
 
-        if input_fell_behind {
-            eprintln!("(output) input stream fell behind: try increasing latency");
+impl Default for AECParameters {
+    fn default() -> Self {
+        AECParameters {
+            sample_rate: 48_000.0,
+            algorithm: Algorithm::default(),
+            taps: nlmf::N_TAPS,
+            mu: 1.0,
+            eps: 1.0,
+            novelty_threshold: 0.0025,
+            highpass_hz: Some(DEFAULT_HIGHPASS_HZ),
+            lowpass_hz: Some(DEFAULT_LOWPASS_HZ),
+            telemetry_interval: 1_000,
+            weights_snapshot_interval: 4_800,
+            output_mode: OutputMode::Processing,
+            nonlinearity: Nonlinearity::default(),
+            step_size: StepSize::default(),
+            path_change: PathChangeDetection::default(),
+            subband: SubbandParameters::default(),
+            kalman: KalmanParameters::default(),
         }
     }
 }
@@ -123,94 +735,363 @@ pub struct AECFiltering {
     capture_buffer: ringbuf::Consumer<f32>,
     /// Outgoing buffer for output
     output_buffer: ringbuf::Producer<f32>,
-    /// The adaptive FIR filter instance
-    nlmf_filter: nlmf::NLMF<f32>,
+    /// The adaptive filter instance
+    canceller: Canceller,
+    /// The microphone and reference samples, delayed as much as the echo estimate
+    aligned: CircularQueue<(f32, f32)>,
+    /// The distortion of the reference by the loudspeaker, before the FIR filter
+    nonlinear_path: NonlinearPath,
+    /// Sets the step size of the adaptive filter, up to `AECParameters::mu`
+    step_size: StepSizeController,
+    /// Tells when the echo path changed, next to the `nlmf` canceller
+    shadow: Option<ShadowFilter>,
     /// The running convolution to input into the FIR filter
     filter_buffer: CircularQueue<f32>,
+    /// The current parameters; updated by control messages
+    parameters: AECParameters,
     /// A low pass filter
-    lowpass_filter: filter::Filter,
+    lowpass_filter: PostFilter,
     /// A high pass filter
-    highpass_fiter: filter::Filter,
+    highpass_fiter: PostFilter,
+    /// Smooths the switches of `AECParameters::output_mode`
+    crossfade: Crossfade,
     /// Control signal to kill the processing thread
     signal_channel: Option<mpsc::Receiver<()>>,
-    /// Debug channel to communicate out the filling state of the buffers
-    /// Message is (time (s), microphone buffer usage level (%), reference buffer usage level (%),
-    /// output buffer usage level (%)): (f32, f32, f32, f32)
-    pub debug_channel: Option<mpsc::Sender<(f32, f32, f32, f32)>>,
+    /// Control messages from `RunningAECFiltering`; a lock-free queue so that sending a message
+    /// never blocks the processing thread
+    control_channel: Option<ringbuf::Consumer<Control>>,
+    /// Snapshots of the weights requested with `Control::SnapshotWeights`
+    snapshot_channel: Option<ringbuf::Producer<f32>>,
+    /// Debug channel to communicate out the filling state of the buffers and the state of the
+    /// adaptive filter; a message is sent every `AECParameters::telemetry_interval` samples.
+    pub debug_channel: Option<mpsc::Sender<Telemetry>>,
+    /// Lock-free tap receiving every processed sample. Frames are dropped when the consumer does
+    /// not keep up, so a slow reader never stalls the processing thread.
+    pub signal_tap: Option<ringbuf::Producer<SignalFrame>>,
+    /// Lock-free tap receiving snapshots of the adaptive filter weights, `AECParameters::taps`
+    /// values at a time, every `AECParameters::weights_snapshot_interval` samples. A snapshot
+    /// which does not fit entirely in the buffer is skipped; the subband canceller, whose weights
+    /// are per band, never sends any.
+    pub weights_tap: Option<ringbuf::Producer<f32>>,
+    /// Lock-free tap receiving every processed sample and every applied control message, for the
+    /// `recorder` module
+    pub recorder_tap: Option<RecorderTap>,
+    /// Levels of the buffers and group delay, for the `latency` module
+    latency: Arc<ProcessingLatency>,
+    /// Samples processed since the last snapshot of the weights
+    samples_since_snapshot: usize,
     /// Used for debugging with debug channel
     start_time: std::time::Instant,
+    /// Energy of the microphone signal since the last telemetry message
+    mic_energy: f32,
+    /// Energy of the residual (microphone minus echo estimate) since the last telemetry message
+    residual_energy: f32,
+    /// Largest novelty since the last telemetry message
+    max_novelty: f32,
 }
 
 /// When the thread to run the filter starts the AECFiltering struct is consumed.
 /// This struct contains the thread handle and kill signal channel to be able to stop the filter.
 pub struct RunningAECFiltering {
     kill_signal_sender: mpsc::Sender<()>,
+    control_sender: ringbuf::Producer<Control>,
+    snapshot_receiver: ringbuf::Consumer<f32>,
+    n_taps: usize,
     thread_join_handle: std::thread::JoinHandle<AECFiltering>,
 }
 
 impl RunningAECFiltering {
     fn new(
         kill_signal_sender: mpsc::Sender<()>,
+        control_sender: ringbuf::Producer<Control>,
+        snapshot_receiver: ringbuf::Consumer<f32>,
+        n_taps: usize,
         thread_join_handle: std::thread::JoinHandle<AECFiltering>,
     ) -> Self {
         let _thread = thread_join_handle.thread();
         RunningAECFiltering {
             kill_signal_sender,
+            control_sender,
+            snapshot_receiver,
+            n_taps,
             thread_join_handle,
         }
     }
 
+    /// Queues a control message for the processing thread, which applies it when it next wakes
+    /// up. The message is handed back if too many messages are already waiting.
+    pub fn control(&mut self, message: Control) -> Result<(), Control> {
+        self.control_sender
+            .push(message)
+            .map_err(|ringbuf::PushError::Full(message)| message)
+    }
+
+    /// Changes the step size of the adaptive filter
+    pub fn set_mu(&mut self, mu: f32) -> Result<(), Control> {
+        self.control(Control::SetMu(mu))
+    }
+
+    /// Changes the regularisation of the normalisation of the step size
+    pub fn set_eps(&mut self, eps: f32) -> Result<(), Control> {
+        self.control(Control::SetEps(eps))
+    }
+
+    /// Changes the novelty above which the weights are not adapted
+    pub fn set_novelty_threshold(&mut self, threshold: f32) -> Result<(), Control> {
+        self.control(Control::SetNoveltyThreshold(threshold))
+    }
+
+    /// Sets all the adaptive filter weights to zero
+    pub fn reset_weights(&mut self) -> Result<(), Control> {
+        self.control(Control::ResetWeights)
+    }
+
+    /// Chooses what is sent to the output; see `OutputMode`
+    pub fn set_output_mode(&mut self, mode: OutputMode) -> Result<(), Control> {
+        self.control(Control::SetOutputMode(mode))
+    }
+
+    /// Switches a post-processing stage on or off
+    pub fn enable_stage(&mut self, stage: Stage, enabled: bool) -> Result<(), Control> {
+        self.control(Control::EnableStage(stage, enabled))
+    }
+
+    /// Realigns the microphone and reference signals after an interruption of their streams
+    pub fn resync(&mut self) -> Result<(), Control> {
+        self.control(Control::Resync)
+    }
+
+    /// Asks the processing thread for a copy of the weights; see `weights_snapshot`
+    pub fn request_weights_snapshot(&mut self) -> Result<(), Control> {
+        self.control(Control::SnapshotWeights)
+    }
+
+    /// The most recent snapshot of the weights delivered since the last call, if any; never any
+    /// for the subband canceller
+    pub fn weights_snapshot(&mut self) -> Option<Vec<f32>> {
+        let mut snapshot = None;
+        while self.snapshot_receiver.len() >= self.n_taps {
+            let mut weights = vec![0.0; self.n_taps];
+            let _ = self.snapshot_receiver.pop_slice(&mut weights);
+            snapshot = Some(weights);
+        }
+        snapshot
+    }
+
     /// kill the thread and consume the struct in the process
     pub fn kill(self) -> AECFiltering {
         self.kill_signal_sender.send(()).unwrap();
+        // the thread may be parked waiting for samples which will never come
+        self.thread_join_handle.thread().unpark();
         self.thread_join_handle.join().unwrap() // may panic if the thread panicked
     }
 }
 
 impl AECFiltering {
-    // hard-coded constructor; in the future parameterize this
+    /// Creates a filter with the default parameters, random initial weights and the given step
+    /// size.
     pub fn new(
         mic_buffer: ringbuf::Consumer<f32>,
         capture_buffer: ringbuf::Consumer<f32>,
         output_buffer: ringbuf::Producer<f32>,
         mu: f32,
     ) -> Self {
-        let mut weights: [f32; nlmf::N_TAPS] = [0.0; nlmf::N_TAPS];
-        {
-            let mut rng = thread_rng();
-            let normal = Normal::new(0.0, 0.5).unwrap();
-            for (w, x) in weights
-                .iter_mut()
-                .zip(normal.sample_iter(&mut rng).take(nlmf::N_TAPS))
-            {
-                *w = x;
+        let parameters = AECParameters {
+            mu,
+            ..AECParameters::default()
+        };
+        Self::with_parameters(
+            mic_buffer,
+            capture_buffer,
+            output_buffer,
+            &parameters,
+            &WeightInit::Random,
+        )
+        .expect("random weights always have one per tap")
+    }
+
+    /// Creates a filter with the given parameters, its weights starting out as `initial_weights`
+    /// says; fails when they cannot be read or do not have one per tap.
+    pub fn with_parameters(
+        mic_buffer: ringbuf::Consumer<f32>,
+        capture_buffer: ringbuf::Consumer<f32>,
+        output_buffer: ringbuf::Producer<f32>,
+        parameters: &AECParameters,
+        initial_weights: &WeightInit,
+    ) -> Result<Self, anyhow::Error> {
+        let weights = initial_weights.weights(parameters.taps)?;
+        Ok(Self::with_weights(
+            mic_buffer,
+            capture_buffer,
+            output_buffer,
+            parameters,
+            weights,
+        ))
+    }
+
+    /// Creates a filter with the given parameters and initial weights, `parameters.taps` of them;
+    /// the subband canceller always starts out from zero weights.
+    pub fn with_weights(
+        mic_buffer: ringbuf::Consumer<f32>,
+        capture_buffer: ringbuf::Consumer<f32>,
+        output_buffer: ringbuf::Producer<f32>,
+        parameters: &AECParameters,
+        weights: Vec<f32>,
+    ) -> Self {
+        let shadow = match parameters.algorithm {
+            Algorithm::Nlmf if parameters.path_change.enabled => Some(ShadowFilter::new(
+                &parameters.path_change,
+                parameters.eps,
+                weights.clone(),
+                parameters.sample_rate,
+            )),
+            _ => None,
+        };
+        let canceller = match parameters.algorithm {
+            Algorithm::Nlmf => Canceller::Nlmf(nlmf::NLMF::new(
+                parameters.taps,
+                parameters.mu,
+                parameters.eps,
+                weights,
+            )),
+            Algorithm::Subband => Canceller::Subband(Box::new(SubbandCanceller::new(
+                &parameters.subband,
+                parameters.taps,
+                parameters.mu,
+                parameters.eps,
+            ))),
+            Algorithm::Kalman => {
+                Canceller::Kalman(Box::new(KalmanCanceller::new(&parameters.kalman, weights)))
             }
         };
-        let nlmf_filter: nlmf::NLMF<f32> = nlmf::NLMF::new(nlmf::N_TAPS, mu, 1.0, weights);
-        let lowpass_filter = filter::Filter::new(filter::LowPass(3400.0));
-        let highpass_fiter = filter::Filter::new(filter::HighPass(300.0));
-        let mut filter_buffer = CircularQueue::with_capacity(nlmf::N_TAPS);
-        for _ in 0..nlmf::N_TAPS {
+        let mut aligned = CircularQueue::with_capacity(canceller.delay() + 1);
+        for _ in 0..canceller.delay() + 1 {
+            aligned.push((0.0, 0.0));
+        }
+        let lowpass_filter = PostFilter::new(
+            filter::LowPass,
+            parameters.lowpass_hz,
+            DEFAULT_LOWPASS_HZ,
+            parameters.sample_rate,
+        );
+        let highpass_fiter = PostFilter::new(
+            filter::HighPass,
+            parameters.highpass_hz,
+            DEFAULT_HIGHPASS_HZ,
+            parameters.sample_rate,
+        );
+        let mut filter_buffer = CircularQueue::with_capacity(parameters.taps);
+        for _ in 0..parameters.taps {
             filter_buffer.push(0.0);
         }
-        AECFiltering {
+        let filtering = AECFiltering {
             mic_buffer,
             capture_buffer,
             output_buffer,
-            nlmf_filter,
+            canceller,
+            aligned,
+            nonlinear_path: NonlinearPath::new(&parameters.nonlinearity, parameters.taps),
+            step_size: StepSizeController::new(&parameters.step_size, parameters.sample_rate),
+            shadow,
             filter_buffer,
+            parameters: parameters.clone(),
             lowpass_filter,
             highpass_fiter,
+            crossfade: Crossfade::new((CROSSFADE_MS / 1_000.0 * parameters.sample_rate) as usize),
             signal_channel: None,
+            control_channel: None,
+            snapshot_channel: None,
             debug_channel: None,
+            signal_tap: None,
+            weights_tap: None,
+            recorder_tap: None,
+            latency: Arc::new(ProcessingLatency::default()),
+            samples_since_snapshot: 0,
             start_time: std::time::Instant::now(),
-        }
+            mic_energy: 0.0,
+            residual_energy: 0.0,
+            max_novelty: 0.0,
+        };
+        filtering.record_group_delay();
+        filtering
+    }
+
+    /// Creates a filter which is not connected to any stream, to process recorded or generated
+    /// signals with `process_offline`.
+    pub fn offline(parameters: &AECParameters, weights: Vec<f32>) -> Self {
+        let (_, mic_buffer) = ringbuf::RingBuffer::new(1).split();
+        let (_, capture_buffer) = ringbuf::RingBuffer::new(1).split();
+        let (output_buffer, _) = ringbuf::RingBuffer::new(1).split();
+        Self::with_weights(
+            mic_buffer,
+            capture_buffer,
+            output_buffer,
+            parameters,
+            weights,
+        )
+    }
+
+    /// The current parameters of the filter, including the changes made by control messages.
+    pub fn parameters(&self) -> &AECParameters {
+        &self.parameters
+    }
+
+    /// The current weights of the adaptive filter; none for the subband canceller.
+    pub fn weights(&self) -> &[f32] {
+        self.canceller.weights()
+    }
+
+    /// Number of samples by which the output lags the input, before the post-processing.
+    pub fn delay(&self) -> usize {
+        self.canceller.delay()
+    }
+
+    /// The nonlinear part of the model of the echo path.
+    pub fn nonlinear_path(&self) -> &NonlinearPath {
+        &self.nonlinear_path
+    }
+
+    /// The step size control of the adaptive filter; its `mu` is the current step size.
+    pub fn step_size(&self) -> &StepSizeController {
+        &self.step_size
+    }
+
+    /// Number of changes of the echo path detected so far, recoveries from double talk left out;
+    /// always zero without the detection.
+    pub fn path_changes(&self) -> usize {
+        self.shadow.as_ref().map_or(0, |shadow| shadow.changes())
+    }
+
+    /// Where the processing thread publishes the levels of its buffers and its group delay.
+    pub fn latency(&self) -> Arc<ProcessingLatency> {
+        self.latency.clone()
+    }
+
+    /// Publishes the group delay of what is sent to the output; everything lags as much as the
+    /// echo estimate, and only the processed signal goes through the post-processing stages.
+    fn record_group_delay(&self) {
+        let delay = match self.parameters.output_mode {
+            OutputMode::Processing => {
+                self.highpass_fiter.group_delay(GROUP_DELAY_HZ)
+                    + self.lowpass_filter.group_delay(GROUP_DELAY_HZ)
+            }
+            _ => 0.0,
+        };
+        self.latency
+            .record_group_delay(self.canceller.delay() as f32 + delay);
     }
 
     /// Starts the processing thread; will block until the thread starts and reports back its handle for unparking.
     pub fn start_thread(mut self) -> (RunningAECFiltering, Thread) {
         let (signal_sender, signal_receiver) = mpsc::channel();
         self.signal_channel = Some(signal_receiver);
+        let (control_sender, control_receiver) =
+            ringbuf::RingBuffer::new(CONTROL_QUEUE_CAPACITY).split();
+        self.control_channel = Some(control_receiver);
+        let n_taps = self.parameters.taps;
+        // room for a snapshot being read while the next one is written
+        let (snapshot_sender, snapshot_receiver) = ringbuf::RingBuffer::new(2 * n_taps).split();
+        self.snapshot_channel = Some(snapshot_sender);
         let thread_handle = Arc::new(Mutex::new(None));
         let thread_handle_clone = thread_handle.clone();
         let thread_joinhandle = std::thread::spawn(move || {
@@ -226,28 +1107,198 @@ impl AECFiltering {
         }
         let the_handle = thread_handle.lock().unwrap().take().unwrap();
         (
-            RunningAECFiltering::new(signal_sender, thread_joinhandle),
+            RunningAECFiltering::new(
+                signal_sender,
+                control_sender,
+                snapshot_receiver,
+                n_taps,
+                thread_joinhandle,
+            ),
             the_handle,
         )
     }
 
+    /// Applies a control message at once; the running thread applies those it receives between
+    /// two blocks of samples, offline processing between any two samples.
+    pub fn apply_control(&mut self, message: Control) {
+        if let Some(tap) = self.recorder_tap.as_mut() {
+            tap.record_control(message);
+        }
+        match message {
+            Control::SetMu(mu) => {
+                self.canceller.set_mu(mu);
+                self.parameters.mu = mu;
+            }
+            Control::SetEps(eps) => {
+                self.canceller.set_eps(eps);
+                if let Some(shadow) = self.shadow.as_mut() {
+                    shadow.set_eps(eps);
+                }
+                self.parameters.eps = eps;
+            }
+            Control::SetNoveltyThreshold(threshold) => {
+                self.parameters.novelty_threshold = threshold;
+            }
+            Control::ResetWeights => {
+                self.canceller.reset_weights();
+                self.nonlinear_path.reset();
+                self.step_size.reset();
+                if let Some(shadow) = self.shadow.as_mut() {
+                    shadow.reset_weights();
+                }
+            }
+            Control::SetOutputMode(mode) => {
+                if mode != self.parameters.output_mode {
+                    self.crossfade.start(self.parameters.output_mode);
+                    self.parameters.output_mode = mode;
+                    self.record_group_delay();
+                }
+            }
+            Control::EnableStage(Stage::HighPass, enabled) => {
+                self.highpass_fiter.enabled = enabled;
+                self.parameters.highpass_hz = self.highpass_fiter.cutoff();
+                self.record_group_delay();
+            }
+            Control::EnableStage(Stage::LowPass, enabled) => {
+                self.lowpass_filter.enabled = enabled;
+                self.parameters.lowpass_hz = self.lowpass_filter.cutoff();
+                self.record_group_delay();
+            }
+            Control::Resync => {
+                for _ in 0..self.mic_buffer.len() {
+                    let _ = self.mic_buffer.pop();
+                }
+                for _ in 0..self.capture_buffer.len() {
+                    let _ = self.capture_buffer.pop();
+                }
+            }
+            Control::SnapshotWeights => {
+                if let Some(channel) = self.snapshot_channel.as_mut() {
+                    // a snapshot which does not fit is dropped; the reader is not keeping up
+                    if channel.remaining() >= self.canceller.weights().len() {
+                        let _ = channel.push_slice(self.canceller.weights());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs the canceller on a microphone sample and the matching reference sample. This is all
+    /// the processing of a sample, shared by the running thread and `process_offline` so that
+    /// both give the same output for the same input.
+    fn process_sample(&mut self, mic_sample: f32, capture_sample: f32) -> SignalFrame {
+        self.filter_buffer.push(capture_sample);
+        let reference = self.nonlinear_path.shape(capture_sample);
+        let (aec_output, novelty) =
+            self.canceller
+                .adapt(reference, mic_sample, self.parameters.novelty_threshold);
+        let input = (mic_sample, capture_sample);
+        self.aligned.push(input);
+        // SAFETY: the queue is full from the start
+        let (mic_sample, capture_sample) = *self.aligned.asc_iter().next().unwrap();
+        let residual = mic_sample - aec_output;
+        // the nonlinearity is held still whenever the weights are, and only the time domain
+        // weights tell how to adapt it
+        if let Canceller::Nlmf(filter) = &self.canceller {
+            if novelty < self.parameters.novelty_threshold {
+                self.nonlinear_path.adapt(residual, &filter.weights);
+            }
+        }
+        // the shadow filter sees the same signals as the filter in use, neither of which lags;
+        // after it takes over, the step size starts out large again
+        if let (Canceller::Nlmf(filter), Some(shadow)) = (&mut self.canceller, self.shadow.as_mut())
+        {
+            let takeover = shadow.adapt(filter, reference, mic_sample, residual);
+            match takeover {
+                Some(Takeover::PathChange) => {
+                    info!("Echo path change detected: switched to the weights of the shadow filter")
+                }
+                Some(Takeover::Recovery) => info!(
+                    "Recovered from double talk: switched to the weights of the shadow filter"
+                ),
+                None => (),
+            }
+            if takeover.is_some() {
+                self.step_size.reset();
+            }
+        }
+        let mu = self.step_size.update(residual, self.parameters.mu);
+        self.canceller.set_mu(mu);
+        // the post-processing always runs, so that its state is current when switching back to it
+        let processed = self.highpass_fiter.tick(self.lowpass_filter.tick(residual));
+        let select =
+            |mode: OutputMode| mode.select(mic_sample, capture_sample, aec_output, processed);
+        let frame = SignalFrame {
+            mic: mic_sample,
+            reference: capture_sample,
+            echo_estimate: aec_output,
+            output: self
+                .crossfade
+                .mix(select(self.parameters.output_mode), select),
+        };
+
+        self.mic_energy += mic_sample * mic_sample;
+        self.residual_energy += residual * residual;
+        self.max_novelty = self.max_novelty.max(novelty);
+
+        if let Some(tap) = self.signal_tap.as_mut() {
+            let _ = tap.push(frame);
+        }
+        if let Some(tap) = self.recorder_tap.as_mut() {
+            // the inputs as received, which `replay` delays just as they were
+            tap.record_frame(SignalFrame {
+                mic: input.0,
+                reference: input.1,
+                ..frame
+            });
+        }
+
+        self.samples_since_snapshot += 1;
+        if self.samples_since_snapshot >= self.parameters.weights_snapshot_interval {
+            self.samples_since_snapshot = 0;
+            if let Some(tap) = self.weights_tap.as_mut() {
+                if tap.remaining() >= self.canceller.weights().len() {
+                    let _ = tap.push_slice(self.canceller.weights());
+                }
+            }
+        }
+        frame
+    }
+
+    /// Processes whole signals at once, e.g. recorded or generated ones, and returns the output.
+    /// Nothing is dropped or padded, so the output only depends on the initial weights, the
+    /// parameters and the control messages applied in between.
+    pub fn process_offline(&mut self, mic: &[f32], reference: &[f32]) -> Vec<f32> {
+        assert_eq!(mic.len(), reference.len(), "the signals must be aligned");
+        mic.iter()
+            .zip(reference)
+            .map(|(&mic_sample, &capture_sample)| {
+                self.process_sample(mic_sample, capture_sample).output
+            })
+            .collect()
+    }
+
     // process all available data in input buffers
     fn process(mut self) -> Self {
+        // samples processed since the last telemetry message, across wake-ups
+        let mut counter = 0;
         loop {
             let signal = self.signal_channel.as_ref().unwrap().try_recv(); // here we unwrap because the thread starter has set this channel.
             match signal {
                 Err(mpsc::TryRecvError::Disconnected) => {
-                    eprintln!("Processing thread was disconnected without notice");
+                    error!("Processing thread was disconnected without notice");
                     break;
                 }
                 Ok(()) => {
-                    eprintln!("Processing thread received kill signal");
+                    info!("Processing thread received kill signal");
                     break;
                 }
                 _ => (),
             }
-            let mut counter = 0;
-
+            // the thread starter has set this channel too.
+            while let Ok(message) = self.control_channel.as_mut().unwrap().pop() {
+                self.apply_control(message);
+            }
             // as long as there is data in *both* buffers
             while !self.mic_buffer.is_empty()
                 && !self.capture_buffer.is_empty()
@@ -256,35 +1307,43 @@ impl AECFiltering {
                 // we are guaranteed there is data here as there can be only one consumer at a time
                 let mic_sample = self.mic_buffer.pop().unwrap(); // see comment above to justify unwrap.
                 let capture_sample = self.capture_buffer.pop().unwrap(); // see comment above to justify unwrap.
-                self.filter_buffer.push(capture_sample);
-                let (aec_output, _novelty) =
-                    self.nlmf_filter.adapt(capture_sample, mic_sample, 0.0025);
-                let filtered = self
-                    .highpass_fiter
-                    .tick(self.lowpass_filter.tick(mic_sample - aec_output));
-
-                if counter % 1_000 == 0 {
+                let filtered = self.process_sample(mic_sample, capture_sample).output;
+
+                if counter % self.parameters.telemetry_interval == 0 {
                     counter = 0;
-                    match &self.debug_channel {
-                        Some(ch) => ch
-                            .send((
-                                self.start_time.elapsed().as_secs_f32(),
-                                self.mic_buffer.len() as f32 / self.mic_buffer.capacity() as f32,
-                                self.capture_buffer.len() as f32
-                                    / self.capture_buffer.capacity() as f32,
-                                self.output_buffer.len() as f32
-                                    / self.output_buffer.capacity() as f32,
-                                //novelty * 100.,
-                            ))
-                            .unwrap(),
-                        None => (),
-                    };
+                    if let Some(ch) = &self.debug_channel {
+                        // the viewer may be gone; the processing goes on without it
+                        let _ = ch.send(Telemetry {
+                            time: self.start_time.elapsed().as_secs_f32(),
+                            mic_level: self.mic_buffer.len() as f32
+                                / self.mic_buffer.capacity() as f32,
+                            capture_level: self.capture_buffer.len() as f32
+                                / self.capture_buffer.capacity() as f32,
+                            output_level: self.output_buffer.len() as f32
+                                / self.output_buffer.capacity() as f32,
+                            novelty: self.max_novelty,
+                            erle_db: 10.0
+                                * ((self.mic_energy + f32::EPSILON)
+                                    / (self.residual_energy + f32::EPSILON))
+                                    .log10(),
+                            delay_samples: self.canceller.bulk_delay(),
+                            mu: match self.canceller {
+                                Canceller::Kalman(_) => f32::NAN,
+                                _ => self.step_size.mu(),
+                            },
+                            path_changes: self.path_changes(),
+                            buffered_samples: self.mic_buffer.len() + self.output_buffer.len(),
+                        });
+                    }
+                    self.mic_energy = 0.0;
+                    self.residual_energy = 0.0;
+                    self.max_novelty = 0.0;
                 }
                 counter += 1;
 
                 // if we can no longer push to output buffer:
                 if self.output_buffer.push(filtered).is_err() {
-                    eprintln!("(filter) output stream fell behind: try increasing latency");
+                    warn!("(filter) output stream fell behind: try increasing latency");
                     // no longer process elements!
                     break;
                 }
@@ -294,10 +1353,193 @@ impl AECFiltering {
                 for _ in 0..self.output_buffer.capacity() / 2 {
                     self.output_buffer.push(0.0).unwrap();
                 }
-                eprintln!("(filter) output buffer getting empty; i.e. inputs are too slow. filling with zeroes");
+                warn!("(filter) output buffer getting empty; i.e. inputs are too slow. filling with zeroes");
             }
+            self.latency.record_levels(
+                self.mic_buffer.len(),
+                self.capture_buffer.len(),
+                self.output_buffer.len(),
+            );
             std::thread::park();
         }
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_conversion() {
+        let (producer, mut consumer) = ringbuf::RingBuffer::new(8).split();
+        let mut capture = Stereo2MonoCapture::new(producer).with_channels(3);
+        let dropped = capture.callback(&[i16::MAX, 0, 0, i16::MIN, i16::MIN, i16::MIN]);
+        assert_eq!(dropped, 0);
+        assert!((consumer.pop().unwrap() - 1.0 / 3.0).abs() < 1e-3);
+        assert!((consumer.pop().unwrap() + 1.0).abs() < 1e-3);
+        assert!(consumer.is_empty());
+        // what does not fit in the buffer is dropped
+        assert_eq!(capture.callback(&[0_i16; 30]), 2);
+
+        let (mut producer, consumer) = ringbuf::RingBuffer::new(8).split();
+        producer.push_slice(&[1.0, -1.0]).unwrap();
+        let mut output = Mono2StereoOutput::new(consumer).with_channels(1);
+        let mut data = [0_u16; 3];
+        // the last sample is missing and played as silence
+        assert_eq!(output.callback(&mut data), 1);
+        assert_eq!(data, [u16::MAX, 0, 32768]);
+    }
+
+    #[test]
+    fn test_reproducible_processing() {
+        let parameters = AECParameters {
+            taps: 64,
+            ..AECParameters::default()
+        };
+        let reference: Vec<f32> = (0..9_600)
+            .map(|i| (0.031 * i as f32).sin() + 0.5 * (0.17 * i as f32).cos())
+            .collect();
+        let mic: Vec<f32> = (0..9_600)
+            .map(|i| if i < 20 { 0.0 } else { 0.4 * reference[i - 20] })
+            .collect();
+        let run = |init: &WeightInit| {
+            let mut filter = AECFiltering::offline(&parameters, init.weights(64).unwrap());
+            let mut output = filter.process_offline(&mic[..4_800], &reference[..4_800]);
+            filter.apply_control(Control::SetMu(0.5));
+            output.extend(filter.process_offline(&mic[4_800..], &reference[4_800..]));
+            output.iter().map(|x| x.to_bits()).collect::<Vec<u32>>()
+        };
+
+        // the same seed and configuration give the same output, bit for bit
+        assert_eq!(
+            run(&WeightInit::SeededRandom(7)),
+            run(&WeightInit::SeededRandom(7))
+        );
+        assert_ne!(
+            run(&WeightInit::SeededRandom(7)),
+            run(&WeightInit::SeededRandom(8))
+        );
+        let weights = WeightInit::SeededRandom(7).weights(64).unwrap();
+        assert_eq!(
+            run(&WeightInit::Vector(weights.clone())),
+            run(&WeightInit::SeededRandom(7))
+        );
+
+        let path = std::env::temp_dir().join(format!("raec-weights-{}.npy", std::process::id()));
+        npy::to_file(&path, weights.iter().copied()).unwrap();
+        assert_eq!(WeightInit::File(path.clone()).weights(64).unwrap(), weights);
+        assert!(WeightInit::File(path.clone()).weights(32).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(WeightInit::Zeros.weights(4).unwrap(), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_controls() {
+        let (_mic_producer, mic_consumer) = ringbuf::RingBuffer::new(64).split();
+        let (_capture_producer, capture_consumer) = ringbuf::RingBuffer::new(64).split();
+        let (output_producer, _output_consumer) = ringbuf::RingBuffer::new(64).split();
+        let parameters = AECParameters {
+            taps: 16,
+            ..AECParameters::default()
+        };
+        let filter = AECFiltering::with_parameters(
+            mic_consumer,
+            capture_consumer,
+            output_producer,
+            &parameters,
+            &WeightInit::SeededRandom(0),
+        )
+        .unwrap();
+        assert_eq!(
+            filter.weights(),
+            &WeightInit::SeededRandom(0).weights(16).unwrap()[..]
+        );
+        let (_, mic_consumer) = ringbuf::RingBuffer::new(64).split();
+        let (_, capture_consumer) = ringbuf::RingBuffer::new(64).split();
+        let (output_producer, _) = ringbuf::RingBuffer::new(64).split();
+        assert!(AECFiltering::with_parameters(
+            mic_consumer,
+            capture_consumer,
+            output_producer,
+            &parameters,
+            &WeightInit::Vector(vec![0.0; 8]),
+        )
+        .is_err());
+        let (mut running, thread) = filter.start_thread();
+
+        running.set_mu(0.5).unwrap();
+        running.set_eps(0.1).unwrap();
+        running.set_novelty_threshold(0.01).unwrap();
+        running.enable_stage(Stage::LowPass, false).unwrap();
+        running.set_output_mode(OutputMode::Bypass).unwrap();
+        running.reset_weights().unwrap();
+        running.request_weights_snapshot().unwrap();
+        thread.unpark();
+
+        let started = std::time::Instant::now();
+        let snapshot = loop {
+            if let Some(snapshot) = running.weights_snapshot() {
+                break snapshot;
+            }
+            assert!(started.elapsed().as_secs() < 5, "no snapshot received");
+            std::thread::yield_now();
+        };
+        assert_eq!(snapshot, vec![0.0; 16]);
+
+        let filter = running.kill();
+        assert_eq!(
+            filter.parameters(),
+            &AECParameters {
+                mu: 0.5,
+                eps: 0.1,
+                novelty_threshold: 0.01,
+                lowpass_hz: None,
+                output_mode: OutputMode::Bypass,
+                ..parameters
+            }
+        );
+    }
+
+    #[test]
+    fn test_group_delay() {
+        let mut filter = AECFiltering::offline(&AECParameters::default(), vec![0.0; nlmf::N_TAPS]);
+        let latency = filter.latency();
+        let both = latency.group_delay();
+        assert!(both > 0.0);
+        filter.apply_control(Control::EnableStage(Stage::LowPass, false));
+        let highpass = latency.group_delay();
+        assert!(highpass > 0.0 && highpass < both);
+        // the other modes do not go through the post-processing
+        filter.apply_control(Control::SetOutputMode(OutputMode::Bypass));
+        assert_eq!(latency.group_delay(), 0.0);
+        filter.apply_control(Control::SetOutputMode(OutputMode::Processing));
+        filter.apply_control(Control::EnableStage(Stage::HighPass, false));
+        assert_eq!(latency.group_delay(), 0.0);
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let select = |mode: OutputMode| mode.select(1.0, 2.0, 3.0, 4.0);
+        let mut crossfade = Crossfade::new(3);
+        assert_eq!(crossfade.mix(select(OutputMode::Bypass), select), 1.0);
+
+        // from the processed signal to the echo estimate in 3 samples
+        crossfade.start(OutputMode::Processing);
+        let mixed: Vec<f32> = (0..5)
+            .map(|_| crossfade.mix(select(OutputMode::EchoEstimate), select))
+            .collect();
+        assert_eq!(mixed, vec![3.75, 3.5, 3.25, 3.0, 3.0]);
+
+        assert_eq!(
+            "Echo-Estimate".parse::<OutputMode>().unwrap(),
+            OutputMode::EchoEstimate
+        );
+        assert!("loopback".parse::<OutputMode>().is_err());
+        assert_eq!(
+            OutputMode::ReferencePassthrough.next(),
+            OutputMode::Processing
+        );
+    }
+}
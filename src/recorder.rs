@@ -0,0 +1,353 @@
+//! Recording of what the processing thread sees, to replay a session offline.
+//!
+//! A `Recorder` receives every sample processed by an `AECFiltering` and every control message
+//! it applied through a lock-free `RecorderTap`, and writes them from its own thread into a
+//! directory:
+//!
+//! - `signals.wav`: the microphone, reference, echo estimate and output signals, in that order, as
+//!   the channels of a 32 bit float WAV file. The microphone and reference signals are those the
+//!   filter received; the echo estimate and output lag them by the block delay of the canceller,
+//!   `AECFiltering::delay`, as they did when played;
+//! - `weights.npy`: the initial weights of the adaptive filter;
+//! - `metadata.json`: the start time, the parameters and configuration of the pipeline, and the
+//!   control messages with the index of the sample they were applied before.
+//!
+//! `replay` runs a recording through `AECFiltering::process_offline` and gives back the recorded
+//! output bit for bit, unless events had to be dropped while recording.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::processing::{AECFiltering, AECParameters, Control, SignalFrame};
+
+pub const SIGNALS_FILE: &str = "signals.wav";
+pub const WEIGHTS_FILE: &str = "weights.npy";
+pub const METADATA_FILE: &str = "metadata.json";
+
+/// Names of the channels of `SIGNALS_FILE`, in order
+pub const CHANNELS: [&str; 4] = ["microphone", "reference", "echo_estimate", "output"];
+
+/// The ends of the recorder queues held by the processing thread. Nothing is ever blocked: what
+/// does not fit in a queue is dropped, and counted.
+pub struct RecorderTap {
+    frames: ringbuf::Producer<SignalFrame>,
+    controls: ringbuf::Producer<RecordedControl>,
+    /// Samples processed so far
+    samples: u64,
+    /// Events which did not fit in the queues
+    dropped: Arc<AtomicUsize>,
+}
+
+impl RecorderTap {
+    pub(crate) fn record_frame(&mut self, frame: SignalFrame) {
+        self.samples += 1;
+        if self.frames.push(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_control(&mut self, control: Control) {
+        let recorded = RecordedControl {
+            sample: self.samples,
+            control,
+        };
+        if self.controls.push(recorded).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A control message and when it was applied.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedControl {
+    /// Index of the first sample processed after the message was applied
+    pub sample: u64,
+    pub control: Control,
+}
+
+/// Everything about a recording but the signals.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Start of the recording in seconds since the Unix epoch; sample `n` was processed about
+    /// `n / sample_rate` seconds later
+    pub started_at: f64,
+    pub sample_rate: u32,
+    /// Names of the channels of the WAV file
+    pub channels: Vec<String>,
+    /// The parameters of the filter when the recording started
+    pub parameters: AECParameters,
+    /// The configuration of the pipeline as TOML, when known
+    pub config: Option<String>,
+    /// Number of recorded samples per channel
+    pub samples: u64,
+    pub controls: Vec<RecordedControl>,
+    /// Events lost because the recorder did not keep up; a recording with lost events cannot be
+    /// replayed exactly
+    pub dropped_events: usize,
+}
+
+impl Metadata {
+    fn write(&self, directory: &Path) -> Result<(), anyhow::Error> {
+        let file = std::fs::File::create(directory.join(METADATA_FILE))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Writes the events of a `RecorderTap` to a directory from a thread of its own.
+pub struct Recorder {
+    directory: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<Result<Metadata, anyhow::Error>>,
+}
+
+impl Recorder {
+    /// Starts recording into `directory`, created if needed, a filter starting with `parameters`
+    /// and `initial_weights`. The returned tap goes into `AECFiltering::recorder_tap`.
+    pub fn start(
+        directory: &Path,
+        parameters: &AECParameters,
+        initial_weights: &[f32],
+        config: Option<String>,
+    ) -> Result<(Recorder, RecorderTap), anyhow::Error> {
+        std::fs::create_dir_all(directory)?;
+        npy::to_file(
+            directory.join(WEIGHTS_FILE),
+            initial_weights.iter().copied(),
+        )?;
+        let sample_rate = parameters.sample_rate as u32;
+        let mut metadata = Metadata {
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            sample_rate,
+            channels: CHANNELS.iter().map(|name| name.to_string()).collect(),
+            parameters: parameters.clone(),
+            config,
+            samples: 0,
+            controls: vec![],
+            dropped_events: 0,
+        };
+        metadata.write(directory)?;
+        let spec = hound::WavSpec {
+            channels: CHANNELS.len() as u16,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(directory.join(SIGNALS_FILE), spec)?;
+
+        // two seconds of samples for the recorder thread to catch up after a hiccup
+        let (frames, mut frame_receiver) =
+            ringbuf::RingBuffer::<SignalFrame>::new(2 * sample_rate as usize).split();
+        let (controls, mut control_receiver) = ringbuf::RingBuffer::new(256).split();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let directory = directory.to_path_buf();
+            let dropped = dropped.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                loop {
+                    // everything sent before the stop request is written
+                    let stopping = stop.load(Ordering::Acquire);
+                    while let Ok(frame) = frame_receiver.pop() {
+                        for &sample in &[
+                            frame.mic,
+                            frame.reference,
+                            frame.echo_estimate,
+                            frame.output,
+                        ] {
+                            writer.write_sample(sample)?;
+                        }
+                        metadata.samples += 1;
+                        // keep the file readable should raec not exit cleanly
+                        if metadata.samples.is_multiple_of(sample_rate as u64) {
+                            writer.flush()?;
+                        }
+                    }
+                    while let Ok(control) = control_receiver.pop() {
+                        metadata.controls.push(control);
+                    }
+                    if stopping {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                writer.finalize()?;
+                metadata.dropped_events = dropped.load(Ordering::Relaxed);
+                metadata.write(&directory)?;
+                Ok(metadata)
+            })
+        };
+        Ok((
+            Recorder {
+                directory: directory.to_path_buf(),
+                stop,
+                thread,
+            },
+            RecorderTap {
+                frames,
+                controls,
+                samples: 0,
+                dropped,
+            },
+        ))
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Writes what is left in the queue and closes the files. The filter should be stopped
+    /// first, so that nothing is sent after this.
+    pub fn finish(self) -> Result<Metadata, anyhow::Error> {
+        self.stop.store(true, Ordering::Release);
+        self.thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("The recorder thread panicked"))?
+    }
+}
+
+/// A recording read back from its directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    pub metadata: Metadata,
+    pub initial_weights: Vec<f32>,
+    pub frames: Vec<SignalFrame>,
+}
+
+impl Recording {
+    pub fn load(directory: &Path) -> Result<Recording, anyhow::Error> {
+        let metadata: Metadata =
+            serde_json::from_reader(std::fs::File::open(directory.join(METADATA_FILE))?)?;
+        let bytes = std::fs::read(directory.join(WEIGHTS_FILE))?;
+        let initial_weights = npy::NpyData::<f32>::from_bytes(&bytes)?.to_vec();
+        let mut reader = hound::WavReader::open(directory.join(SIGNALS_FILE))?;
+        if reader.spec().channels as usize != CHANNELS.len() {
+            return Err(anyhow::anyhow!(
+                "{} has {} channels instead of {}",
+                SIGNALS_FILE,
+                reader.spec().channels,
+                CHANNELS.len()
+            ));
+        }
+        let samples = reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?;
+        let frames = samples
+            .chunks_exact(CHANNELS.len())
+            .map(|frame| SignalFrame {
+                mic: frame[0],
+                reference: frame[1],
+                echo_estimate: frame[2],
+                output: frame[3],
+            })
+            .collect();
+        Ok(Recording {
+            metadata,
+            initial_weights,
+            frames,
+        })
+    }
+}
+
+/// Runs the recorded microphone and reference signals through a filter set up as the recorded
+/// one was, applying the recorded control messages at the same samples, and returns its output.
+pub fn replay(recording: &Recording) -> Vec<f32> {
+    let mut filter = AECFiltering::offline(
+        &recording.metadata.parameters,
+        recording.initial_weights.clone(),
+    );
+    let mic: Vec<f32> = recording.frames.iter().map(|frame| frame.mic).collect();
+    let reference: Vec<f32> = recording
+        .frames
+        .iter()
+        .map(|frame| frame.reference)
+        .collect();
+    let mut output = Vec::with_capacity(mic.len());
+    for recorded in &recording.metadata.controls {
+        let end = (recorded.sample as usize).min(mic.len());
+        let start = output.len();
+        output.extend(filter.process_offline(&mic[start..end], &reference[start..end]));
+        filter.apply_control(recorded.control);
+    }
+    let start = output.len();
+    output.extend(filter.process_offline(&mic[start..], &reference[start..]));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{Algorithm, OutputMode};
+
+    #[test]
+    fn test_record_and_replay() {
+        // the block cancellers delay their output, but not what is recorded of their input
+        for &algorithm in Algorithm::ALL.iter() {
+            record_and_replay(algorithm);
+        }
+    }
+
+    fn record_and_replay(algorithm: Algorithm) {
+        let directory = std::env::temp_dir().join(format!(
+            "raec-recorder-test-{}-{}",
+            std::process::id(),
+            algorithm
+        ));
+        let parameters = AECParameters {
+            taps: 16,
+            algorithm,
+            ..AECParameters::default()
+        };
+        let weights: Vec<f32> = (0..16).map(|i| 0.01 * i as f32).collect();
+        let mut filter = AECFiltering::offline(&parameters, weights);
+        let (recorder, tap) =
+            Recorder::start(&directory, &parameters, filter.weights(), None).unwrap();
+        filter.recorder_tap = Some(tap);
+
+        let reference: Vec<f32> = (0..4_800)
+            .map(|i| (0.05 * i as f32).sin() * (0.003 * i as f32).cos())
+            .collect();
+        let mic: Vec<f32> = (0..4_800)
+            .map(|i| if i < 5 { 0.0 } else { 0.6 * reference[i - 5] })
+            .collect();
+        let mut output = filter.process_offline(&mic[..2_400], &reference[..2_400]);
+        filter.apply_control(Control::SetMu(0.25));
+        filter.apply_control(Control::SetOutputMode(OutputMode::EchoEstimate));
+        output.extend(filter.process_offline(&mic[2_400..], &reference[2_400..]));
+        filter.recorder_tap = None;
+
+        let metadata = recorder.finish().unwrap();
+        assert_eq!(metadata.samples, 4_800);
+        assert_eq!(metadata.dropped_events, 0);
+        assert_eq!(
+            metadata.controls,
+            vec![
+                RecordedControl {
+                    sample: 2_400,
+                    control: Control::SetMu(0.25)
+                },
+                RecordedControl {
+                    sample: 2_400,
+                    control: Control::SetOutputMode(OutputMode::EchoEstimate)
+                },
+            ]
+        );
+
+        let recording = Recording::load(&directory).unwrap();
+        assert_eq!(recording.metadata, metadata);
+        let recorded_output: Vec<f32> = recording.frames.iter().map(|f| f.output).collect();
+        let replayed = replay(&recording);
+        let bits = |signal: &[f32]| signal.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&recorded_output), bits(&output), "{}", algorithm);
+        assert_eq!(bits(&replayed), bits(&output), "{}", algorithm);
+        let recorded_mic: Vec<f32> = recording.frames.iter().map(|f| f.mic).collect();
+        assert_eq!(recorded_mic, mic, "{}", algorithm);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,838 @@
+//! Synthetic echo scenarios, to exercise the echo cancellation on something like real echo.
+//!
+//! A `Scenario` places a loudspeaker, a microphone and possibly a near-end talker in a shoebox
+//! room, and `Scenario::generate` gives the signals the microphone would pick up. The room impulse
+//! responses come from the image-source method of Allen and Berkley: each reflection off a wall is
+//! replaced by a mirror image of the source, attenuated by the distance it travels and by the
+//! walls it bounced off. The absorption of the walls follows from the reverberation time through
+//! Sabine's formula; as with bare parallel walls in a real room, the decay of the late
+//! reverberation in a large or reverberant room comes out up to half as long again.
+//!
+//! A loudspeaker driven hard distorts what it plays; it can be made to saturate softly, as a
+//! `tanh` curve, before the room filters its sound.
+//!
+//! The far-end and near-end signals are either synthetic, speech-like or white noise, or read from
+//! a WAV file. The components of the microphone signal are kept apart so that the performance of
+//! the cancellation can be measured against them.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+
+/// Speed of sound in air at room temperature (m/s)
+pub const SPEED_OF_SOUND: f32 = 343.0;
+
+/// Half the length of the windowed sinc placing a reflection between two samples
+const SINC_HALF_WIDTH: isize = 16;
+
+/// Formant frequencies of a few vowels (Hz)
+const VOWELS: [(f32, f32); 5] = [
+    (800.0, 1200.0),
+    (500.0, 1900.0),
+    (300.0, 2300.0),
+    (500.0, 900.0),
+    (350.0, 800.0),
+];
+
+/// A position in a room, in metres from one of its corners.
+pub type Position = [f32; 3];
+
+/// A shoebox room.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Room {
+    /// Length, width and height (m)
+    pub dimensions: [f32; 3],
+    /// Time for the sound to decay by 60 dB (s)
+    pub rt60_s: f32,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Room {
+            dimensions: [5.0, 4.0, 3.0],
+            rt60_s: 0.3,
+        }
+    }
+}
+
+impl Room {
+    /// Reflection coefficient of the walls giving the reverberation time, from Sabine's formula.
+    pub fn reflection_coefficient(&self) -> f32 {
+        let [x, y, z] = self.dimensions;
+        let volume = x * y * z;
+        let surface = 2.0 * (x * y + y * z + z * x);
+        let absorption = (0.161 * volume / (surface * self.rt60_s)).min(1.0);
+        (1.0 - absorption).sqrt()
+    }
+
+    pub fn contains(&self, position: Position) -> bool {
+        position
+            .iter()
+            .zip(&self.dimensions)
+            .all(|(&p, &size)| 0.0 < p && p < size)
+    }
+
+    /// The first `length` samples of the impulse response from `source` to `receiver`.
+    pub fn impulse_response(
+        &self,
+        source: Position,
+        receiver: Position,
+        sample_rate: f32,
+        length: usize,
+    ) -> Vec<f32> {
+        let mut response = vec![0.0; length];
+        let beta = self.reflection_coefficient();
+        let max_distance =
+            (length as isize + SINC_HALF_WIDTH) as f32 * SPEED_OF_SOUND / sample_rate;
+        // images per axis: (offset of the image from the receiver, number of reflections)
+        let images: Vec<Vec<(f32, i32)>> = (0..3)
+            .map(|axis| {
+                let size = self.dimensions[axis];
+                let max_order = (max_distance / (2.0 * size)).ceil() as i32 + 1;
+                let mut images = vec![];
+                for n in -max_order..=max_order {
+                    for mirrored in 0..2 {
+                        let image =
+                            (1 - 2 * mirrored) as f32 * source[axis] + 2.0 * n as f32 * size;
+                        let reflections = (n - mirrored).abs() + n.abs();
+                        images.push((image - receiver[axis], reflections));
+                    }
+                }
+                images
+            })
+            .collect();
+        for &(dx, rx) in &images[0] {
+            for &(dy, ry) in &images[1] {
+                for &(dz, rz) in &images[2] {
+                    let distance = (dx * dx + dy * dy + dz * dz).sqrt();
+                    if distance > max_distance {
+                        continue;
+                    }
+                    let gain = beta.powi(rx + ry + rz) / (4.0 * PI * distance.max(0.01));
+                    add_fractional_impulse(
+                        &mut response,
+                        distance / SPEED_OF_SOUND * sample_rate,
+                        gain,
+                    );
+                }
+            }
+        }
+        response
+    }
+}
+
+/// Adds an impulse at a fractional position, spread over the neighbouring samples by a Hann
+/// windowed sinc.
+fn add_fractional_impulse(response: &mut [f32], at: f32, gain: f32) {
+    let centre = at.round() as isize;
+    for i in
+        (centre - SINC_HALF_WIDTH).max(0)..(centre + SINC_HALF_WIDTH).min(response.len() as isize)
+    {
+        let t = i as f32 - at;
+        let sinc = if t.abs() < 1e-6 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        let window = 0.5 * (1.0 + (PI * t / SINC_HALF_WIDTH as f32).cos());
+        response[i as usize] += gain * sinc * window;
+    }
+}
+
+/// The first `signal.len()` samples of the convolution of `signal` with `response`.
+pub fn convolve(signal: &[f32], response: &[f32]) -> Vec<f32> {
+    if signal.is_empty() || response.is_empty() {
+        return vec![0.0; signal.len()];
+    }
+    let size = (signal.len() + response.len() - 1).next_power_of_two();
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(size);
+    let inverse = planner.plan_fft_inverse(size);
+    let spectrum = |x: &[f32]| {
+        let mut spectrum = vec![Complex::new(0.0, 0.0); size];
+        for (bin, &sample) in spectrum.iter_mut().zip(x) {
+            *bin = Complex::new(sample, 0.0);
+        }
+        forward.process(&mut spectrum);
+        spectrum
+    };
+    let mut product = spectrum(signal);
+    for (a, b) in product.iter_mut().zip(spectrum(response)) {
+        *a *= b;
+    }
+    inverse.process(&mut product);
+    product
+        .iter()
+        .take(signal.len())
+        .map(|bin| bin.re / size as f32)
+        .collect()
+}
+
+/// Root mean square of a signal.
+pub fn rms(signal: &[f32]) -> f32 {
+    if signal.is_empty() {
+        return 0.0;
+    }
+    (signal.iter().map(|x| x * x).sum::<f32>() / signal.len() as f32).sqrt()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Scales `signal` to the given RMS.
+fn normalise(signal: &mut [f32], target_rms: f32) {
+    let current = rms(signal);
+    if current > 0.0 {
+        signal.iter_mut().for_each(|x| *x *= target_rms / current);
+    }
+}
+
+/// A two pole resonator at `frequency` with a bandwidth of `bandwidth`, normalised to unit gain
+/// at its peak.
+struct Resonator {
+    a1: f32,
+    a2: f32,
+    gain: f32,
+    y: [f32; 2],
+}
+
+impl Resonator {
+    fn new(frequency: f32, bandwidth: f32, sample_rate: f32) -> Self {
+        let r = (-PI * bandwidth / sample_rate).exp();
+        let w = 2.0 * PI * frequency / sample_rate;
+        Resonator {
+            a1: 2.0 * r * w.cos(),
+            a2: -r * r,
+            gain: (1.0 - r) * (1.0 - 2.0 * r * (2.0 * w).cos() + r * r).sqrt(),
+            y: [0.0; 2],
+        }
+    }
+
+    fn tick(&mut self, x: f32) -> f32 {
+        let y = self.gain * x + self.a1 * self.y[0] + self.a2 * self.y[1];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// A signal with the rhythm and spectrum of speech: voiced syllables at a varying pitch, shaped
+/// by the formants of a vowel, separated by pauses. Normalised to unit RMS.
+pub fn speech_like(rng: &mut impl Rng, sample_rate: f32, samples: usize) -> Vec<f32> {
+    let noise = Normal::new(0.0, 1.0).unwrap();
+    let mut signal = Vec::with_capacity(samples);
+    while signal.len() < samples {
+        let syllable = (rng.gen_range(0.1, 0.3) * sample_rate) as usize;
+        let pitch = rng.gen_range(90.0, 220.0);
+        let (f1, f2) = VOWELS[rng.gen_range(0, VOWELS.len())];
+        let mut formants = [
+            Resonator::new(f1, 80.0, sample_rate),
+            Resonator::new(f2, 120.0, sample_rate),
+        ];
+        let mut phase = 0.0;
+        for i in 0..syllable {
+            // the pitch glides down over the syllable
+            phase += pitch * (1.0 - 0.2 * i as f32 / syllable as f32) / sample_rate;
+            let pulse = if phase >= 1.0 {
+                phase -= 1.0;
+                1.0
+            } else {
+                0.0
+            };
+            let excitation = pulse + 0.05 * noise.sample(rng) as f32;
+            let voiced = formants[0].tick(excitation) + 0.5 * formants[1].tick(excitation);
+            let envelope = (PI * i as f32 / syllable as f32).sin().powi(2);
+            signal.push(envelope * voiced);
+        }
+        // short gaps between syllables, longer ones between words
+        let pause = if rng.gen_bool(0.3) {
+            rng.gen_range(0.1, 0.4)
+        } else {
+            rng.gen_range(0.0, 0.05)
+        };
+        signal.resize(signal.len() + (pause * sample_rate) as usize, 0.0);
+    }
+    signal.truncate(samples);
+    normalise(&mut signal, 1.0);
+    signal
+}
+
+/// Reads a WAV file as a mono signal, averaging its channels.
+pub fn read_wav(path: &Path, sample_rate: u32) -> Result<Vec<f32>, anyhow::Error> {
+    let mut reader = hound::WavReader::open(path)
+        .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?;
+    let spec = reader.spec();
+    if spec.sample_rate != sample_rate {
+        return Err(anyhow::anyhow!(
+            "{} is sampled at {} Hz instead of {} Hz",
+            path.display(),
+            spec.sample_rate,
+            sample_rate
+        ));
+    }
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 2.0_f32.powi(spec.bits_per_sample as i32 - 1);
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    Ok(samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect())
+}
+
+/// Where a signal of a scenario comes from.
+///
+/// Written as `speech`, `white-noise` or the path of a WAV file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Source {
+    /// See `speech_like`
+    Speech,
+    WhiteNoise,
+    /// A WAV file at the sample rate of the scenario, looped as needed
+    Wav(PathBuf),
+}
+
+impl Source {
+    /// `samples` samples of the signal, at unit RMS for the synthetic ones.
+    pub fn signal(
+        &self,
+        rng: &mut impl Rng,
+        sample_rate: u32,
+        samples: usize,
+    ) -> Result<Vec<f32>, anyhow::Error> {
+        Ok(match self {
+            Source::Speech => speech_like(rng, sample_rate as f32, samples),
+            Source::WhiteNoise => white_noise(rng, samples),
+            Source::Wav(path) => {
+                let wav = read_wav(path, sample_rate)?;
+                if wav.is_empty() {
+                    return Err(anyhow::anyhow!("{} is empty", path.display()));
+                }
+                wav.iter().copied().cycle().take(samples).collect()
+            }
+        })
+    }
+}
+
+impl From<String> for Source {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "speech" => Source::Speech,
+            "white-noise" => Source::WhiteNoise,
+            _ => Source::Wav(PathBuf::from(s)),
+        }
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> Self {
+        source.to_string()
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Speech => write!(f, "speech"),
+            Source::WhiteNoise => write!(f, "white-noise"),
+            Source::Wav(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn white_noise(rng: &mut impl Rng, samples: usize) -> Vec<f32> {
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    normal.sample_iter(rng).take(samples).collect()
+}
+
+/// A talker in the room, heard by the microphone along with the echo.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NearEnd {
+    pub source: Source,
+    pub position: Position,
+    /// Level at the microphone relative to the echo (dB)
+    pub level_db: f32,
+    /// When the talk starts (s)
+    pub start_s: f32,
+    /// When the talk ends (s); at the end of the scenario when not given
+    pub end_s: Option<f32>,
+}
+
+impl Default for NearEnd {
+    fn default() -> Self {
+        NearEnd {
+            source: Source::Speech,
+            position: [3.5, 2.5, 1.6],
+            level_db: 0.0,
+            start_s: 0.0,
+            end_s: None,
+        }
+    }
+}
+
+/// A sudden change of the echo path, e.g. the loudspeaker being moved.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EchoPathChange {
+    /// When the change happens (s)
+    pub at_s: f32,
+    /// Where the loudspeaker is after the change
+    pub loudspeaker: Position,
+    /// The delay of the echo after the change (ms); unchanged when not given
+    pub delay_ms: Option<f32>,
+}
+
+impl Default for EchoPathChange {
+    fn default() -> Self {
+        EchoPathChange {
+            at_s: 5.0,
+            loudspeaker: [1.5, 3.0, 1.0],
+            delay_ms: None,
+        }
+    }
+}
+
+/// A far-end signal played in a room and picked up with near-end talk and noise.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    pub sample_rate: u32,
+    pub duration_s: f32,
+    /// Seed of the random generator; the same seed gives the same signals
+    pub seed: u64,
+    pub room: Room,
+    pub loudspeaker: Position,
+    pub microphone: Position,
+    /// Length of the room impulse responses (ms); the reverberation time when not given
+    pub impulse_response_ms: Option<f32>,
+    /// Delay of the echo on top of the propagation in the room, e.g. that of the audio devices
+    /// (ms)
+    pub delay_ms: f32,
+    /// The signal played by the loudspeaker
+    pub far_end: Source,
+    /// RMS level of the far-end signal (dB full scale)
+    pub far_end_level_db: f32,
+    /// Level the loudspeaker saturates at (full scale); it plays the far-end signal undistorted
+    /// when not given
+    pub loudspeaker_saturation: Option<f32>,
+    pub near_end: Option<NearEnd>,
+    /// Level of the echo relative to a white background noise (dB); no noise when not given
+    pub snr_db: Option<f32>,
+    pub echo_path_change: Option<EchoPathChange>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            sample_rate: 16_000,
+            duration_s: 10.0,
+            seed: 0,
+            room: Room::default(),
+            loudspeaker: [1.0, 1.0, 1.0],
+            microphone: [2.0, 1.5, 1.2],
+            impulse_response_ms: None,
+            delay_ms: 0.0,
+            far_end: Source::Speech,
+            far_end_level_db: -20.0,
+            loudspeaker_saturation: None,
+            near_end: None,
+            snr_db: Some(40.0),
+            echo_path_change: None,
+        }
+    }
+}
+
+/// The signals of a generated scenario, all of the same length.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Simulation {
+    pub sample_rate: u32,
+    /// The signal played by the loudspeaker, i.e. the reference of the echo canceller
+    pub far_end: Vec<f32>,
+    /// The far-end signal as played by the loudspeaker and picked up by the microphone
+    pub echo: Vec<f32>,
+    /// The near-end talk as picked up by the microphone
+    pub near_end: Vec<f32>,
+    pub noise: Vec<f32>,
+    /// The sum of the echo, the near-end talk and the noise
+    pub microphone: Vec<f32>,
+    /// The impulse responses of the echo path, delay included: before the change, and after it
+    /// if there is one
+    pub echo_paths: Vec<Vec<f32>>,
+    /// The first sample with the changed echo path
+    pub change_at: Option<usize>,
+}
+
+impl Scenario {
+    fn samples(&self, seconds: f32) -> usize {
+        (seconds.max(0.0) * self.sample_rate as f32).round() as usize
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.sample_rate == 0 || self.duration_s <= 0.0 || self.room.rt60_s <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "The sample rate, duration and reverberation time must be positive"
+            ));
+        }
+        if self
+            .loudspeaker_saturation
+            .is_some_and(|level| level <= 0.0)
+        {
+            return Err(anyhow::anyhow!(
+                "The saturation level of the loudspeaker must be positive"
+            ));
+        }
+        let mut positions = vec![
+            ("loudspeaker", self.loudspeaker),
+            ("microphone", self.microphone),
+        ];
+        if let Some(near_end) = &self.near_end {
+            positions.push(("near-end talker", near_end.position));
+        }
+        if let Some(change) = &self.echo_path_change {
+            positions.push(("moved loudspeaker", change.loudspeaker));
+        }
+        for (name, position) in positions {
+            if !self.room.contains(position) {
+                return Err(anyhow::anyhow!(
+                    "The {} at {:?} is not inside the room of {:?}",
+                    name,
+                    position,
+                    self.room.dimensions
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The impulse response from `source` to the microphone, delayed by `delay_ms`.
+    fn echo_path(&self, source: Position, delay_ms: f32) -> Vec<f32> {
+        let length = self.samples(
+            self.impulse_response_ms
+                .unwrap_or(1_000.0 * self.room.rt60_s)
+                / 1_000.0,
+        );
+        let mut path = vec![0.0; self.samples(delay_ms / 1_000.0)];
+        path.extend(self.room.impulse_response(
+            source,
+            self.microphone,
+            self.sample_rate as f32,
+            length,
+        ));
+        path
+    }
+
+    pub fn generate(&self) -> Result<Simulation, anyhow::Error> {
+        self.validate()?;
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let samples = self.samples(self.duration_s);
+
+        let mut far_end = self.far_end.signal(&mut rng, self.sample_rate, samples)?;
+        normalise(&mut far_end, db_to_gain(self.far_end_level_db));
+        let played: Vec<f32> = match self.loudspeaker_saturation {
+            Some(level) => far_end.iter().map(|x| level * (x / level).tanh()).collect(),
+            None => far_end.clone(),
+        };
+        let mut echo_paths = vec![self.echo_path(self.loudspeaker, self.delay_ms)];
+        let mut echo = convolve(&played, &echo_paths[0]);
+        let mut change_at = None;
+        if let Some(change) = &self.echo_path_change {
+            let path = self.echo_path(change.loudspeaker, change.delay_ms.unwrap_or(self.delay_ms));
+            let at = self.samples(change.at_s).min(samples);
+            let changed = convolve(&played, &path);
+            echo[at..].copy_from_slice(&changed[at..]);
+            echo_paths.push(path);
+            change_at = Some(at);
+        }
+        let echo_rms = rms(&echo);
+
+        let mut near_end = vec![0.0; samples];
+        if let Some(talker) = &self.near_end {
+            let start = self.samples(talker.start_s).min(samples);
+            let end = talker
+                .end_s
+                .map_or(samples, |end| self.samples(end).clamp(start, samples));
+            let mut talk = talker
+                .source
+                .signal(&mut rng, self.sample_rate, end - start)?;
+            talk.resize(samples - start, 0.0);
+            let path = self.echo_path(talker.position, 0.0);
+            near_end[start..].copy_from_slice(&convolve(&talk, &path));
+            // the level is that of the talk while it lasts
+            let talk_rms = rms(&near_end[start..end]);
+            if talk_rms > 0.0 {
+                let gain = echo_rms * db_to_gain(talker.level_db) / talk_rms;
+                near_end.iter_mut().for_each(|x| *x *= gain);
+            }
+        }
+
+        let mut noise = vec![0.0; samples];
+        if let Some(snr_db) = self.snr_db {
+            noise = white_noise(&mut rng, samples);
+            normalise(&mut noise, echo_rms / db_to_gain(snr_db));
+        }
+
+        let microphone = echo
+            .iter()
+            .zip(&near_end)
+            .zip(&noise)
+            .map(|((e, n), v)| e + n + v)
+            .collect();
+        Ok(Simulation {
+            sample_rate: self.sample_rate,
+            far_end,
+            echo,
+            near_end,
+            noise,
+            microphone,
+            echo_paths,
+            change_at,
+        })
+    }
+}
+
+/// What the tests of the cancellers share: a small room, the parameters they run with and levels.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::processing::{AECFiltering, AECParameters};
+
+    /// Level of a signal (dB), from its RMS.
+    pub fn level_db(signal: &[f32]) -> f32 {
+        20.0 * rms(signal).log10()
+    }
+
+    /// A scenario in a small, damped room, with an echo path short enough for 512 taps.
+    pub fn small_room() -> Scenario {
+        Scenario {
+            room: Room {
+                rt60_s: 0.15,
+                ..Room::default()
+            },
+            impulse_response_ms: Some(25.0),
+            ..Scenario::default()
+        }
+    }
+
+    /// The parameters of a canceller of 512 taps at 16 kHz, without post-processing.
+    pub fn parameters() -> AECParameters {
+        AECParameters {
+            sample_rate: 16_000.0,
+            taps: 512,
+            highpass_hz: None,
+            lowpass_hz: None,
+            ..AECParameters::default()
+        }
+    }
+
+    /// Cancels the echo of a simulation, from zero weights; the output is aligned with the
+    /// microphone, whatever the delay of the canceller.
+    pub fn cancel(parameters: &AECParameters, simulation: &Simulation) -> (AECFiltering, Vec<f32>) {
+        let mut filter = AECFiltering::offline(parameters, vec![0.0; parameters.taps]);
+        let padding = vec![0.0; filter.delay()];
+        let output = filter.process_offline(
+            &[&simulation.microphone[..], &padding].concat(),
+            &[&simulation.far_end[..], &padding].concat(),
+        );
+        let output = output[filter.delay()..].to_vec();
+        (filter, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{cancel, level_db, parameters, small_room};
+    use super::*;
+
+    #[test]
+    fn test_impulse_response() {
+        let room = Room {
+            dimensions: [6.0, 5.0, 3.0],
+            rt60_s: 0.4,
+        };
+        let sample_rate = 16_000.0;
+        let response = room.impulse_response([1.0, 1.0, 1.2], [4.0, 1.0, 1.7], sample_rate, 8_000);
+
+        // the direct path: the first and strongest arrival
+        let distance = (9.0_f32 + 0.25).sqrt();
+        let direct = distance / SPEED_OF_SOUND * sample_rate;
+        let (peak, &peak_value) = response
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.abs().partial_cmp(&b.1.abs()).unwrap())
+            .unwrap();
+        assert_eq!(peak, direct.round() as usize);
+        assert!((peak_value - 1.0 / (4.0 * PI * distance)).abs() < 0.005);
+        assert!(response[..peak - SINC_HALF_WIDTH as usize]
+            .iter()
+            .all(|&x| x == 0.0));
+
+        // the decay, from the backward integrated energy between -5 and -25 dB
+        let mut energy: Vec<f32> = response
+            .iter()
+            .rev()
+            .scan(0.0, |sum, x| {
+                *sum += x * x;
+                Some(*sum)
+            })
+            .collect();
+        energy.reverse();
+        let decay_db = |db: f32| {
+            energy
+                .iter()
+                .position(|&e| 10.0 * (e / energy[0]).log10() < db)
+                .unwrap() as f32
+                / sample_rate
+        };
+        let rt60 = 3.0 * (decay_db(-25.0) - decay_db(-5.0));
+        // the specular reflections of parallel walls decay somewhat slower than Sabine's formula
+        assert!(
+            0.9 * room.rt60_s < rt60 && rt60 < 1.5 * room.rt60_s,
+            "rt60 = {}",
+            rt60
+        );
+
+        // a more absorbent room decays faster
+        let dry = Room {
+            rt60_s: 0.1,
+            ..room.clone()
+        };
+        let dry_response =
+            dry.impulse_response([1.0, 1.0, 1.2], [4.0, 1.0, 1.7], sample_rate, 8_000);
+        assert!(rms(&dry_response[1_600..]) < 0.1 * rms(&response[1_600..]));
+    }
+
+    #[test]
+    fn test_convolve() {
+        let signal = [1.0, 2.0, 3.0, 4.0];
+        let convolved = convolve(&signal, &[0.0, 0.5, 0.25]);
+        for (got, expected) in convolved.iter().zip(&[0.0, 0.5, 1.25, 2.0]) {
+            assert!((got - expected).abs() < 1e-5);
+        }
+        assert_eq!(convolved.len(), signal.len());
+    }
+
+    #[test]
+    fn test_scenario() {
+        let scenario = Scenario {
+            duration_s: 2.0,
+            delay_ms: 10.0,
+            near_end: Some(NearEnd {
+                level_db: -6.0,
+                start_s: 1.0,
+                ..NearEnd::default()
+            }),
+            snr_db: Some(30.0),
+            echo_path_change: Some(EchoPathChange {
+                at_s: 1.5,
+                ..EchoPathChange::default()
+            }),
+            ..Scenario::default()
+        };
+        let simulation = scenario.generate().unwrap();
+        assert_eq!(simulation, scenario.generate().unwrap());
+        assert_eq!(simulation.microphone.len(), 32_000);
+        assert!((level_db(&simulation.far_end) + 20.0).abs() < 0.01);
+
+        // the echo path starts with the delay, then the direct path
+        let path = &simulation.echo_paths[0];
+        let distance = (1.0_f32 + 0.25 + 0.04).sqrt();
+        let direct = 160 + (distance / SPEED_OF_SOUND * 16_000.0).round() as usize;
+        assert!(path[..direct - SINC_HALF_WIDTH as usize]
+            .iter()
+            .all(|&x| x == 0.0));
+        assert!(path[direct].abs() > 0.5 / (4.0 * PI * distance));
+
+        let echo_db = level_db(&simulation.echo);
+        assert!((echo_db - level_db(&simulation.noise) - 30.0).abs() < 0.01);
+        assert!(simulation.near_end[..16_000].iter().all(|&x| x == 0.0));
+        assert!((echo_db - 6.0 - level_db(&simulation.near_end[16_000..])).abs() < 0.01);
+
+        // the echo follows the second path after the change
+        assert_eq!(simulation.change_at, Some(24_000));
+        assert_eq!(simulation.echo_paths.len(), 2);
+        let second = convolve(&simulation.far_end, &simulation.echo_paths[1]);
+        assert_eq!(simulation.echo[24_000..], second[24_000..]);
+        assert_ne!(simulation.echo[..24_000], second[..24_000]);
+
+        for (i, &sample) in simulation.microphone.iter().enumerate() {
+            let sum = simulation.echo[i] + simulation.near_end[i] + simulation.noise[i];
+            assert_eq!(sample, sum);
+        }
+
+        let outside = Scenario {
+            microphone: [2.0, 5.0, 1.0],
+            ..Scenario::default()
+        };
+        assert!(outside.generate().is_err());
+        let missing = Scenario {
+            far_end: Source::Wav(PathBuf::from("/nonexistent.wav")),
+            ..Scenario::default()
+        };
+        assert!(missing.generate().is_err());
+        let silent = Scenario {
+            loudspeaker_saturation: Some(0.0),
+            ..Scenario::default()
+        };
+        assert!(silent.generate().is_err());
+
+        // a saturating loudspeaker distorts the far-end signal before the room
+        let clipped = Scenario {
+            duration_s: 0.5,
+            loudspeaker_saturation: Some(0.05),
+            ..Scenario::default()
+        }
+        .generate()
+        .unwrap();
+        let played: Vec<f32> = clipped
+            .far_end
+            .iter()
+            .map(|x| 0.05 * (x / 0.05).tanh())
+            .collect();
+        assert_eq!(clipped.echo, convolve(&played, &clipped.echo_paths[0]));
+    }
+
+    #[test]
+    fn test_cancels_simulated_echo() {
+        let scenario = Scenario {
+            duration_s: 4.0,
+            far_end: Source::WhiteNoise,
+            echo_path_change: Some(EchoPathChange {
+                at_s: 2.0,
+                ..EchoPathChange::default()
+            }),
+            ..small_room()
+        };
+        let simulation = scenario.generate().unwrap();
+        let (_, output) = cancel(&parameters(), &simulation);
+        let erle_db = |range: std::ops::Range<usize>| {
+            level_db(&simulation.microphone[range.clone()]) - level_db(&output[range])
+        };
+        // converged before the change, thrown off by it, converged again
+        assert!(
+            erle_db(24_000..32_000) > 20.0,
+            "{}",
+            erle_db(24_000..32_000)
+        );
+        assert!(erle_db(32_000..32_800) < erle_db(24_000..32_000) - 10.0);
+        assert!(
+            erle_db(56_000..64_000) > 20.0,
+            "{}",
+            erle_db(56_000..64_000)
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Step size control of the adaptive filter.
+//!
+//! A large step size converges fast, but the weights then wander with the noise in the
+//! microphone signal and leave more echo behind; a small one leaves less echo behind but takes
+//! long to converge, at the start and again whenever the echo path changes. `AECParameters::mu`
+//! stays fixed with the `fixed` control. With the `variable` control it is the largest step size,
+//! and the `StepSizeController` follows the non-parametric variable step size NLMS algorithm of
+//! Benesty et al.: the step size is `mu (1 - σ_v / σ_e)`, where `σ_e²` is the smoothed power of
+//! the residual and `σ_v²` that of the noise no filter can take out of it. While the residual
+//! holds much more echo than noise, at the start or after the echo path changed, the step size
+//! is close to `mu`; it comes down as the residual comes down to the noise, but no lower than
+//! `min_mu`, so that the filter keeps following slow changes.
+//!
+//! The noise power is the minimum of the residual power, which rises by a factor of `e` every
+//! `NOISE_RISE_S` at most. It is kept no more than `MAX_ECHO_TO_NOISE` below the residual power,
+//! so that it starts out low enough for the step size to start out large, but catches up with
+//! the actual noise within seconds. Near-end speech raises the residual just as an echo path
+//! change does, and with it the step size: the novelty threshold is what keeps the weights still
+//! then.
+
+use serde::{Deserialize, Serialize};
+
+/// Lowest noise power the controller assumes, i.e. -100 dB full scale
+const NOISE_FLOOR: f32 = 1e-10;
+/// Time for the estimated noise power to rise by a factor of `e` when the residual does not come
+/// down to it (s); slower than the filter converges
+const NOISE_RISE_S: f32 = 0.5;
+/// Smallest ratio of the estimated noise power to the residual power, i.e. -40 dB
+const MAX_ECHO_TO_NOISE: f32 = 1e-4;
+
+/// How the step size of the adaptive filter is set; see the module documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepSizeControl {
+    #[default]
+    Fixed,
+    Variable,
+}
+
+impl StepSizeControl {
+    pub const ALL: [StepSizeControl; 2] = [StepSizeControl::Fixed, StepSizeControl::Variable];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StepSizeControl::Fixed => "fixed",
+            StepSizeControl::Variable => "variable",
+        }
+    }
+}
+
+impl std::fmt::Display for StepSizeControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for StepSizeControl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StepSizeControl::ALL
+            .iter()
+            .copied()
+            .find(|control| control.name() == s.to_lowercase())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown step size control \"{}\"; use one of {}",
+                    s,
+                    StepSizeControl::ALL
+                        .iter()
+                        .map(|control| control.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Parameters of the step size control.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StepSize {
+    pub control: StepSizeControl,
+    /// Smallest step size of the `variable` control; `AECParameters::mu` is the largest
+    pub min_mu: f32,
+    /// Time constant of the smoothing of the residual power (ms)
+    pub smoothing_ms: f32,
+}
+
+impl Default for StepSize {
+    fn default() -> Self {
+        StepSize {
+            control: StepSizeControl::Fixed,
+            min_mu: 0.05,
+            smoothing_ms: 100.0,
+        }
+    }
+}
+
+impl StepSize {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.min_mu < 0.0 {
+            return Err(anyhow::anyhow!(
+                "The smallest step size must not be negative"
+            ));
+        }
+        if self.smoothing_ms <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "The smoothing time constant of the residual power must be positive"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Sets the step size of the adaptive filter from its residual.
+#[derive(Clone, Debug)]
+pub struct StepSizeController {
+    parameters: StepSize,
+    /// Smoothing factor of the residual power
+    smoothing: f32,
+    /// Largest factor by which the noise power rises in one sample
+    rise: f32,
+    /// Smoothed power of the residual
+    error_power: f32,
+    /// Estimated power of the noise in the residual
+    noise_power: f32,
+    /// The step size given by the last update
+    mu: f32,
+}
+
+impl StepSizeController {
+    pub fn new(parameters: &StepSize, sample_rate: f32) -> Self {
+        StepSizeController {
+            parameters: parameters.clone(),
+            smoothing: 1.0 - (-1_000.0 / (parameters.smoothing_ms * sample_rate)).exp(),
+            rise: (1.0 / (NOISE_RISE_S * sample_rate)).exp(),
+            error_power: 0.0,
+            noise_power: NOISE_FLOOR,
+            mu: 0.0,
+        }
+    }
+
+    pub fn control(&self) -> StepSizeControl {
+        self.parameters.control
+    }
+
+    /// Takes in the residual of the last sample and returns the step size for the next one, at
+    /// most `mu`.
+    pub fn update(&mut self, error: f32, mu: f32) -> f32 {
+        self.mu = match self.parameters.control {
+            StepSizeControl::Fixed => mu,
+            StepSizeControl::Variable => {
+                self.error_power += self.smoothing * (error * error - self.error_power);
+                self.noise_power = (self.noise_power * self.rise)
+                    .min(self.error_power)
+                    .max(self.error_power * MAX_ECHO_TO_NOISE)
+                    .max(NOISE_FLOOR);
+                let ratio = (self.noise_power / (self.error_power + NOISE_FLOOR)).sqrt();
+                (mu * (1.0 - ratio)).max(self.parameters.min_mu.min(mu))
+            }
+        };
+        self.mu
+    }
+
+    /// The step size given by the last update.
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    /// Forgets the residual seen so far, so that the step size starts out large again.
+    pub fn reset(&mut self) {
+        self.error_power = 0.0;
+        self.noise_power = NOISE_FLOOR;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::AECParameters;
+    use crate::simulation::testing::{cancel, level_db, parameters, small_room};
+    use crate::simulation::{EchoPathChange, Scenario, Source};
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_follows_residual() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut noise = |amplitude: f32| amplitude * rng.gen_range(-1.0, 1.0);
+        let parameters = StepSize {
+            control: StepSizeControl::Variable,
+            ..StepSize::default()
+        };
+        let mut controller = StepSizeController::new(&parameters, 16_000.0);
+        // the residual is all echo at first, then comes down to the noise
+        for i in 0..16_000 {
+            let echo = 0.1 * (-(i as f32) / 2_000.0).exp();
+            controller.update(noise(echo + 1e-3), 1.0);
+            if i == 1_600 {
+                assert!(controller.mu() > 0.8, "{}", controller.mu());
+            }
+        }
+        for _ in 0..48_000 {
+            controller.update(noise(1e-3), 1.0);
+        }
+        assert_eq!(controller.mu(), parameters.min_mu);
+        // the echo path changed
+        for _ in 0..1_600 {
+            controller.update(noise(0.03), 1.0);
+        }
+        assert!(controller.mu() > 0.8, "{}", controller.mu());
+        // never more than the largest step size
+        assert!(controller.update(0.5, 0.5) <= 0.5);
+
+        let mut fixed = StepSizeController::new(&StepSize::default(), 16_000.0);
+        assert_eq!(fixed.update(0.5, 0.3), 0.3);
+        assert_eq!(fixed.update(1e-6, 0.3), 0.3);
+    }
+
+    #[test]
+    fn test_converges_fast_and_settles_low() {
+        let scenario = Scenario {
+            duration_s: 6.0,
+            far_end: Source::WhiteNoise,
+            snr_db: Some(30.0),
+            echo_path_change: Some(EchoPathChange {
+                at_s: 3.0,
+                ..EchoPathChange::default()
+            }),
+            ..small_room()
+        };
+        let simulation = scenario.generate().unwrap();
+        // the ERLE over the first second, the third and the one after the change
+        let run = |control, mu| {
+            let parameters = AECParameters {
+                mu,
+                step_size: StepSize {
+                    control,
+                    ..StepSize::default()
+                },
+                ..parameters()
+            };
+            let (_, output) = cancel(&parameters, &simulation);
+            let erle_db = |second: usize| {
+                let range = second * 16_000..(second + 1) * 16_000;
+                level_db(&simulation.microphone[range.clone()]) - level_db(&output[range])
+            };
+            [erle_db(0), erle_db(2), erle_db(3)]
+        };
+        let large = run(StepSizeControl::Fixed, 1.0);
+        let small = run(StepSizeControl::Fixed, 0.05);
+        let variable = run(StepSizeControl::Variable, 1.0);
+        // as fast as the large step size, as little misadjustment as the small one
+        assert!(
+            variable[0] > large[0] - 1.0,
+            "{:?} vs {:?}",
+            variable,
+            large
+        );
+        assert!(
+            variable[0] > small[0] + 10.0,
+            "{:?} vs {:?}",
+            variable,
+            small
+        );
+        assert!(
+            variable[1] > large[1] + 1.5,
+            "{:?} vs {:?}",
+            variable,
+            large
+        );
+        assert!(
+            variable[2] > large[2] - 1.0,
+            "{:?} vs {:?}",
+            variable,
+            large
+        );
+        assert!(
+            variable[2] > small[2] + 5.0,
+            "{:?} vs {:?}",
+            variable,
+            small
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! Negotiation of a stream configuration with each audio device and construction of the streams.
+//!
+//! The three streams must run at the same sample rate, as nothing resamples between them, but
+//! each device keeps its own sample format, number of channels and buffer size. The samples are
+//! converted to and from mono f32 by `Stereo2MonoCapture` and `Mono2StereoOutput`.
+
+use cpal::traits::DeviceTrait;
+use cpal::{SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfigRange};
+use log::error;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::devices::Direction;
+use crate::processing::{Mono2StereoOutput, Stereo2MonoCapture};
+
+/// A stream configuration supported by a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NegotiatedConfig {
+    pub config: cpal::StreamConfig,
+    pub sample_format: SampleFormat,
+}
+
+impl std::fmt::Display for NegotiatedConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}, {} channel(s) at {} Hz, ",
+            self.sample_format, self.config.channels, self.config.sample_rate.0
+        )?;
+        match self.config.buffer_size {
+            cpal::BufferSize::Default => write!(f, "default buffer size"),
+            cpal::BufferSize::Fixed(frames) => write!(f, "buffers of {} frames", frames),
+        }
+    }
+}
+
+/// The part of a `cpal::SupportedStreamConfigRange` used to choose a configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub buffer_size: SupportedBufferSize,
+    pub sample_format: SampleFormat,
+}
+
+impl From<&SupportedStreamConfigRange> for ConfigRange {
+    fn from(range: &SupportedStreamConfigRange) -> Self {
+        ConfigRange {
+            channels: range.channels(),
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            buffer_size: range.buffer_size().clone(),
+            sample_format: range.sample_format(),
+        }
+    }
+}
+
+/// Preference of the sample formats, best first; f32 needs no conversion.
+fn format_rank(format: SampleFormat) -> usize {
+    match format {
+        SampleFormat::F32 => 0,
+        SampleFormat::I16 => 1,
+        SampleFormat::U16 => 2,
+    }
+}
+
+/// Picks the best of the supported configurations for the given sample rate.
+///
+/// The configurations with the format and number of channels of the device default come first,
+/// then the formats needing the least conversion. A buffer size outside of the range supported by
+/// the chosen configuration is clamped into it.
+pub fn choose_config(
+    supported: &[ConfigRange],
+    default: Option<(SampleFormat, u16)>,
+    sample_rate: u32,
+    buffer_frames: Option<u32>,
+) -> Option<NegotiatedConfig> {
+    let range = supported
+        .iter()
+        .filter(|range| {
+            range.min_sample_rate <= sample_rate && sample_rate <= range.max_sample_rate
+        })
+        .min_by_key(|range| {
+            let same_format = default.map(|(format, _)| format) == Some(range.sample_format);
+            let same_channels = default.map(|(_, channels)| channels) == Some(range.channels);
+            (
+                !same_format,
+                !same_channels,
+                format_rank(range.sample_format),
+                range.channels,
+            )
+        })?;
+    let buffer_size = match (buffer_frames, &range.buffer_size) {
+        (None, _) => cpal::BufferSize::Default,
+        (Some(frames), SupportedBufferSize::Range { min, max }) => {
+            cpal::BufferSize::Fixed(frames.clamp(*min, *max))
+        }
+        (Some(frames), SupportedBufferSize::Unknown) => cpal::BufferSize::Fixed(frames),
+    };
+    Some(NegotiatedConfig {
+        config: cpal::StreamConfig {
+            channels: range.channels,
+            sample_rate: SampleRate(sample_rate),
+            buffer_size,
+        },
+        sample_format: range.sample_format,
+    })
+}
+
+/// The sample rate of the default configuration of the device.
+pub fn default_sample_rate(
+    device: &cpal::Device,
+    direction: Direction,
+) -> Result<u32, anyhow::Error> {
+    let config = match direction {
+        Direction::Input => device.default_input_config()?,
+        Direction::Output => device.default_output_config()?,
+    };
+    Ok(config.sample_rate().0)
+}
+
+/// Finds a configuration of the device running at `sample_rate`; see `choose_config`.
+pub fn negotiate(
+    device: &cpal::Device,
+    direction: Direction,
+    sample_rate: u32,
+    buffer_frames: Option<u32>,
+) -> Result<NegotiatedConfig, anyhow::Error> {
+    let (supported, default): (Vec<ConfigRange>, _) = match direction {
+        Direction::Input => (
+            device
+                .supported_input_configs()?
+                .map(|range| ConfigRange::from(&range))
+                .collect(),
+            device.default_input_config().ok(),
+        ),
+        Direction::Output => (
+            device
+                .supported_output_configs()?
+                .map(|range| ConfigRange::from(&range))
+                .collect(),
+            device.default_output_config().ok(),
+        ),
+    };
+    let default = default.map(|config| (config.sample_format(), config.channels()));
+    choose_config(&supported, default, sample_rate, buffer_frames).ok_or_else(|| {
+        anyhow::anyhow!(
+            "The {} device \"{}\" does not support a sample rate of {} Hz; supported: {}",
+            direction,
+            device.name().unwrap_or_default(),
+            sample_rate,
+            supported
+                .iter()
+                .map(|range| format!("{}-{} Hz", range.min_sample_rate, range.max_sample_rate))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })
+}
+
+/// Activity of a stream, shared with its callbacks so that it can be watched from another thread.
+#[derive(Debug, Default)]
+pub struct StreamHealth {
+    /// Number of data callbacks run so far
+    callbacks: AtomicUsize,
+    /// Number of errors reported so far
+    errors: AtomicUsize,
+    /// Number of samples lost so far: those which did not fit in the buffer of an input stream,
+    /// or were missing from the buffer of an output stream
+    lost_samples: AtomicUsize,
+    /// Delay of the device at the last callback which told it (µs), plus one; zero until then
+    device_latency_us: AtomicU64,
+}
+
+impl StreamHealth {
+    pub fn callbacks(&self) -> usize {
+        self.callbacks.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn lost_samples(&self) -> usize {
+        self.lost_samples.load(Ordering::Relaxed)
+    }
+
+    /// How long the device holds a sample on its side of the stream, at the last callback which
+    /// told it: from capture to callback for an input, from callback to playback for an output.
+    pub fn device_latency(&self) -> Option<Duration> {
+        match self.device_latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us - 1)),
+        }
+    }
+
+    pub(crate) fn record_callback(&self) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_lost_samples(&self, samples: usize) {
+        self.lost_samples.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_device_latency(&self, latency: Option<Duration>) {
+        if let Some(latency) = latency {
+            let us = latency.as_micros().min(u64::MAX as u128 - 1) as u64;
+            self.device_latency_us.store(us + 1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn error_callback(
+        self: Arc<Self>,
+    ) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        move |err| {
+            error!("an error occurred on stream: {}", err);
+            self.record_error();
+        }
+    }
+}
+
+/// Handles a buffer of an input stream: feeds it to `capture` and counts it in `health`, along
+/// with how long ago its first sample was captured, if known.
+///
+/// `capture` is only used when its lock is free, so that a stream being torn down never blocks
+/// the one replacing it.
+pub(crate) fn input_callback<T: cpal::Sample>(
+    capture: &Mutex<Stereo2MonoCapture>,
+    data: &[T],
+    health: &StreamHealth,
+    device_latency: Option<Duration>,
+) {
+    health.record_device_latency(device_latency);
+    if let Ok(mut capture) = capture.try_lock() {
+        let dropped = if capture.unparks() {
+            capture.callback_and_unpark(data)
+        } else {
+            capture.callback(data)
+        };
+        health.record_lost_samples(dropped);
+    }
+    health.record_callback();
+}
+
+/// Fills a buffer of an output stream from `output` and counts it in `health`, along with how
+/// long until its first sample is played, if known; see `input_callback` for the use of the
+/// lock. When the lock is taken the buffer is silent.
+pub(crate) fn output_callback<T: cpal::Sample>(
+    output: &Mutex<Mono2StereoOutput>,
+    data: &mut [T],
+    health: &StreamHealth,
+    device_latency: Option<Duration>,
+) {
+    health.record_device_latency(device_latency);
+    match output.try_lock() {
+        Ok(mut output) => health.record_lost_samples(output.callback(data)),
+        Err(_) => {
+            let silence = T::from(&0.0_f32);
+            data.iter_mut().for_each(|sample| *sample = silence);
+        }
+    }
+    health.record_callback();
+}
+
+/// Builds an input stream feeding `capture`, converting from the negotiated sample format.
+///
+/// See `input_callback`; `health` counts the callbacks, lost samples and errors of the stream,
+/// and keeps the device latency given by the timestamps of the callbacks.
+pub fn build_input_stream(
+    device: &cpal::Device,
+    negotiated: &NegotiatedConfig,
+    capture: Arc<Mutex<Stereo2MonoCapture>>,
+    health: Arc<StreamHealth>,
+) -> Result<cpal::Stream, anyhow::Error> {
+    fn build<T: cpal::Sample>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        capture: Arc<Mutex<Stereo2MonoCapture>>,
+        health: Arc<StreamHealth>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let callback_health = health.clone();
+        device.build_input_stream(
+            config,
+            move |data: &[T], info: &cpal::InputCallbackInfo| {
+                let timestamp = info.timestamp();
+                let latency = timestamp.callback.duration_since(&timestamp.capture);
+                input_callback(&capture, data, &callback_health, latency)
+            },
+            health.error_callback(),
+        )
+    }
+
+    capture
+        .lock()
+        .unwrap()
+        .set_channels(negotiated.config.channels as usize);
+    let config = &negotiated.config;
+    let stream = match negotiated.sample_format {
+        SampleFormat::F32 => build::<f32>(device, config, capture, health),
+        SampleFormat::I16 => build::<i16>(device, config, capture, health),
+        SampleFormat::U16 => build::<u16>(device, config, capture, health),
+    }?;
+    Ok(stream)
+}
+
+/// Builds an output stream played from `output`, converting to the negotiated sample format.
+///
+/// See `output_callback`; `health` counts the callbacks, lost samples and errors of the stream,
+/// and keeps the device latency given by the timestamps of the callbacks.
+pub fn build_output_stream(
+    device: &cpal::Device,
+    negotiated: &NegotiatedConfig,
+    output: Arc<Mutex<Mono2StereoOutput>>,
+    health: Arc<StreamHealth>,
+) -> Result<cpal::Stream, anyhow::Error> {
+    fn build<T: cpal::Sample>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        output: Arc<Mutex<Mono2StereoOutput>>,
+        health: Arc<StreamHealth>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let callback_health = health.clone();
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                let timestamp = info.timestamp();
+                let latency = timestamp.playback.duration_since(&timestamp.callback);
+                output_callback(&output, data, &callback_health, latency)
+            },
+            health.error_callback(),
+        )
+    }
+
+    output
+        .lock()
+        .unwrap()
+        .set_channels(negotiated.config.channels as usize);
+    let config = &negotiated.config;
+    let stream = match negotiated.sample_format {
+        SampleFormat::F32 => build::<f32>(device, config, output, health),
+        SampleFormat::I16 => build::<i16>(device, config, output, health),
+        SampleFormat::U16 => build::<u16>(device, config, output, health),
+    }?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(channels: u16, rates: (u32, u32), sample_format: SampleFormat) -> ConfigRange {
+        ConfigRange {
+            channels,
+            min_sample_rate: rates.0,
+            max_sample_rate: rates.1,
+            buffer_size: SupportedBufferSize::Range { min: 64, max: 4096 },
+            sample_format,
+        }
+    }
+
+    #[test]
+    fn test_choose_config() {
+        let supported = vec![
+            range(1, (8_000, 48_000), SampleFormat::I16),
+            range(2, (8_000, 48_000), SampleFormat::I16),
+            range(2, (44_100, 44_100), SampleFormat::F32),
+            range(6, (8_000, 96_000), SampleFormat::U16),
+        ];
+
+        // the device default wins when it supports the rate
+        let chosen = choose_config(&supported, Some((SampleFormat::I16, 2)), 48_000, None).unwrap();
+        assert_eq!(chosen.sample_format, SampleFormat::I16);
+        assert_eq!(chosen.config.channels, 2);
+        assert_eq!(chosen.config.buffer_size, cpal::BufferSize::Default);
+
+        // otherwise the format needing the least conversion
+        let chosen = choose_config(&supported, None, 44_100, Some(16)).unwrap();
+        assert_eq!(chosen.sample_format, SampleFormat::F32);
+        assert_eq!(chosen.config.sample_rate, SampleRate(44_100));
+        assert_eq!(chosen.config.buffer_size, cpal::BufferSize::Fixed(64));
+
+        let chosen = choose_config(&supported, None, 96_000, Some(256)).unwrap();
+        assert_eq!(chosen.sample_format, SampleFormat::U16);
+        assert_eq!(chosen.config.channels, 6);
+        assert_eq!(chosen.config.buffer_size, cpal::BufferSize::Fixed(256));
+
+        assert!(choose_config(&supported, None, 192_000, None).is_none());
+    }
+}
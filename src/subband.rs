@@ -0,0 +1,380 @@
+//! Echo cancellation in subbands.
+//!
+//! The full band adaptive filter converges slowly on speech: its step size is normalised by the
+//! power of the whole reference, so the quiet high frequencies adapt far more slowly than the
+//! loud low ones. The `SubbandCanceller` splits the microphone and reference signals into the
+//! bands of an oversampled `FilterBank` and runs a short normalised least mean squares filter on
+//! the complex values of each band, with a step size normalised by the power of its own band:
+//! every band converges about as fast, whatever the colour of the reference. The step size of
+//! each band is moreover `mu` scaled by the `band_mu` profile, e.g. to adapt the bands with
+//! little echo energy more cautiously. The echo estimates of the bands are put back together by
+//! the synthesis of the filter bank.
+//!
+//! A frame of the microphone holds the echo of the frames of the reference overlapping it,
+//! including later ones when the echo path is shorter than a frame. The microphone is delayed by
+//! `frame - hop` samples before its analysis, so that the filters of the bands only need the
+//! frames of the reference already seen; along with the delay of the filter bank, the echo
+//! estimate then lags the input by `2 frame - hop - 1` samples.
+//!
+//! The filter of each band spans as many frames as the echo path of `taps` samples, and
+//! `2 (oversampling - 1)` more for the frames overlapping its ends. The regularisation of the
+//! normalisation is divided by the hop, as a band holds about that much less of the energy of the
+//! reference than the full band over the same time. The weights start out at zero.
+
+use circular_queue::CircularQueue;
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+
+use crate::filterbank::{Analysis, FilterBank, Synthesis};
+
+/// Layout of the filter bank of the `subband` canceller.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubbandParameters {
+    /// Number of samples of a frame; there are `frame / 2 + 1` bands
+    pub frame: usize,
+    /// Number of frames overlapping each sample; at least 3, and a divisor of `frame`
+    pub oversampling: usize,
+    /// Step size of each band relative to `mu`, given at evenly spaced frequencies from DC to
+    /// Nyquist and interpolated linearly in between; empty for `mu` in every band
+    pub band_mu: Vec<f32>,
+}
+
+impl Default for SubbandParameters {
+    fn default() -> Self {
+        SubbandParameters {
+            frame: 256,
+            oversampling: 4,
+            band_mu: vec![],
+        }
+    }
+}
+
+impl SubbandParameters {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !self.band_mu.iter().all(|&mu| mu > 0.0 && mu.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "The relative step sizes of the bands must be positive"
+            ));
+        }
+        self.filter_bank().map(|_| ())
+    }
+
+    pub fn filter_bank(&self) -> Result<FilterBank, anyhow::Error> {
+        FilterBank::new(self.frame, self.oversampling)
+    }
+
+    /// Number of samples by which the echo estimate lags the input.
+    pub fn delay(&self) -> usize {
+        2 * self.frame - self.frame / self.oversampling - 1
+    }
+
+    /// The step size of every band relative to `mu`, from DC to Nyquist.
+    pub fn band_mu(&self) -> Vec<f32> {
+        let bands = self.frame / 2 + 1;
+        match self.band_mu.len() {
+            0 => vec![1.0; bands],
+            1 => vec![self.band_mu[0]; bands],
+            points => (0..bands)
+                .map(|band| {
+                    let position = (band * (points - 1)) as f32 / (bands - 1).max(1) as f32;
+                    let below = (position as usize).min(points - 2);
+                    let fraction = position - below as f32;
+                    self.band_mu[below] * (1.0 - fraction) + self.band_mu[below + 1] * fraction
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Adaptive filters in the bands of a filter bank, estimating the echo of the reference in the
+/// microphone signal.
+pub struct SubbandCanceller {
+    bank: FilterBank,
+    mic: Analysis,
+    reference: Analysis,
+    synthesis: Synthesis,
+    /// Delays the microphone so that the filters stay causal
+    mic_delay: CircularQueue<f32>,
+    /// Number of taps of the filter of each band
+    taps: usize,
+    /// The last `taps` frames of the reference, band after band; the newest of each band at
+    /// `newest`
+    history: Vec<Complex<f32>>,
+    newest: usize,
+    /// The weights of the filters, band after band, from the newest frame to the oldest
+    weights: Vec<Complex<f32>>,
+    /// The echo estimate of the last frame
+    estimate: Vec<Complex<f32>>,
+    mu: f32,
+    /// Step size of each band relative to `mu`
+    band_mu: Vec<f32>,
+    eps: f32,
+    /// Novelty of the last frame
+    novelty: f32,
+}
+
+impl SubbandCanceller {
+    /// A canceller of echo paths up to `taps` samples long. Panics if the parameters do not
+    /// validate.
+    pub fn new(parameters: &SubbandParameters, taps: usize, mu: f32, eps: f32) -> Self {
+        let bank = parameters.filter_bank().unwrap_or_else(|e| panic!("{}", e));
+        let lookahead = bank.frame() - bank.hop();
+        let mut mic_delay = CircularQueue::with_capacity(lookahead + 1);
+        for _ in 0..lookahead + 1 {
+            mic_delay.push(0.0);
+        }
+        let band_taps = taps.div_ceil(bank.hop()) + 2 * (parameters.oversampling - 1);
+        let zeros = vec![Complex::new(0.0, 0.0); bank.bands() * band_taps];
+        SubbandCanceller {
+            mic: bank.analysis(),
+            reference: bank.analysis(),
+            synthesis: bank.synthesis(),
+            mic_delay,
+            taps: band_taps,
+            history: zeros.clone(),
+            newest: 0,
+            weights: zeros,
+            estimate: vec![Complex::new(0.0, 0.0); bank.bands()],
+            mu,
+            band_mu: parameters.band_mu(),
+            eps,
+            novelty: 0.0,
+            bank,
+        }
+    }
+
+    /// Takes in a sample of the reference and of the microphone, and gives back the echo
+    /// estimate and the novelty of the last frame, as `NLMF::adapt`. The estimate lags the input
+    /// by `delay`. The filter of a band is only adapted when its novelty is below the threshold.
+    pub fn adapt(&mut self, reference: f32, mic: f32, novelty_threshold: f32) -> (f32, f32) {
+        self.mic_delay.push(mic);
+        // SAFETY: the queue is full from the start
+        let delayed_mic = *self.mic_delay.asc_iter().next().unwrap();
+        if let Some(bands) = self.reference.push(reference) {
+            self.newest = (self.newest + 1) % self.taps;
+            for (band, &value) in bands.iter().enumerate() {
+                self.history[band * self.taps + self.newest] = value;
+            }
+        }
+        // both analyses complete their frames on the same samples
+        if let Some(bands) = self.mic.push(delayed_mic) {
+            let (taps, newest) = (self.taps, self.newest);
+            let eps = self.eps / self.bank.hop() as f32;
+            self.novelty = 0.0;
+            for (band, &target) in bands.iter().enumerate() {
+                let history = &self.history[band * taps..(band + 1) * taps];
+                let weights = &mut self.weights[band * taps..(band + 1) * taps];
+                // the reference from the newest frame to the oldest
+                let frames = || (0..taps).map(|lag| history[(newest + taps - lag) % taps]);
+                let estimate: Complex<f32> = weights.iter().zip(frames()).map(|(w, x)| w * x).sum();
+                let power: f32 = frames().map(|x| x.norm_sqr()).sum();
+                let peak = frames().map(|x| x.norm_sqr()).fold(0.0, f32::max).sqrt();
+                let error = target - estimate;
+                let step = self.mu * self.band_mu[band] / (eps + power);
+                let novelty = step * error.norm_sqr() * peak;
+                if novelty < novelty_threshold {
+                    for (w, x) in weights.iter_mut().zip(frames()) {
+                        *w += step * error * x.conj();
+                    }
+                }
+                self.novelty = self.novelty.max(novelty);
+                self.estimate[band] = estimate;
+            }
+            self.synthesis.push(&self.estimate);
+        }
+        (self.synthesis.pop(), self.novelty)
+    }
+
+    pub fn filter_bank(&self) -> &FilterBank {
+        &self.bank
+    }
+
+    /// Number of samples by which the echo estimate lags the input.
+    pub fn delay(&self) -> usize {
+        self.bank.delay() + self.bank.frame() - self.bank.hop()
+    }
+
+    /// Number of taps of the filter of each band.
+    pub fn band_taps(&self) -> usize {
+        self.taps
+    }
+
+    /// The weights of the filter of a band, from the newest frame of the reference to the oldest.
+    pub fn band_weights(&self, band: usize) -> &[Complex<f32>] {
+        &self.weights[band * self.taps..(band + 1) * self.taps]
+    }
+
+    /// The step size of every band, before normalisation.
+    pub fn band_mu(&self) -> impl Iterator<Item = f32> + '_ {
+        self.band_mu.iter().map(move |relative| self.mu * relative)
+    }
+
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    /// Sets `mu`, which the `band_mu` profile scales in each band.
+    pub fn set_mu(&mut self, mu: f32) {
+        self.mu = mu;
+    }
+
+    pub fn eps(&self) -> f32 {
+        self.eps
+    }
+
+    pub fn set_eps(&mut self, eps: f32) {
+        self.eps = eps;
+    }
+
+    /// Sets all weights to zero, forgetting everything learned so far
+    pub fn reset_weights(&mut self) {
+        for w in self.weights.iter_mut() {
+            *w = Complex::new(0.0, 0.0);
+        }
+    }
+
+    /// Estimated bulk delay (in samples) of the modelled echo path, from the frame whose weights
+    /// carry the most energy over all the bands.
+    pub fn bulk_delay(&self) -> usize {
+        let energy = |lag: usize| -> f32 {
+            self.weights
+                .chunks_exact(self.taps)
+                .map(|band| band[lag].norm_sqr())
+                .sum()
+        };
+        let loudest = (0..self.taps)
+            .max_by(|&a, &b| energy(a).partial_cmp(&energy(b)).unwrap())
+            .unwrap_or(0);
+        // the microphone is delayed by the lookahead
+        (loudest * self.bank.hop()).saturating_sub(self.bank.frame() - self.bank.hop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{AECFiltering, AECParameters, Algorithm, OutputMode};
+    use crate::simulation::testing::{cancel, level_db, parameters, small_room};
+    use crate::simulation::{Scenario, Source};
+
+    #[test]
+    fn test_parameters() {
+        let parameters = SubbandParameters::default();
+        assert!(parameters.validate().is_ok());
+        let canceller = SubbandCanceller::new(&parameters, 1024, 1.0, 1.0);
+        assert_eq!(canceller.delay(), parameters.delay());
+        assert_eq!(canceller.delay(), 447);
+        assert_eq!(canceller.band_taps(), 16 + 6);
+        assert!(SubbandParameters {
+            oversampling: 3,
+            ..parameters.clone()
+        }
+        .validate()
+        .is_err());
+
+        // the profile is interpolated over the bands
+        let profile = SubbandParameters {
+            frame: 8,
+            band_mu: vec![1.0, 0.5],
+            ..parameters.clone()
+        };
+        assert_eq!(profile.band_mu(), vec![1.0, 0.875, 0.75, 0.625, 0.5]);
+        let flat = SubbandParameters {
+            band_mu: vec![0.5],
+            ..parameters.clone()
+        };
+        assert_eq!(flat.band_mu(), vec![0.5; 129]);
+        assert_eq!(parameters.band_mu(), vec![1.0; 129]);
+        assert!(SubbandParameters {
+            band_mu: vec![1.0, 0.0],
+            ..parameters
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_band_step_sizes() {
+        // an echo path of a single tap, on a reference with as much energy in every band
+        let reference: Vec<f32> = (0..2_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 16) as f32 / 32_768.0 - 1.0)
+            .collect();
+        // the energy of the weights of the lowest and highest bands
+        let run = |band_mu: Vec<f32>| {
+            let parameters = SubbandParameters {
+                frame: 16,
+                band_mu,
+                ..SubbandParameters::default()
+            };
+            let mut canceller = SubbandCanceller::new(&parameters, 16, 0.5, 1.0);
+            for i in 3..reference.len() {
+                canceller.adapt(reference[i], 0.5 * reference[i - 3], f32::INFINITY);
+            }
+            let energy = |band: usize| -> f32 {
+                canceller
+                    .band_weights(band)
+                    .iter()
+                    .map(|w| w.norm_sqr())
+                    .sum()
+            };
+            let step_sizes: Vec<f32> = canceller.band_mu().collect();
+            let bands = canceller.filter_bank().bands();
+            (step_sizes, energy(0), energy(bands - 1))
+        };
+        let (step_sizes, low, high) = run(vec![1.0, 0.01]);
+        assert_eq!(step_sizes.first(), Some(&0.5));
+        assert!((step_sizes.last().unwrap() - 0.005).abs() < 1e-6);
+        // the highest band only started to converge
+        let (_, flat_low, flat_high) = run(vec![]);
+        assert!(
+            (low - flat_low).abs() < 1e-3 * flat_low,
+            "{} vs {}",
+            low,
+            flat_low
+        );
+        assert!(high < 0.25 * flat_high, "{} vs {}", high, flat_high);
+    }
+
+    #[test]
+    fn test_converges_faster_on_speech() {
+        let scenario = Scenario {
+            duration_s: 4.0,
+            far_end: Source::Speech,
+            ..small_room()
+        };
+        let simulation = scenario.generate().unwrap();
+        // in the second second
+        let run = |algorithm| {
+            let parameters = AECParameters {
+                algorithm,
+                ..parameters()
+            };
+            let (_, output) = cancel(&parameters, &simulation);
+            level_db(&simulation.microphone[16_000..32_000]) - level_db(&output[16_000..32_000])
+        };
+        let nlmf_db = run(Algorithm::Nlmf);
+        let subband_db = run(Algorithm::Subband);
+        assert!(subband_db > nlmf_db + 3.0, "{} vs {}", subband_db, nlmf_db);
+    }
+
+    #[test]
+    fn test_output_lags_input() {
+        // every output mode lags the input by the delay of the canceller
+        let parameters = AECParameters {
+            sample_rate: 16_000.0,
+            algorithm: Algorithm::Subband,
+            taps: 512,
+            output_mode: OutputMode::Bypass,
+            ..AECParameters::default()
+        };
+        let mic: Vec<f32> = (0..2_000).map(|i| (0.05 * i as f32).sin()).collect();
+        let mut filter = AECFiltering::offline(&parameters, vec![]);
+        let output = filter.process_offline(&mic, &vec![0.0; mic.len()]);
+        let delay = parameters.subband.delay();
+        assert_eq!(filter.delay(), delay);
+        assert!(output[..delay].iter().all(|&x| x == 0.0));
+        assert_eq!(output[delay..], mic[..mic.len() - delay]);
+        assert!(filter.weights().is_empty());
+    }
+}
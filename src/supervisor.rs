@@ -0,0 +1,259 @@
+//! Keeps the audio streams alive while devices come and go.
+//!
+//! A `Supervisor` owns the streams feeding and draining the buffers of an `AECFiltering`. It
+//! watches each of them for errors and for stalls (no callback for a while), tears a failed
+//! stream down and builds a new one on the same buffers through its `Backend`, which looks the
+//! device up again in case it was unplugged and plugged back. A device which cannot be opened is
+//! retried with an increasing delay.
+
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, CpalBackend, Endpoint};
+use crate::processing::{Mono2StereoOutput, Stereo2MonoCapture};
+use crate::streams::StreamHealth;
+
+/// Delay before the first attempt to rebuild a failed stream
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(250);
+/// Longest delay between two attempts to rebuild a failed stream
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// Tells from the activity of a stream whether it died.
+struct Watchdog {
+    health: Arc<StreamHealth>,
+    /// Callbacks counted at the last check, and when that count last changed
+    last_progress: (usize, Instant),
+}
+
+impl Watchdog {
+    fn new(health: Arc<StreamHealth>, now: Instant) -> Self {
+        Watchdog {
+            health,
+            last_progress: (0, now),
+        }
+    }
+
+    /// Why the stream should be rebuilt, if it should.
+    fn failure(&mut self, now: Instant, stall_timeout: Duration) -> Option<&'static str> {
+        if self.health.errors() > 0 {
+            return Some("reported an error");
+        }
+        let callbacks = self.health.callbacks();
+        if callbacks != self.last_progress.0 {
+            self.last_progress = (callbacks, now);
+        } else if now.duration_since(self.last_progress.1) > stall_timeout {
+            return Some("stalled");
+        }
+        None
+    }
+}
+
+/// One of the supervised streams.
+struct Supervised<S> {
+    /// Role of the stream, for the messages
+    name: String,
+    /// How the device was selected, to find it again
+    selector: String,
+    endpoint: Endpoint,
+    /// The running stream and its watchdog, if any
+    stream: Option<(S, Watchdog)>,
+    /// Delay before the next attempt to rebuild the stream, and when that attempt is due
+    retry: (Duration, Instant),
+    /// Samples lost by the previous streams built for this one
+    lost_samples: usize,
+}
+
+impl<S> Supervised<S> {
+    /// Tears the stream down, keeping the count of its lost samples.
+    fn tear_down(&mut self) {
+        if let Some((_, watchdog)) = self.stream.take() {
+            self.lost_samples += watchdog.health.lost_samples();
+        }
+    }
+}
+
+/// What `Supervisor::check` did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckReport {
+    /// Streams found failed or stalled, and torn down
+    pub failed: Vec<String>,
+    /// Streams built again; the processing should be resynchronised
+    pub rebuilt: Vec<String>,
+}
+
+/// Builds, watches and rebuilds the audio streams.
+pub struct Supervisor<B: Backend = CpalBackend> {
+    backend: B,
+    /// A stream without callbacks for this long is considered dead
+    stall_timeout: Duration,
+    streams: Vec<Supervised<B::Stream>>,
+}
+
+impl<B: Backend> Supervisor<B> {
+    pub fn new(backend: B, stall_timeout: Duration) -> Self {
+        Supervisor {
+            backend,
+            stall_timeout,
+            streams: vec![],
+        }
+    }
+
+    /// Builds an input stream on the device matching `selector`, feeding `capture`.
+    pub fn add_input(
+        &mut self,
+        name: &str,
+        selector: &str,
+        capture: Stereo2MonoCapture,
+    ) -> Result<(), anyhow::Error> {
+        self.add(
+            name,
+            selector,
+            Endpoint::Input(Arc::new(Mutex::new(capture))),
+        )
+    }
+
+    /// Builds an output stream on the device matching `selector`, playing from `output`.
+    pub fn add_output(
+        &mut self,
+        name: &str,
+        selector: &str,
+        output: Mono2StereoOutput,
+    ) -> Result<(), anyhow::Error> {
+        self.add(
+            name,
+            selector,
+            Endpoint::Output(Arc::new(Mutex::new(output))),
+        )
+    }
+
+    fn add(&mut self, name: &str, selector: &str, endpoint: Endpoint) -> Result<(), anyhow::Error> {
+        let mut supervised = Supervised {
+            name: name.to_string(),
+            selector: selector.to_string(),
+            endpoint,
+            stream: None,
+            retry: (INITIAL_RETRY_DELAY, Instant::now()),
+            lost_samples: 0,
+        };
+        // the first time round a failure is reported rather than retried: the setup is wrong
+        self.build(&mut supervised)?;
+        self.streams.push(supervised);
+        Ok(())
+    }
+
+    /// Builds a new stream for the device; the stream is not started.
+    fn build(&self, supervised: &mut Supervised<B::Stream>) -> Result<(), anyhow::Error> {
+        // a fresh health record, so that late callbacks of an old stream are not counted
+        let health = Arc::new(StreamHealth::default());
+        let (stream, description) = self.backend.build_stream(
+            &supervised.selector,
+            &supervised.endpoint,
+            health.clone(),
+        )?;
+        info!("{} stream: {}", supervised.name, description);
+        supervised.stream = Some((stream, Watchdog::new(health, Instant::now())));
+        Ok(())
+    }
+
+    /// Starts all the streams.
+    pub fn play(&self) -> Result<(), anyhow::Error> {
+        for (stream, _) in self.streams.iter().filter_map(|s| s.stream.as_ref()) {
+            self.backend.play(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Tears down the streams which reported an error or stalled, and tries to rebuild those
+    /// which are down and due for another attempt. To be called regularly.
+    pub fn check(&mut self) -> CheckReport {
+        let now = Instant::now();
+        let mut report = CheckReport::default();
+        let mut supervised_streams = std::mem::take(&mut self.streams);
+        for supervised in supervised_streams.iter_mut() {
+            if let Some((_, watchdog)) = supervised.stream.as_mut() {
+                if let Some(reason) = watchdog.failure(now, self.stall_timeout) {
+                    warn!("The {} stream {}; rebuilding it", supervised.name, reason);
+                    supervised.tear_down();
+                    supervised.retry = (INITIAL_RETRY_DELAY, now);
+                    report.failed.push(supervised.name.clone());
+                }
+            }
+            if supervised.stream.is_none() && now >= supervised.retry.1 {
+                let rebuilt = self.build(supervised).and_then(|()| {
+                    let (stream, _) = supervised.stream.as_ref().unwrap(); // SAFETY: just built
+                    self.backend.play(stream)
+                });
+                match rebuilt {
+                    Ok(()) => {
+                        info!("The {} stream is running again", supervised.name);
+                        report.rebuilt.push(supervised.name.clone());
+                    }
+                    Err(e) => {
+                        supervised.tear_down();
+                        let delay = supervised.retry.0;
+                        warn!(
+                            "Could not rebuild the {} stream ({}); retrying in {:?}",
+                            supervised.name, e, delay
+                        );
+                        supervised.retry = ((2 * delay).min(MAX_RETRY_DELAY), now + delay);
+                    }
+                }
+            }
+        }
+        self.streams = supervised_streams;
+        report
+    }
+
+    /// Whether all the streams are running.
+    pub fn all_running(&self) -> bool {
+        self.streams.iter().all(|s| s.stream.is_some())
+    }
+
+    /// Delay of the device of the stream called `name`, as last told by its callbacks; see
+    /// `StreamHealth::device_latency`.
+    pub fn device_latency(&self, name: &str) -> Option<Duration> {
+        let supervised = self.streams.iter().find(|s| s.name == name)?;
+        let (_, watchdog) = supervised.stream.as_ref()?;
+        watchdog.health.device_latency()
+    }
+
+    /// Samples lost so far by the stream called `name`, over all the streams built for it; see
+    /// `StreamHealth::lost_samples`.
+    pub fn lost_samples(&self, name: &str) -> Option<usize> {
+        let supervised = self.streams.iter().find(|s| s.name == name)?;
+        let current = supervised.stream.as_ref();
+        Some(supervised.lost_samples + current.map_or(0, |(_, w)| w.health.lost_samples()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let start = Instant::now();
+        let timeout = Duration::from_millis(500);
+        let health = Arc::new(StreamHealth::default());
+        let mut watchdog = Watchdog::new(health.clone(), start);
+
+        assert_eq!(watchdog.failure(start + timeout / 2, timeout), None);
+        // callbacks keep the stream alive
+        health.record_callback();
+        assert_eq!(watchdog.failure(start + timeout * 2, timeout), None);
+        assert_eq!(watchdog.failure(start + timeout * 3, timeout), None);
+        assert_eq!(
+            watchdog.failure(start + timeout * 4, timeout),
+            Some("stalled")
+        );
+        health.record_callback();
+        assert_eq!(watchdog.failure(start + timeout * 4, timeout), None);
+
+        health.record_error();
+        assert_eq!(
+            watchdog.failure(start + timeout * 4, timeout),
+            Some("reported an error")
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Simulated audio devices, to run the threaded pipeline without sound hardware.
+//!
+//! A `VirtualBackend` holds input devices playing a signal (silence, samples or a generator) and
+//! output devices recording what they are given. Each stream runs on a thread of its own which
+//! calls the same callbacks as the cpal streams, one buffer of `buffer_frames` frames at a time,
+//! at the pace of the device clock:
+//!
+//! - the clock of a device can run `skew_ppm` parts per million faster (or slower, when negative)
+//!   than the nominal sample rate;
+//! - each callback can come late by the absolute value of a normal variable of standard
+//!   deviation `jitter_ms`, without delaying the following ones;
+//! - during a dropout the device makes no callback at all. Dropouts are timed from the moment a
+//!   stream of the device first plays, so that a rebuilt stream resumes in the same schedule. The
+//!   signal of an input device goes on meanwhile and is lost, an output device records silence.
+//!
+//! A device can also be unplugged with `VirtualBackend::set_available`: its stream reports an
+//! error and no stream can be built on it until it is plugged back.
+
+use log::debug;
+use rand::thread_rng;
+use rand_distr::{Distribution, Normal};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, Endpoint};
+use crate::devices::{DeviceSelector, Direction};
+use crate::simulation;
+use crate::streams::{self, StreamHealth};
+
+/// Buffer size of a device unless told otherwise
+const DEFAULT_BUFFER_FRAMES: usize = 256;
+/// Number of channels of a device unless told otherwise
+const DEFAULT_CHANNELS: usize = 2;
+/// Longest a stream thread sleeps before checking whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What an input device records, one mono sample at a time; every channel gets the same sample.
+pub enum Signal {
+    Silence,
+    /// Played once, then silence
+    Samples(Vec<f32>),
+    /// Called with the index of each sample
+    Generator(Box<dyn FnMut(usize) -> f32 + Send>),
+}
+
+impl Signal {
+    /// The samples of a mono WAV file; see `simulation::read_wav`.
+    pub fn from_wav(path: &Path, sample_rate: u32) -> Result<Self, anyhow::Error> {
+        Ok(Signal::Samples(simulation::read_wav(path, sample_rate)?))
+    }
+
+    /// The sample at `index`.
+    fn sample(&mut self, index: usize) -> f32 {
+        match self {
+            Signal::Silence => 0.0,
+            Signal::Samples(samples) => samples.get(index).copied().unwrap_or(0.0),
+            Signal::Generator(generate) => generate(index),
+        }
+    }
+}
+
+/// The description of a simulated device, to add to a `VirtualBackend`.
+pub struct VirtualDevice {
+    settings: Settings,
+    /// Input devices only
+    signal: Signal,
+}
+
+/// Everything about a device but its signal.
+struct Settings {
+    name: String,
+    direction: Direction,
+    buffer_frames: usize,
+    channels: usize,
+    skew_ppm: f64,
+    jitter_ms: f32,
+    /// Start and length of each dropout, from the first time the device plays
+    dropouts: Vec<(Duration, Duration)>,
+}
+
+impl VirtualDevice {
+    /// An input device recording `signal`.
+    pub fn input(name: &str, signal: Signal) -> Self {
+        Self::new(name, Direction::Input, signal)
+    }
+
+    /// An output device keeping what it plays; see `VirtualBackend::played`.
+    pub fn output(name: &str) -> Self {
+        Self::new(name, Direction::Output, Signal::Silence)
+    }
+
+    fn new(name: &str, direction: Direction, signal: Signal) -> Self {
+        VirtualDevice {
+            settings: Settings {
+                name: name.to_string(),
+                direction,
+                buffer_frames: DEFAULT_BUFFER_FRAMES,
+                channels: DEFAULT_CHANNELS,
+                skew_ppm: 0.0,
+                jitter_ms: 0.0,
+                dropouts: vec![],
+            },
+            signal,
+        }
+    }
+
+    pub fn with_buffer_frames(mut self, frames: usize) -> Self {
+        assert!(frames > 0, "A buffer needs at least one frame");
+        self.settings.buffer_frames = frames;
+        self
+    }
+
+    pub fn with_channels(mut self, channels: usize) -> Self {
+        assert!(channels > 0, "A device needs at least one channel");
+        self.settings.channels = channels;
+        self
+    }
+
+    /// Makes the clock of the device run `ppm` parts per million fast, or slow when negative.
+    pub fn with_skew_ppm(mut self, ppm: f64) -> Self {
+        self.settings.skew_ppm = ppm;
+        self
+    }
+
+    /// Delays each callback by up to a few times `ms` milliseconds.
+    pub fn with_jitter_ms(mut self, ms: f32) -> Self {
+        assert!(ms >= 0.0, "The jitter cannot be negative");
+        self.settings.jitter_ms = ms;
+        self
+    }
+
+    /// Stops the callbacks for `length`, `at` after the device first plays.
+    pub fn with_dropout(mut self, at: Duration, length: Duration) -> Self {
+        self.settings.dropouts.push((at, length));
+        self
+    }
+}
+
+impl Settings {
+    /// Time between two callbacks at `sample_rate`, according to the clock of the device.
+    fn period(&self, sample_rate: u32) -> Duration {
+        let nominal = self.buffer_frames as f64 / sample_rate as f64;
+        Duration::from_secs_f64(nominal / (1.0 + self.skew_ppm * 1e-6))
+    }
+
+    fn in_dropout(&self, since_epoch: Duration) -> bool {
+        self.dropouts
+            .iter()
+            .any(|&(at, length)| since_epoch >= at && since_epoch < at + length)
+    }
+}
+
+/// What changes on a device while its streams come and go.
+struct DeviceState {
+    signal: Signal,
+    /// Index of the next sample of the signal
+    position: usize,
+    /// What an output device played, first channel only
+    played: Vec<f32>,
+    /// When a stream of the device first played
+    epoch: Option<Instant>,
+    available: bool,
+}
+
+struct Device {
+    settings: Settings,
+    state: Mutex<DeviceState>,
+}
+
+/// Simulated devices, all run at the same nominal sample rate. Clones share the devices.
+#[derive(Clone)]
+pub struct VirtualBackend {
+    sample_rate: u32,
+    devices: Vec<Arc<Device>>,
+}
+
+impl VirtualBackend {
+    pub fn new(sample_rate: u32) -> Self {
+        VirtualBackend {
+            sample_rate,
+            devices: vec![],
+        }
+    }
+
+    pub fn with_device(mut self, device: VirtualDevice) -> Self {
+        self.devices.push(Arc::new(Device {
+            settings: device.settings,
+            state: Mutex::new(DeviceState {
+                signal: device.signal,
+                position: 0,
+                played: vec![],
+                epoch: None,
+                available: true,
+            }),
+        }));
+        self
+    }
+
+    fn device(&self, name: &str) -> &Device {
+        self.devices
+            .iter()
+            .find(|device| device.settings.name == name)
+            .unwrap_or_else(|| panic!("No virtual device called \"{}\"", name))
+    }
+
+    /// What the output device called `name` played so far.
+    ///
+    /// # Panics
+    ///
+    /// If there is no such device.
+    pub fn played(&self, name: &str) -> Vec<f32> {
+        self.device(name).state.lock().unwrap().played.clone()
+    }
+
+    /// Unplugs the device called `name`, or plugs it back.
+    ///
+    /// # Panics
+    ///
+    /// If there is no such device.
+    pub fn set_available(&self, name: &str, available: bool) {
+        self.device(name).state.lock().unwrap().available = available;
+    }
+}
+
+impl Backend for VirtualBackend {
+    type Stream = VirtualStream;
+
+    fn build_stream(
+        &self,
+        selector: &str,
+        endpoint: &Endpoint,
+        health: Arc<StreamHealth>,
+    ) -> Result<(VirtualStream, String), anyhow::Error> {
+        let direction = endpoint.direction();
+        let candidates: Vec<&Arc<Device>> = self
+            .devices
+            .iter()
+            .filter(|device| {
+                device.settings.direction == direction && device.state.lock().unwrap().available
+            })
+            .collect();
+        let names: Vec<String> = candidates
+            .iter()
+            .map(|device| device.settings.name.clone())
+            .collect();
+        let device = candidates[selector
+            .parse::<DeviceSelector>()?
+            .select(&names, names.first().map(String::as_str))?]
+        .clone();
+
+        let settings = &device.settings;
+        match endpoint {
+            Endpoint::Input(capture) => capture.lock().unwrap().set_channels(settings.channels),
+            Endpoint::Output(output) => output.lock().unwrap().set_channels(settings.channels),
+        }
+        let summary = format!(
+            "virtual device \"{}\", {} channel(s) at {} Hz, buffers of {} frames",
+            settings.name, settings.channels, self.sample_rate, settings.buffer_frames
+        );
+        Ok((
+            VirtualStream::spawn(device, self.sample_rate, endpoint.clone(), health),
+            summary,
+        ))
+    }
+
+    fn play(&self, stream: &VirtualStream) -> Result<(), anyhow::Error> {
+        stream.playing.store(true, Ordering::Relaxed);
+        stream.thread.as_ref().unwrap().thread().unpark(); // SAFETY: only taken when dropped
+        Ok(())
+    }
+}
+
+/// A stream of a virtual device; stops when dropped.
+pub struct VirtualStream {
+    playing: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualStream {
+    fn spawn(
+        device: Arc<Device>,
+        sample_rate: u32,
+        endpoint: Endpoint,
+        health: Arc<StreamHealth>,
+    ) -> Self {
+        let playing = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_playing, thread_stop) = (playing.clone(), stop.clone());
+        let thread = std::thread::spawn(move || {
+            if wait(
+                || thread_playing.load(Ordering::Relaxed),
+                &thread_stop,
+                None,
+            ) {
+                run(&device, sample_rate, &endpoint, &health, &thread_stop);
+            }
+        });
+        VirtualStream {
+            playing,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Waits until `ready` or `deadline`, whichever comes first; returns false if told to stop
+/// meanwhile.
+fn wait(ready: impl Fn() -> bool, stop: &AtomicBool, deadline: Option<Instant>) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if ready() || deadline.is_some_and(|deadline| now >= deadline) {
+            return true;
+        }
+        let timeout = deadline.map_or(POLL_INTERVAL, |deadline| {
+            (deadline - now).min(POLL_INTERVAL)
+        });
+        std::thread::park_timeout(timeout);
+    }
+}
+
+/// Makes the callbacks of a playing stream until told to stop.
+fn run(
+    device: &Device,
+    sample_rate: u32,
+    endpoint: &Endpoint,
+    health: &StreamHealth,
+    stop: &AtomicBool,
+) {
+    let settings = &device.settings;
+    let period = settings.period(sample_rate);
+    let jitter = Normal::new(0.0, settings.jitter_ms / 1_000.0).unwrap();
+    let frames = settings.buffer_frames;
+    let mut data = vec![0.0_f32; frames * settings.channels];
+    let start = Instant::now();
+    let epoch = *device.state.lock().unwrap().epoch.get_or_insert(start);
+    let mut rng = thread_rng();
+    for buffer in 1_u32.. {
+        let due = start + period * buffer;
+        let late = Duration::from_secs_f32(jitter.sample(&mut rng).abs());
+        if !wait(|| false, stop, Some(due + late)) {
+            return;
+        }
+        let mut state = device.state.lock().unwrap();
+        if !state.available {
+            debug!("The virtual device \"{}\" was unplugged", settings.name);
+            health.record_error();
+            return;
+        }
+        let silent = settings.in_dropout(due - epoch);
+        match endpoint {
+            Endpoint::Input(capture) => {
+                for frame in data.chunks_exact_mut(settings.channels) {
+                    let index = state.position;
+                    let sample = state.signal.sample(index);
+                    frame.iter_mut().for_each(|value| *value = sample);
+                    state.position += 1;
+                }
+                drop(state);
+                if !silent {
+                    // the first frame of the buffer was captured a period before it was due
+                    streams::input_callback(capture, &data, health, Some(period + late));
+                }
+            }
+            Endpoint::Output(output) => {
+                if silent {
+                    let played = state.played.len() + frames;
+                    state.played.resize(played, 0.0);
+                } else {
+                    drop(state);
+                    // what is given to the device is played at once
+                    streams::output_callback(output, &mut data, health, Some(Duration::ZERO));
+                    let played = data.chunks_exact(settings.channels).map(|frame| frame[0]);
+                    device.state.lock().unwrap().played.extend(played);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Pipeline;
+    use crate::processing::{AECParameters, OutputMode, RunningAECFiltering};
+    use crate::supervisor::Supervisor;
+
+    const SAMPLE_RATE: u32 = 8_000;
+
+    /// A pipeline bypassing the canceller, on `microphone` and two more virtual devices.
+    fn virtual_pipeline(
+        microphone: VirtualDevice,
+        latency_samples: usize,
+    ) -> (Pipeline, VirtualBackend) {
+        let parameters = AECParameters {
+            output_mode: OutputMode::Bypass,
+            sample_rate: SAMPLE_RATE as f32,
+            // short enough to keep up in debug builds
+            taps: 64,
+            ..AECParameters::default()
+        };
+        let weights = vec![0.0; parameters.taps];
+        let backend = VirtualBackend::new(SAMPLE_RATE)
+            .with_device(microphone)
+            .with_device(VirtualDevice::input("loopback", Signal::Silence))
+            .with_device(VirtualDevice::output("speaker"));
+        let pipeline = Pipeline::new(&parameters, weights, latency_samples, 4 * latency_samples);
+        (pipeline, backend)
+    }
+
+    fn supervise(
+        pipeline: Pipeline,
+        backend: &VirtualBackend,
+    ) -> (Supervisor<VirtualBackend>, RunningAECFiltering) {
+        let mut supervisor = Supervisor::new(backend.clone(), Duration::from_millis(100));
+        supervisor
+            .add_input("input", "mic", pipeline.microphone)
+            .unwrap();
+        supervisor
+            .add_input("capture", "loopback", pipeline.capture)
+            .unwrap();
+        supervisor
+            .add_output("output", "speaker", pipeline.output)
+            .unwrap();
+        let running = pipeline.wakeup.start(pipeline.filter);
+        supervisor.play().unwrap();
+        (supervisor, running)
+    }
+
+    /// Calls `condition` every 10 ms until it holds, for up to ten seconds so that a loaded
+    /// machine does not fail the tests; returns whether it held.
+    fn eventually(mut condition: impl FnMut() -> bool) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(10) {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Index of the first sample above a half.
+    fn onset(signal: &[f32]) -> Option<usize> {
+        signal.iter().position(|&sample| sample > 0.5)
+    }
+
+    #[test]
+    fn test_signals() {
+        let mut samples = Signal::Samples(vec![1.0, 2.0]);
+        assert_eq!(
+            (0..3).map(|i| samples.sample(i)).collect::<Vec<_>>(),
+            vec![1.0, 2.0, 0.0]
+        );
+        let mut ramp = Signal::Generator(Box::new(|i| i as f32));
+        assert_eq!(ramp.sample(5), 5.0);
+
+        let settings = VirtualDevice::output("speaker")
+            .with_buffer_frames(80)
+            .with_skew_ppm(1_000.0)
+            .with_dropout(Duration::from_secs(1), Duration::from_millis(500))
+            .settings;
+        let period = settings.period(8_000).as_secs_f64();
+        assert!((period - 0.01 / 1.001).abs() < 1e-9, "{}", period);
+        assert!(!settings.in_dropout(Duration::from_millis(999)));
+        assert!(settings.in_dropout(Duration::from_millis(1_200)));
+        assert!(!settings.in_dropout(Duration::from_millis(1_500)));
+    }
+
+    #[test]
+    fn test_latency() {
+        // a click every half second
+        let click = Signal::Generator(Box::new(|i| (i % 4_000 == 1_000) as u8 as f32));
+        let microphone = VirtualDevice::input("mic", click)
+            .with_buffer_frames(64)
+            .with_jitter_ms(0.5);
+        let latency_samples = 400;
+        let (pipeline, backend) = virtual_pipeline(microphone, latency_samples);
+        let latency = pipeline.filter.latency();
+        let (supervisor, running) = supervise(pipeline, &backend);
+
+        // the first click has been played and the buffers have settled
+        assert!(eventually(|| onset(&backend.played("speaker")).is_some()));
+        let report = latency.report(
+            SAMPLE_RATE as f32,
+            supervisor.device_latency("input"),
+            supervisor.device_latency("output"),
+        );
+        running.kill();
+        drop(supervisor);
+        let played = backend.played("speaker");
+        // the buffers hold the latency, plus up to a buffer of each stream
+        let delay = onset(&played).expect("the click was not played") - 1_000;
+        assert!(
+            delay >= latency_samples && delay <= latency_samples + 3 * 256,
+            "delay of {} samples",
+            delay
+        );
+
+        // a buffer of the microphone, plus the jitter
+        let input_ms = report.input_device_ms.unwrap();
+        assert!((8.0..12.0).contains(&input_ms), "{}", report);
+        assert_eq!(report.output_device_ms, Some(0.0));
+        assert_eq!(report.processing_ms, 0.0);
+        // the output buffer drains a device buffer at a time
+        let delay_ms = 1_000.0 * delay as f32 / SAMPLE_RATE as f32;
+        assert!(
+            (report.total_ms() - delay_ms).abs() < 2.0 * 256.0 / 8.0,
+            "{} for a delay of {} ms",
+            report,
+            delay_ms
+        );
+    }
+
+    #[test]
+    fn test_dropout_recovery() {
+        let microphone = VirtualDevice::input("mic", Signal::Generator(Box::new(|_| 1.0)))
+            .with_buffer_frames(80)
+            .with_dropout(Duration::from_millis(200), Duration::from_millis(300));
+        let (pipeline, backend) = virtual_pipeline(microphone, 400);
+        let (mut supervisor, mut running) = supervise(pipeline, &backend);
+
+        let mut failed = vec![];
+        let mut rebuilt = vec![];
+        // the microphone is heard again once back, though the buffers no longer hold the latency
+        let heard_again = |played: &[f32]| {
+            let tail = &played[played.len().saturating_sub(800)..];
+            tail.iter().filter(|&&sample| sample == 1.0).count() > 400
+        };
+        let recovered = eventually(|| {
+            let report = supervisor.check();
+            if !report.rebuilt.is_empty() {
+                running.resync().unwrap();
+            }
+            failed.extend(report.failed);
+            rebuilt.extend(report.rebuilt);
+            !rebuilt.is_empty()
+                && supervisor.all_running()
+                && heard_again(&backend.played("speaker"))
+        });
+        running.kill();
+        // the output runs dry while the microphone is silent
+        assert!(supervisor.lost_samples("output").unwrap() > 0);
+        assert_eq!(supervisor.lost_samples("input"), Some(0));
+        assert!(failed.contains(&"input".to_string()), "{:?}", failed);
+        assert!(rebuilt.contains(&"input".to_string()), "{:?}", rebuilt);
+        assert!(recovered, "{:?}", backend.played("speaker"));
+    }
+
+    #[test]
+    fn test_unplug() {
+        let (pipeline, backend) =
+            virtual_pipeline(VirtualDevice::input("mic", Signal::Silence), 400);
+        let (mut supervisor, running) = supervise(pipeline, &backend);
+
+        backend.set_available("speaker", false);
+        let mut failed = vec![];
+        assert!(eventually(|| {
+            failed.extend(supervisor.check().failed);
+            !failed.is_empty()
+        }));
+        assert_eq!(failed, vec!["output".to_string()]);
+        assert!(!supervisor.all_running());
+        backend.set_available("speaker", true);
+        assert!(eventually(|| {
+            supervisor.check();
+            supervisor.all_running()
+        }));
+
+        // the processing thread stops, even parked waiting for samples
+        drop(supervisor);
+        let killed = Instant::now();
+        running.kill();
+        assert!(killed.elapsed() < Duration::from_secs(5));
+    }
+}